@@ -0,0 +1,410 @@
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use log::debug;
+use sevenz_rust::{Password, SevenZReader};
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::JoinHandle;
+use zip::ZipArchive;
+
+// Archives are recognised by their contents, since the extension isn't always there.
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = b"\x1F\x8B";
+const SEVEN_ZIP_MAGIC: &[u8] = b"7z\xBC\xAF\x27\x1C";
+
+// Entry sizes come from the archive's own headers, which can claim anything, so they're
+// only trusted this far when setting aside memory.
+const MAX_PREALLOCATION: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Zip,
+    // Gzip only ever holds a single file
+    Gzip,
+    SevenZip,
+}
+
+// Anything an archive can be read from, such as a file or a buffer read from stdin.
+pub trait Source: Read + Seek {}
+
+impl<T: Read + Seek> Source for T {}
+
+// A decompressed file from inside an archive.
+pub struct Entry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl Entry {
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    pub fn reader(&self) -> Cursor<&[u8]> {
+        Cursor::new(&self.data)
+    }
+}
+
+// The files in an archive. Directories are skipped.
+//
+// Entries are decompressed one at a time as they're needed. 7z archives are usually
+// solid, meaning the files have to be decompressed in order in one go, so those are
+// decompressed on a thread of their own that hands each file over as it's done.
+pub enum Entries {
+    Zip {
+        archive: ZipArchive<Box<dyn Source + Send>>,
+        index: usize,
+    },
+    SevenZip {
+        path: PathBuf,
+        receiver: Receiver<Result<Entry>>,
+        worker: Option<JoinHandle<()>>,
+    },
+    Decompressed(std::vec::IntoIter<Entry>),
+}
+
+impl Iterator for Entries {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (archive, index) = match self {
+            Entries::Zip { archive, index } => (archive, index),
+            Entries::SevenZip {
+                path,
+                receiver,
+                worker,
+            } => {
+                if let Ok(entry) = receiver.recv() {
+                    return Some(entry);
+                }
+
+                // The worker is done, or it crashed before it got to the end.
+                return match worker.take()?.join() {
+                    Ok(()) => None,
+                    Err(_) => Some(Err(anyhow!("Crashed while decompressing {:?}", path))),
+                };
+            }
+            Entries::Decompressed(entries) => return entries.next().map(Ok),
+        };
+
+        while *index < archive.len() {
+            // Moving on first, so a broken entry is only reported once.
+            *index += 1;
+
+            let mut file = match archive.by_index(*index - 1) {
+                Ok(file) => file,
+                Err(err) => {
+                    let err = anyhow!(err).context(format!("Failed to read entry {}", *index));
+                    return Some(Err(err));
+                }
+            };
+
+            if file.is_dir() {
+                continue;
+            }
+
+            let name = file.name().to_string();
+            let mut data = Vec::with_capacity(preallocation(file.size()));
+
+            debug!("Decompressing {} ({} bytes)", name, file.size());
+            return Some(
+                file.read_to_end(&mut data)
+                    .with_context(|| format!("Failed to decompress {}", name))
+                    .map(|_| Entry { name, data }),
+            );
+        }
+
+        None
+    }
+}
+
+// The archive format of the file, or None when it isn't one.
+pub fn detect_format(path: &Path) -> Result<Option<Format>> {
+    let mut f = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    detect_format_from_reader(&mut f)
+}
+
+// Checks the reader for a known archive signature and rewinds it again.
+pub fn detect_format_from_reader<R: Read + Seek>(reader: &mut R) -> Result<Option<Format>> {
+    let mut magic = Vec::with_capacity(SEVEN_ZIP_MAGIC.len());
+    reader.seek(SeekFrom::Start(0))?;
+    reader
+        .take(SEVEN_ZIP_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let format = [
+        (Format::Zip, ZIP_MAGIC),
+        (Format::Gzip, GZIP_MAGIC),
+        (Format::SevenZip, SEVEN_ZIP_MAGIC),
+    ]
+    .into_iter()
+    .find(|(_, m)| magic.starts_with(m))
+    .map(|(format, _)| format);
+
+    Ok(format)
+}
+
+// The files in the archive, or None when the path isn't an archive.
+pub fn entries(path: &Path) -> Result<Option<Entries>> {
+    let f = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    entries_from_reader(Box::new(f), path)
+}
+
+// The files in the archive read from `reader`, or None when it isn't an archive.
+// `path` is used in error messages and to name the file inside a gzip archive.
+pub fn entries_from_reader(
+    mut reader: Box<dyn Source + Send>,
+    path: &Path,
+) -> Result<Option<Entries>> {
+    let format = match detect_format_from_reader(&mut reader)? {
+        Some(format) => format,
+        None => return Ok(None),
+    };
+    debug!("Reading {:?} as {:?}", path, format);
+
+    let entries = match format {
+        Format::Zip => Entries::Zip {
+            archive: ZipArchive::new(reader)
+                .with_context(|| format!("Failed to read archive {:?}", path))?,
+            index: 0,
+        },
+        Format::Gzip => Entries::Decompressed(vec![gzip_entry(reader, path)?].into_iter()),
+        Format::SevenZip => seven_zip_entries(reader, path)?,
+    };
+
+    Ok(Some(entries))
+}
+
+// Gzip files may record the original file name. When they don't, it's the name of the
+// gzip file itself minus the ".gz".
+fn gzip_entry(reader: Box<dyn Source>, path: &Path) -> Result<Entry> {
+    let mut decoder = GzDecoder::new(reader);
+    let mut data = Vec::new();
+    decoder
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to decompress {:?}", path))?;
+
+    let name = match decoder.header().and_then(|h| h.filename()) {
+        Some(name) => String::from_utf8_lossy(name).to_string(),
+        None => path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+    };
+
+    Ok(Entry { name, data })
+}
+
+// Reads the archive's headers straight away, so a broken archive is an error here rather
+// than from the first entry. The files are decompressed on a worker thread, which waits
+// for each one to be taken before moving on, and stops when the entries are dropped.
+fn seven_zip_entries(mut reader: Box<dyn Source + Send>, path: &Path) -> Result<Entries> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut archive = SevenZReader::new(reader, len, Password::empty())
+        .with_context(|| format!("Failed to read archive {:?}", path))?;
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let worker_path = path.to_path_buf();
+
+    let worker = std::thread::spawn(move || {
+        let result = archive.for_each_entries(|entry, r| {
+            if entry.is_directory() {
+                return Ok(true);
+            }
+
+            debug!("Decompressing {} ({} bytes)", entry.name(), entry.size());
+            let mut data = Vec::with_capacity(preallocation(entry.size()));
+            r.read_to_end(&mut data)?;
+            let entry = Entry {
+                name: entry.name().to_string(),
+                data,
+            };
+
+            Ok(sender.send(Ok(entry)).is_ok())
+        });
+
+        if let Err(err) = result {
+            let err =
+                anyhow::Error::new(err).context(format!("Failed to decompress {:?}", worker_path));
+            let _ = sender.send(Err(err));
+        }
+    });
+
+    Ok(Entries::SevenZip {
+        path: path.to_path_buf(),
+        receiver,
+        worker: Some(worker),
+    })
+}
+
+fn preallocation(declared_size: u64) -> usize {
+    declared_size.min(MAX_PREALLOCATION) as usize
+}
+
+// The one file in an archive, for callers that expect a single ROM per path.
+// Returns None when the path isn't an archive.
+pub fn single_entry(path: &Path) -> Result<Option<Entry>> {
+    let mut entries = match entries(path)? {
+        Some(entries) => entries,
+        None => return Ok(None),
+    };
+
+    let entry = match entries.next() {
+        Some(entry) => entry?,
+        None => bail!("Archive {:?} is empty", path),
+    };
+
+    if entries.next().is_some() {
+        bail!("Archive {:?} holds more than one file", path);
+    }
+
+    Ok(Some(entry))
+}
+
+// Copies the ROM at `source` to `dest` and hands the copy to `patch` to edit in place.
+// ROMs in archives are decompressed on the way, so the copy is always the bare ROM rather
+// than the archive. The copy is removed again when patching fails.
+pub fn patch_copy<T>(
+    source: &Path,
+    dest: &Path,
+    patch: impl FnOnce(&mut File) -> Result<T>,
+) -> Result<T> {
+    match single_entry(source)? {
+        Some(entry) => std::fs::write(dest, &entry.data),
+        None => std::fs::copy(source, dest).map(|_| ()),
+    }
+    .with_context(|| format!("Failed to copy {:?} to {:?}", source, dest))?;
+
+    let patched = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dest)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut f| patch(&mut f));
+
+    if patched.is_err() {
+        let _ = std::fs::remove_file(dest);
+    }
+
+    patched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenz_rust::{SeqReader, SevenZArchiveEntry, SevenZWriter, SourceReader};
+
+    // A solid 7z archive holding the files, all in one block
+    fn seven_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = SevenZWriter::new(Cursor::new(Vec::new())).unwrap();
+
+        let entries = files
+            .iter()
+            .map(|(name, _)| {
+                let mut entry = SevenZArchiveEntry::new();
+                entry.name = name.to_string();
+                entry.has_stream = true;
+                entry
+            })
+            .collect();
+        let readers = files
+            .iter()
+            .map(|(_, data)| SourceReader::new(*data))
+            .collect();
+
+        writer
+            .push_archive_entries(entries, SeqReader::new(readers))
+            .unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    // A zip archive whose second file has a broken local header
+    fn zip_with_broken_entry(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut writer, data).unwrap();
+        }
+        let mut archive = writer.finish().unwrap().into_inner();
+
+        let second = archive
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| *w == ZIP_MAGIC)
+            .nth(1)
+            .unwrap()
+            .0;
+        archive[second] = 0;
+
+        archive
+    }
+
+    fn entries(data: Vec<u8>) -> Result<Entries> {
+        Ok(entries_from_reader(Box::new(Cursor::new(data)), Path::new("test.7z"))?.unwrap())
+    }
+
+    #[test]
+    fn reads_solid_seven_zip_entries_in_order() {
+        let archive = seven_zip(&[("a.sfc", &[1; 4096]), ("b.sfc", &[2; 100])]);
+        let entries: Vec<Entry> = entries(archive).unwrap().map(Result::unwrap).collect();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "a.sfc");
+        assert_eq!(entries[0].data, [1; 4096]);
+        assert_eq!(entries[1].name, "b.sfc");
+        assert_eq!(entries[1].data, [2; 100]);
+    }
+
+    #[test]
+    fn stops_decompressing_when_dropped() {
+        let archive = seven_zip(&[
+            ("a.sfc", &[1; 16]),
+            ("b.sfc", &[2; 16]),
+            ("c.sfc", &[3; 16]),
+        ]);
+        let mut entries = entries(archive).unwrap();
+
+        assert_eq!(entries.next().unwrap().unwrap().name, "a.sfc");
+        let worker = match &mut entries {
+            Entries::SevenZip { worker, .. } => worker.take().unwrap(),
+            _ => panic!("expected a 7z archive"),
+        };
+
+        drop(entries);
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn reports_corrupt_seven_zip_data() {
+        let mut archive = seven_zip(&[("a.sfc", &[1; 4096])]);
+        // Flip bits in the compressed data, which starts right after the signature header
+        for byte in &mut archive[32..48] {
+            *byte ^= 0xFF;
+        }
+
+        let result: Result<Vec<Entry>> = entries(archive).and_then(|e| e.collect());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn moves_past_broken_zip_entries() {
+        let archive = zip_with_broken_entry(&[
+            ("a.sfc", &[1; 16]),
+            ("b.sfc", &[2; 16]),
+            ("c.sfc", &[3; 16]),
+        ]);
+        let mut entries = entries(archive).unwrap();
+
+        assert_eq!(entries.next().unwrap().unwrap().name, "a.sfc");
+        assert!(entries.next().unwrap().is_err());
+        assert_eq!(entries.next().unwrap().unwrap().name, "c.sfc");
+        assert!(entries.next().is_none());
+    }
+}
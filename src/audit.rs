@@ -0,0 +1,283 @@
+use crate::dat::Dat;
+use crate::Loaded;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+// How many games the DAT has that were found, missing or only found as bad dumps. Unknown
+// files don't belong to a game, so those are counted by file.
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub have: usize,
+    pub missing: usize,
+    pub unknown: usize,
+    pub bad_dumps: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Found {
+    pub game: String,
+    pub path: PathBuf,
+
+    // Name of the file inside the archive, when the path is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Unknown {
+    pub path: PathBuf,
+
+    // Name of the file inside the archive, when the path is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<String>,
+
+    // Why the file couldn't be read as a ROM, if it couldn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditReport {
+    pub dat: String,
+    pub summary: Summary,
+    pub have: Vec<Found>,
+    pub missing: Vec<String>,
+    pub unknown: Vec<Unknown>,
+    pub bad_dumps: Vec<Found>,
+}
+
+// Sorts the scanned files into what the DAT says we have, what's missing, what the DAT
+// doesn't know about, and known bad dumps.
+//
+// A game counts as present as soon as one of its ROMs is found. Games whose ROMs were never
+// dumped can't be collected, so they're never reported as missing.
+//
+// Files that couldn't be read as a ROM are still matched on the hashes of the raw file.
+pub fn audit(dat: &Dat, scanned: Vec<Loaded>) -> AuditReport {
+    let mut have = Vec::new();
+    let mut unknown = Vec::new();
+    let mut bad_dumps = Vec::new();
+    let mut found_games = HashSet::new();
+    let mut bad_dump_games = HashSet::new();
+
+    for loaded in scanned {
+        let error = loaded.rom.as_ref().err().map(|err| format!("{:#}", err));
+        let found = loaded
+            .hashes()
+            .and_then(|hashes| dat.find(hashes.payload()));
+
+        match found {
+            Some((game, rom)) => {
+                found_games.insert(game.name.to_string());
+                let found = Found {
+                    game: game.name.to_string(),
+                    path: loaded.path,
+                    entry: loaded.entry,
+                };
+
+                match rom.is_bad_dump() {
+                    true => {
+                        bad_dump_games.insert(game.name.to_string());
+                        bad_dumps.push(found);
+                    }
+                    false => have.push(found),
+                }
+            }
+            None => unknown.push(Unknown {
+                path: loaded.path,
+                entry: loaded.entry,
+                error,
+            }),
+        }
+    }
+
+    let have_games = have.iter().map(|found| &found.game).collect::<HashSet<_>>();
+
+    let missing: Vec<String> = dat
+        .games
+        .iter()
+        .filter(|game| !found_games.contains(&game.name))
+        .filter(|game| game.roms.iter().any(|rom| !rom.is_no_dump()))
+        .map(|game| game.name.to_string())
+        .collect();
+
+    AuditReport {
+        dat: dat.name.to_string(),
+        summary: Summary {
+            have: have_games.len(),
+            missing: missing.len(),
+            unknown: unknown.len(),
+            bad_dumps: bad_dump_games.len(),
+        },
+        have,
+        missing,
+        unknown,
+        bad_dumps,
+    }
+}
+
+// Writes one row per game or file with its audit status.
+pub fn write_csv(report: &AuditReport, path: &Path) -> Result<()> {
+    let mut writer =
+        csv::Writer::from_path(path).with_context(|| format!("Failed to create {:?}", path))?;
+    writer.write_record(["status", "game", "path", "entry"])?;
+
+    for found in &report.have {
+        let path = found.path.to_string_lossy();
+        let entry = found.entry.as_deref().unwrap_or_default();
+        writer.write_record(["have", &found.game, &path, entry])?;
+    }
+
+    for game in &report.missing {
+        writer.write_record(["missing", game, "", ""])?;
+    }
+
+    for unknown in &report.unknown {
+        let path = unknown.path.to_string_lossy();
+        let entry = unknown.entry.as_deref().unwrap_or_default();
+        writer.write_record(["unknown", "", &path, entry])?;
+    }
+
+    for found in &report.bad_dumps {
+        let path = found.path.to_string_lossy();
+        let entry = found.entry.as_deref().unwrap_or_default();
+        writer.write_record(["bad_dump", &found.game, &path, entry])?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+// Writes the summary counts as a single row, named after the DAT.
+pub fn write_summary_csv(report: &AuditReport, path: &Path) -> Result<()> {
+    let mut writer =
+        csv::Writer::from_path(path).with_context(|| format!("Failed to create {:?}", path))?;
+    writer.write_record(["dat", "have", "missing", "unknown", "bad_dumps"])?;
+
+    let summary = &report.summary;
+    writer.write_record([
+        report.dat.to_string(),
+        summary.have.to_string(),
+        summary.missing.to_string(),
+        summary.unknown.to_string(),
+        summary.bad_dumps.to_string(),
+    ])?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dat::dat_from_file;
+    use crate::hash;
+    use anyhow::anyhow;
+
+    fn sha1(fill: u8) -> String {
+        hash::hash_reader(&[fill; 1024][..], 0).unwrap().file.sha1
+    }
+
+    fn dat(dir: &Path) -> Dat {
+        let path = dir.join("set.dat");
+        std::fs::write(
+            &path,
+            format!(
+                r#"<datafile>
+    <header><name>Test Set</name></header>
+    <game name="Found Game"><rom name="found.bin" size="1024" sha1="{}"/></game>
+    <game name="Missing Game"><rom name="missing.bin" size="1024" crc="00000000"/></game>
+    <game name="Bad Game"><rom name="bad.bin" size="1024" sha1="{}" status="baddump"/></game>
+</datafile>"#,
+                sha1(0x55),
+                sha1(0x66)
+            ),
+        )
+        .unwrap();
+
+        dat_from_file(&path).unwrap()
+    }
+
+    // A file that couldn't be read as a ROM, with a copier header in front of the data
+    // the DAT lists
+    fn unreadable(name: &str, fill: u8) -> Loaded {
+        let mut data = vec![0; 512];
+        data.extend(vec![fill; 1024]);
+
+        Loaded {
+            path: PathBuf::from(name),
+            entry: None,
+            rom: Err(anyhow!("Could not automatically determine the platform")),
+            raw_hashes: Some(hash::hash_reader(&data[..], 512).unwrap()),
+        }
+    }
+
+    #[test]
+    fn matches_unreadable_files_on_raw_hashes() {
+        let dir = std::env::temp_dir().join(format!("romboss-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dat = dat(&dir);
+
+        let report = audit(
+            &dat,
+            vec![unreadable("found.bin", 0x55), unreadable("other.bin", 0)],
+        );
+
+        assert_eq!(report.have.len(), 1);
+        assert_eq!(report.have[0].game, "Found Game");
+        assert_eq!(report.missing, ["Missing Game", "Bad Game"]);
+        assert_eq!(report.unknown.len(), 1);
+        assert!(report.unknown[0].error.is_some());
+
+        let csv = dir.join("audit.csv");
+        write_csv(&report, &csv).unwrap();
+        let rows = std::fs::read_to_string(&csv).unwrap();
+        assert_eq!(
+            rows.lines().collect::<Vec<_>>(),
+            [
+                "status,game,path,entry",
+                "have,Found Game,found.bin,",
+                "missing,Missing Game,,",
+                "missing,Bad Game,,",
+                "unknown,,other.bin,",
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counts_games_rather_than_dumps() {
+        let dir = std::env::temp_dir().join(format!("romboss-audit-sum-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dat = dat(&dir);
+
+        let report = audit(
+            &dat,
+            vec![
+                unreadable("found.bin", 0x55),
+                unreadable("found (copy).bin", 0x55),
+                unreadable("bad.bin", 0x66),
+                unreadable("bad (copy).bin", 0x66),
+            ],
+        );
+
+        assert_eq!(report.have.len(), 2);
+        assert_eq!(report.bad_dumps.len(), 2);
+        assert_eq!(report.summary.have, 1);
+        assert_eq!(report.summary.bad_dumps, 1);
+        assert_eq!(report.summary.missing, 1);
+
+        let csv = dir.join("summary.csv");
+        write_summary_csv(&report, &csv).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&csv).unwrap(),
+            "dat,have,missing,unknown,bad_dumps\nTest Set,1,1,0,1\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,638 @@
+use crate::hash::Hashes;
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// A ROM set as described by a DAT file, like the ones published by No-Intro and Redump.
+#[derive(Debug, Default)]
+pub struct Dat {
+    pub name: String,
+    pub games: Vec<Game>,
+    by_sha1: HashMap<String, (usize, usize)>,
+    by_md5: HashMap<String, (usize, usize)>,
+    by_crc32: HashMap<(String, Option<u64>), (usize, usize)>,
+}
+
+#[derive(Debug, Default)]
+pub struct Game {
+    pub name: String,
+    pub roms: Vec<DatRom>,
+}
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct DatRom {
+    pub name: String,
+    pub size: Option<u64>,
+    pub crc32: Option<String>,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+    pub sha256: Option<String>,
+    pub serial: Option<String>,
+    // "verified", "baddump" or "nodump". Unset means the dump is believed good but unverified.
+    pub status: Option<String>,
+}
+
+impl DatRom {
+    pub fn is_verified(&self) -> bool {
+        self.status.as_deref() == Some("verified")
+    }
+
+    pub fn is_bad_dump(&self) -> bool {
+        self.status.as_deref() == Some("baddump")
+    }
+
+    pub fn is_no_dump(&self) -> bool {
+        self.status.as_deref() == Some("nodump")
+    }
+}
+
+// A header value that doesn't line up with what the DAT says about the game.
+#[derive(Serialize, Debug)]
+pub struct HeaderMismatch {
+    pub field: &'static str,
+    pub header: String,
+    pub dat: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RomMatch {
+    pub path: PathBuf,
+
+    // Name of the file inside the archive, when the path is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<String>,
+
+    pub matched: bool,
+    pub game: Option<String>,
+    pub rom: Option<DatRom>,
+    pub verified: bool,
+    pub header_mismatches: Vec<HeaderMismatch>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RomMatch {
+    pub fn failed(path: &Path, entry: Option<String>, error: &anyhow::Error) -> RomMatch {
+        RomMatch {
+            path: path.to_path_buf(),
+            entry,
+            matched: false,
+            game: None,
+            rom: None,
+            verified: false,
+            header_mismatches: Vec::new(),
+            error: Some(format!("{:#}", error)),
+        }
+    }
+}
+
+impl Dat {
+    // Finds the game and ROM matching the hashes, trying the strongest hash first.
+    pub fn find(&self, hashes: &Hashes) -> Option<(&Game, &DatRom)> {
+        let (game, rom) = self
+            .by_sha1
+            .get(&hashes.sha1)
+            .or_else(|| self.by_md5.get(&hashes.md5))
+            .or_else(|| {
+                self.by_crc32
+                    .get(&(hashes.crc32.clone(), Some(hashes.size)))
+            })
+            .or_else(|| self.by_crc32.get(&(hashes.crc32.clone(), None)))?;
+
+        let game = &self.games[*game];
+        Some((game, &game.roms[*rom]))
+    }
+
+    fn index(&mut self) {
+        for (g, game) in self.games.iter().enumerate() {
+            for (r, rom) in game.roms.iter().enumerate() {
+                if let Some(sha1) = &rom.sha1 {
+                    self.by_sha1.insert(sha1.clone(), (g, r));
+                }
+                if let Some(md5) = &rom.md5 {
+                    self.by_md5.insert(md5.clone(), (g, r));
+                }
+                if let Some(crc32) = &rom.crc32 {
+                    self.by_crc32.insert((crc32.clone(), rom.size), (g, r));
+                }
+            }
+        }
+    }
+}
+
+pub fn dat_from_file(path: &Path) -> Result<Dat> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read DAT file {:?}", path))?;
+
+    let mut dat = match contents.trim_start().starts_with('<') {
+        true => parse_logiqx(&contents).context("Failed to parse Logiqx XML DAT")?,
+        false => parse_clrmamepro(&contents).context("Failed to parse ClrMamePro DAT")?,
+    };
+
+    debug!("Loaded DAT {:?} with {} games", dat.name, dat.games.len());
+    dat.index();
+
+    Ok(dat)
+}
+
+// Hashes in DATs are hex, but not consistently lowercase.
+fn normalize_hash(value: &str) -> String {
+    value.trim().to_ascii_lowercase()
+}
+
+fn parse_logiqx(contents: &str) -> Result<Dat> {
+    // Logiqx DATs declare their DTD, which roxmltree refuses by default.
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let doc = roxmltree::Document::parse_with_options(contents, options)?;
+    let root = doc.root_element();
+
+    let name = root
+        .children()
+        .find(|n| n.has_tag_name("header"))
+        .and_then(|h| h.children().find(|n| n.has_tag_name("name")))
+        .and_then(|n| n.text())
+        .unwrap_or_default()
+        .to_string();
+
+    let mut games = Vec::new();
+
+    for node in root
+        .children()
+        .filter(|n| n.has_tag_name("game") || n.has_tag_name("machine"))
+    {
+        let game_serial = node
+            .children()
+            .find(|n| n.has_tag_name("serial"))
+            .and_then(|n| n.text())
+            .map(str::to_string);
+
+        let roms = node
+            .children()
+            .filter(|n| n.has_tag_name("rom"))
+            .map(|rom| DatRom {
+                name: rom.attribute("name").unwrap_or_default().to_string(),
+                size: rom.attribute("size").and_then(|s| s.parse().ok()),
+                crc32: rom.attribute("crc").map(normalize_hash),
+                md5: rom.attribute("md5").map(normalize_hash),
+                sha1: rom.attribute("sha1").map(normalize_hash),
+                sha256: rom.attribute("sha256").map(normalize_hash),
+                serial: rom
+                    .attribute("serial")
+                    .map(str::to_string)
+                    .or_else(|| game_serial.clone()),
+                status: rom.attribute("status").map(str::to_string),
+            })
+            .collect();
+
+        games.push(Game {
+            name: node.attribute("name").unwrap_or_default().to_string(),
+            roms,
+        });
+    }
+
+    Ok(Dat {
+        name,
+        games,
+        ..Default::default()
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+}
+
+// Splits a ClrMamePro DAT into parentheses and words, with quoted strings as one word.
+fn tokenize(contents: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated string {:?}", word),
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+            c if c.is_whitespace() => (),
+            c => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == '(' || next == ')' {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+// A parenthesised block of key/value pairs, where values can be nested blocks.
+#[derive(Debug, Default)]
+struct Block {
+    entries: Vec<(String, Value)>,
+}
+
+#[derive(Debug)]
+enum Value {
+    Word(String),
+    Block(Block),
+}
+
+impl Block {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().find_map(|(k, v)| match v {
+            Value::Word(w) if k == key => Some(w.as_str()),
+            _ => None,
+        })
+    }
+
+    fn blocks<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a Block> + 'a {
+        self.entries.iter().filter_map(move |(k, v)| match v {
+            Value::Block(b) if k == key => Some(b),
+            _ => None,
+        })
+    }
+}
+
+fn parse_block(tokens: &mut std::iter::Peekable<std::vec::IntoIter<Token>>) -> Result<Block> {
+    let mut block = Block::default();
+
+    loop {
+        let key = match tokens.next() {
+            Some(Token::Word(key)) => key,
+            Some(Token::Close) | None => return Ok(block),
+            Some(Token::Open) => bail!("Expected a key but found '('"),
+        };
+
+        let value = match tokens.next() {
+            Some(Token::Open) => Value::Block(parse_block(tokens)?),
+            Some(Token::Word(word)) => Value::Word(word),
+            Some(Token::Close) | None => bail!("Missing value for {:?}", key),
+        };
+
+        block.entries.push((key, value));
+    }
+}
+
+fn parse_clrmamepro(contents: &str) -> Result<Dat> {
+    let mut tokens = tokenize(contents)?.into_iter().peekable();
+    let document = parse_block(&mut tokens)?;
+
+    let name = document
+        .blocks("clrmamepro")
+        .next()
+        .and_then(|h| h.get("name"))
+        .unwrap_or_default()
+        .to_string();
+
+    let mut games = Vec::new();
+
+    for game in document.blocks("game").chain(document.blocks("machine")) {
+        let game_serial = game.get("serial").map(str::to_string);
+
+        let roms = game
+            .blocks("rom")
+            .map(|rom| DatRom {
+                name: rom.get("name").unwrap_or_default().to_string(),
+                size: rom.get("size").and_then(|s| s.parse().ok()),
+                crc32: rom.get("crc").map(normalize_hash),
+                md5: rom.get("md5").map(normalize_hash),
+                sha1: rom.get("sha1").map(normalize_hash),
+                sha256: rom.get("sha256").map(normalize_hash),
+                serial: rom
+                    .get("serial")
+                    .map(str::to_string)
+                    .or_else(|| game_serial.clone()),
+                status: rom
+                    .get("status")
+                    .or_else(|| rom.get("flags"))
+                    .map(str::to_string),
+            })
+            .collect();
+
+        games.push(Game {
+            name: game.get("name").unwrap_or_default().to_string(),
+            roms,
+        });
+    }
+
+    Ok(Dat {
+        name,
+        games,
+        ..Default::default()
+    })
+}
+
+// Uppercase letters and digits only, so "Super Mario World (USA)" and "SUPER MARIOWORLD"
+// can be compared.
+fn squash(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+// Reduces a serial to the part that identifies the game, so the Mega Drive header's
+// "GM 00001009-00" and the DAT's "MK-1009" can be compared. That drops the software type
+// prefix, the "-00" revision suffix and any leading zeros.
+fn normalize_serial(value: &str) -> String {
+    let value = value.trim();
+    let value = ["GM ", "AI "]
+        .iter()
+        .find_map(|prefix| value.strip_prefix(prefix))
+        .unwrap_or(value);
+    let value = match value.rsplit_once('-') {
+        Some((serial, revision))
+            if revision.len() == 2 && revision.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            serial
+        }
+        _ => value,
+    };
+
+    squash(value).trim_start_matches('0').to_string()
+}
+
+// Strips the "(USA) (Rev 1)" style tags off a No-Intro name.
+fn base_name(name: &str) -> &str {
+    match name.find(" (") {
+        Some(pos) => &name[..pos],
+        None => name,
+    }
+}
+
+// Compares identifying header values against the matched DAT entry.
+//
+// Titles are checked against the game name, and serials or game codes against the DAT's
+// serial when it has one. Header values are often abbreviated, so a value only counts as
+// a mismatch when neither one contains the other.
+pub fn header_mismatches(
+    game: &Game,
+    rom: &DatRom,
+    header_values: &[(&'static str, String)],
+) -> Vec<HeaderMismatch> {
+    let mut mismatches = Vec::new();
+
+    for (field, value) in header_values {
+        let normalize = match *field {
+            "title" => squash,
+            _ => normalize_serial,
+        };
+
+        let header = normalize(value);
+        if header.is_empty() {
+            continue;
+        }
+
+        let dat = match *field {
+            "title" => base_name(&game.name).to_string(),
+            _ => match &rom.serial {
+                Some(serial) => serial.to_string(),
+                None => continue,
+            },
+        };
+        let normalized = normalize(&dat);
+
+        if !normalized.contains(&header) && !header.contains(&normalized) {
+            mismatches.push(HeaderMismatch {
+                field,
+                header: value.to_string(),
+                dat,
+            });
+        }
+    }
+
+    mismatches
+}
+
+// Looks up the ROM in the DAT by its hashes and checks its header against the entry.
+pub fn match_rom(
+    dat: &Dat,
+    path: &Path,
+    entry: Option<String>,
+    hashes: &Hashes,
+    header_values: &[(&'static str, String)],
+) -> RomMatch {
+    let found = dat.find(hashes);
+
+    RomMatch {
+        path: path.to_path_buf(),
+        entry,
+        matched: found.is_some(),
+        game: found.map(|(game, _)| game.name.to_string()),
+        rom: found.map(|(_, rom)| rom.clone()),
+        verified: found.is_some_and(|(_, rom)| rom.is_verified()),
+        header_mismatches: found
+            .map(|(game, rom)| header_mismatches(game, rom, header_values))
+            .unwrap_or_default(),
+        error: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGIQX: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "http://www.logiqx.com/Dats/datafile.dtd">
+<datafile>
+    <header>
+        <name>Nintendo - Super Nintendo Entertainment System</name>
+    </header>
+    <game name="Super Mario World (USA)">
+        <serial>SNS-MW-USA</serial>
+        <rom name="Super Mario World (USA).sfc" size="524288" crc="B19ED489" md5="CDD3C8C37322978CA8669B34BC89C804" sha1="6B47BB75D16514B6A476AA0C73A683A2A4C18765" status="verified"/>
+    </game>
+    <game name="Broken Game (Japan)">
+        <rom name="Broken Game (Japan).sfc" size="1024" crc="12345678" status="baddump"/>
+    </game>
+</datafile>
+"#;
+
+    const CLRMAMEPRO: &str = r#"clrmamepro (
+    name "Sega - Mega Drive - Genesis"
+)
+
+game (
+    name "Sonic the Hedgehog (USA, Europe)"
+    serial "MK-1009"
+    rom ( name "Sonic the Hedgehog (USA, Europe).md" size 524288 crc f9394e97 sha1 6ddb7de1e17e7f6cdb88927bd906352030daa194 flags verified )
+)
+
+game (
+    name "Missing Dump (Japan)"
+    rom ( name "Missing Dump (Japan).md" size 1024 flags nodump )
+)
+"#;
+
+    fn load(contents: &str) -> Dat {
+        let path = std::env::temp_dir().join(format!(
+            "romboss-dat-{}-{}.dat",
+            std::process::id(),
+            contents.len()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        let dat = dat_from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        dat.unwrap()
+    }
+
+    fn hashes(size: u64, crc32: &str, sha1: &str) -> Hashes {
+        Hashes {
+            size,
+            crc32: crc32.to_string(),
+            md5: String::new(),
+            sha1: sha1.to_string(),
+            sha256: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_logiqx() {
+        let dat = load(LOGIQX);
+
+        assert_eq!(dat.name, "Nintendo - Super Nintendo Entertainment System");
+        assert_eq!(dat.games.len(), 2);
+
+        let rom = &dat.games[0].roms[0];
+        assert_eq!(rom.name, "Super Mario World (USA).sfc");
+        assert_eq!(rom.size, Some(524288));
+        assert_eq!(rom.crc32.as_deref(), Some("b19ed489"));
+        assert_eq!(rom.md5.as_deref(), Some("cdd3c8c37322978ca8669b34bc89c804"));
+        assert_eq!(rom.serial.as_deref(), Some("SNS-MW-USA"));
+        assert!(rom.is_verified());
+        assert!(dat.games[1].roms[0].is_bad_dump());
+    }
+
+    #[test]
+    fn parses_clrmamepro() {
+        let dat = load(CLRMAMEPRO);
+
+        assert_eq!(dat.name, "Sega - Mega Drive - Genesis");
+        assert_eq!(dat.games.len(), 2);
+        assert_eq!(dat.games[0].name, "Sonic the Hedgehog (USA, Europe)");
+
+        let rom = &dat.games[0].roms[0];
+        assert_eq!(rom.size, Some(524288));
+        assert_eq!(rom.crc32.as_deref(), Some("f9394e97"));
+        assert_eq!(rom.serial.as_deref(), Some("MK-1009"));
+        assert!(rom.is_verified());
+        assert!(dat.games[1].roms[0].is_no_dump());
+    }
+
+    #[test]
+    fn rejects_unterminated_strings() {
+        assert!(parse_clrmamepro("game ( name \"Unfinished )").is_err());
+    }
+
+    #[test]
+    fn finds_by_sha1_then_crc32() {
+        let dat = load(LOGIQX);
+
+        let (game, _) = dat
+            .find(&hashes(0, "", "6b47bb75d16514b6a476aa0c73a683a2a4c18765"))
+            .unwrap();
+        assert_eq!(game.name, "Super Mario World (USA)");
+
+        let (game, _) = dat.find(&hashes(1024, "12345678", "")).unwrap();
+        assert_eq!(game.name, "Broken Game (Japan)");
+
+        // The CRC32 only counts when the size agrees too.
+        assert!(dat.find(&hashes(2048, "12345678", "")).is_none());
+    }
+
+    #[test]
+    fn matches_and_checks_header_values() {
+        let dat = load(CLRMAMEPRO);
+        let hashes = hashes(524288, "f9394e97", "");
+
+        let found = match_rom(
+            &dat,
+            Path::new("sonic.md"),
+            None,
+            &hashes,
+            &[
+                ("title", "SONIC THE HEDGEHOG".to_string()),
+                ("serial_number", "GM 00001009-00".to_string()),
+            ],
+        );
+
+        assert!(found.matched);
+        assert!(found.verified);
+        assert_eq!(
+            found.game.as_deref(),
+            Some("Sonic the Hedgehog (USA, Europe)")
+        );
+        assert!(found.header_mismatches.is_empty());
+    }
+
+    #[test]
+    fn reports_header_values_for_another_game() {
+        let dat = load(CLRMAMEPRO);
+        let hashes = hashes(524288, "f9394e97", "");
+
+        let found = match_rom(
+            &dat,
+            Path::new("sonic.md"),
+            None,
+            &hashes,
+            &[
+                ("title", "STREETS OF RAGE".to_string()),
+                ("serial_number", "GM 00001079-00".to_string()),
+            ],
+        );
+
+        assert!(found.matched);
+        let fields: Vec<_> = found.header_mismatches.iter().map(|m| m.field).collect();
+        assert_eq!(fields, ["title", "serial_number"]);
+    }
+
+    #[test]
+    fn normalizes_serials() {
+        assert_eq!(normalize_serial("GM 00001009-00"), "1009");
+        assert_eq!(normalize_serial("AI 00004013-01"), "4013");
+        assert_eq!(normalize_serial("GM MK-1009 -00"), "MK1009");
+        assert_eq!(normalize_serial("MK-1009"), "MK1009");
+        assert_eq!(normalize_serial("SNS-MW-USA"), "SNSMWUSA");
+    }
+
+    #[test]
+    fn reports_no_match() {
+        let dat = load(CLRMAMEPRO);
+        let found = match_rom(
+            &dat,
+            Path::new("unknown.md"),
+            None,
+            &hashes(16, "00000000", ""),
+            &[],
+        );
+
+        assert!(!found.matched);
+        assert!(found.game.is_none());
+        assert!(found.header_mismatches.is_empty());
+    }
+}
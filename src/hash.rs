@@ -0,0 +1,155 @@
+use anyhow::Result;
+use md5::Md5;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::io::{Read, Seek, SeekFrom};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hashes {
+    pub size: u64,
+    pub crc32: String,
+    pub md5: String,
+    pub sha1: String,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RomHashes {
+    pub file: Hashes,
+
+    // Only present when the file starts with a copier header, which DATs leave out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headerless: Option<Hashes>,
+
+    // Only present for Nintendo 64 ROMs stored in another byte order. DATs use big-endian.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub big_endian: Option<Hashes>,
+}
+
+impl RomHashes {
+    // The hashes of the actual ROM data, which is what DATs list.
+    pub fn payload(&self) -> &Hashes {
+        self.big_endian
+            .as_ref()
+            .or(self.headerless.as_ref())
+            .unwrap_or(&self.file)
+    }
+}
+
+struct Hasher {
+    size: u64,
+    crc32: crc32fast::Hasher,
+    md5: Md5,
+    sha1: Sha1,
+    sha256: Sha256,
+}
+
+impl Hasher {
+    fn new() -> Hasher {
+        Hasher {
+            size: 0,
+            crc32: crc32fast::Hasher::new(),
+            md5: Md5::new(),
+            sha1: Sha1::new(),
+            sha256: Sha256::new(),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        self.size += bytes.len() as u64;
+        self.crc32.update(bytes);
+        self.md5.update(bytes);
+        self.sha1.update(bytes);
+        self.sha256.update(bytes);
+    }
+
+    fn finish(self) -> Hashes {
+        Hashes {
+            size: self.size,
+            crc32: format!("{:08x}", self.crc32.finalize()),
+            md5: format!("{:x}", self.md5.finalize()),
+            sha1: format!("{:x}", self.sha1.finalize()),
+            sha256: format!("{:x}", self.sha256.finalize()),
+        }
+    }
+}
+
+// Hashes everything in the reader in a single pass. When `header_len` is set, the data
+// after that many bytes is hashed separately as well.
+pub fn hash_reader<R: Read>(mut reader: R, header_len: u64) -> Result<RomHashes> {
+    let mut file = Hasher::new();
+    let mut headerless = match header_len {
+        0 => None,
+        _ => Some(Hasher::new()),
+    };
+
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut pos = 0u64;
+
+    loop {
+        let len = reader.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+
+        let chunk = &buffer[..len];
+        file.update(chunk);
+
+        if let Some(hasher) = headerless.as_mut() {
+            let skip = header_len.saturating_sub(pos).min(len as u64) as usize;
+            hasher.update(&chunk[skip..]);
+        }
+
+        pos += len as u64;
+    }
+
+    Ok(RomHashes {
+        file: file.finish(),
+        headerless: headerless.map(Hasher::finish),
+        big_endian: None,
+    })
+}
+
+// Hashes a file that couldn't be read as a ROM, so it can still be matched against DATs.
+//
+// Copier headers are left out the way DATs do it. An iNES header gives itself away with
+// its magic value, and other copiers put 512 bytes in front of ROM data that's a multiple
+// of 1 kB.
+pub fn hash_raw<R: Read + Seek>(reader: &mut R, size: u64) -> Result<RomHashes> {
+    let mut magic = [0; 4];
+    reader.seek(SeekFrom::Start(0))?;
+    let header_len = match reader.read_exact(&mut magic) {
+        Ok(()) if &magic == b"NES\x1A" => 16,
+        _ if size % 1024 == 512 => 512,
+        _ => 0,
+    };
+
+    reader.seek(SeekFrom::Start(0))?;
+    hash_reader(reader.take(size), header_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn skips_copier_headers_in_raw_files() {
+        let mut ines = b"NES\x1A".to_vec();
+        ines.resize(16 + 2048, 0xAA);
+        let hashes = hash_raw(&mut Cursor::new(&ines), ines.len() as u64).unwrap();
+        assert_eq!(hashes.payload().size, 2048);
+
+        let copier = vec![0xAA; 512 + 2048];
+        let hashes = hash_raw(&mut Cursor::new(&copier), copier.len() as u64).unwrap();
+        assert_eq!(hashes.payload().size, 2048);
+
+        let plain = vec![0xAA; 2048];
+        let hashes = hash_raw(&mut Cursor::new(&plain), plain.len() as u64).unwrap();
+        assert!(hashes.headerless.is_none());
+        assert_eq!(hashes.payload().size, 2048);
+    }
+}
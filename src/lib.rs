@@ -0,0 +1,503 @@
+use anyhow::{Context, Result};
+use log::{debug, info};
+use platform::{RomInfo, RomPlatform, PLATFORMS};
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+
+pub mod archive;
+pub mod audit;
+pub mod dat;
+pub mod hash;
+pub mod metadata;
+pub mod platform;
+pub mod rename;
+
+// Uses the given platform, or detects it when there isn't one.
+// `name` is only used for its extension, and can be the name of an archive entry.
+pub fn resolve_platform<R: Read + Seek>(
+    reader: &mut R,
+    size: u64,
+    name: &Path,
+    platform: Option<Platform>,
+) -> Result<Platform> {
+    if let Some(platform) = platform {
+        return Ok(platform);
+    }
+
+    let detection = detect_platform(reader, size, name)?.context(concat!(
+        "Could not automatically determine the platform. ",
+        "Specify the platform explicitly"
+    ))?;
+    info!(
+        "Detected platform {:?} with {}% confidence",
+        detection.platform, detection.confidence
+    );
+
+    Ok(detection.platform)
+}
+
+// A ROM read from a file, or from one entry of an archive.
+pub struct Loaded {
+    pub path: PathBuf,
+    // Name of the file inside the archive, when the path is one
+    pub entry: Option<String>,
+    pub rom: Result<Rom>,
+    // Hashes of the raw file, when it couldn't be read as a ROM
+    pub raw_hashes: Option<hash::RomHashes>,
+}
+
+impl Loaded {
+    fn new<R: Read + Seek>(
+        path: &Path,
+        entry: Option<String>,
+        rom: Result<Rom>,
+        reader: &mut R,
+        size: u64,
+    ) -> Loaded {
+        let raw_hashes = match rom {
+            Ok(_) => None,
+            Err(_) => hash::hash_raw(reader, size).ok(),
+        };
+
+        Loaded {
+            path: path.to_path_buf(),
+            entry,
+            rom,
+            raw_hashes,
+        }
+    }
+
+    // A path that couldn't be read at all, not even to hash it.
+    pub fn failed(path: &Path, error: anyhow::Error) -> Loaded {
+        Loaded {
+            path: path.to_path_buf(),
+            entry: None,
+            rom: Err(error),
+            raw_hashes: None,
+        }
+    }
+
+    // The ROM's hashes, or the raw file's when it couldn't be read as a ROM.
+    pub fn hashes(&self) -> Option<&hash::RomHashes> {
+        match &self.rom {
+            Ok(rom) => Some(rom.hashes()),
+            Err(_) => self.raw_hashes.as_ref(),
+        }
+    }
+}
+
+// Reads the ROM at the path. Archives give one result for each file inside them.
+pub fn roms_from_path(path: &Path, platform: Option<Platform>) -> Result<Vec<Loaded>> {
+    if let Some(entries) = archive::entries(path)? {
+        return roms_from_entries(path, entries, platform);
+    }
+
+    let mut f = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let size = f.metadata()?.len();
+    let rom = resolve_platform(&mut f, size, path, platform)
+        .and_then(|platform| rom_from_file(path, platform));
+
+    Ok(vec![Loaded::new(path, None, rom, &mut f, size)])
+}
+
+// Reads the ROM from data that's already in memory, such as stdin. `path` is only used to
+// label the results and for its extension.
+pub fn roms_from_bytes(
+    path: &Path,
+    data: Vec<u8>,
+    platform: Option<Platform>,
+) -> Result<Vec<Loaded>> {
+    let size = data.len() as u64;
+    let mut reader = Cursor::new(data);
+
+    match archive::detect_format_from_reader(&mut reader)? {
+        Some(format) => {
+            let entries = archive::entries_from_reader(Box::new(reader), path)?
+                .with_context(|| format!("Failed to read {:?} archive {:?}", format, path))?;
+            roms_from_entries(path, entries, platform)
+        }
+        None => {
+            let rom = resolve_platform(&mut reader, size, path, platform)
+                .and_then(|platform| rom_from_reader(&mut reader, size, platform));
+
+            Ok(vec![Loaded::new(path, None, rom, &mut reader, size)])
+        }
+    }
+}
+
+fn roms_from_entries(
+    path: &Path,
+    entries: archive::Entries,
+    platform: Option<Platform>,
+) -> Result<Vec<Loaded>> {
+    let mut loaded = Vec::new();
+
+    for entry in entries {
+        // A broken entry doesn't stop the rest of the archive from being read.
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                let err = err.context(format!("Failed to read archive {:?}", path));
+                loaded.push(Loaded::failed(path, err));
+                continue;
+            }
+        };
+        let mut reader = entry.reader();
+        let rom = resolve_platform(&mut reader, entry.size(), Path::new(&entry.name), platform)
+            .and_then(|platform| rom_from_reader(&mut reader, entry.size(), platform));
+
+        loaded.push(Loaded::new(
+            path,
+            Some(entry.name.clone()),
+            rom,
+            &mut reader,
+            entry.size(),
+        ));
+    }
+
+    Ok(loaded)
+}
+
+// A ROM read for one of the platforms.
+#[derive(Debug)]
+pub struct Rom {
+    pub platform: Platform,
+    pub info: Box<dyn RomInfo>,
+}
+
+// Serialized as `{"<platform>": {...}}` so it's clear which fields to expect.
+impl Serialize for Rom {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(&self.platform, &self.info)?;
+        map.end()
+    }
+}
+
+// Content probes scoring below this are not trusted over the file extension.
+const MIN_CONTENT_CONFIDENCE: u8 = 50;
+
+// Extensions are frequently wrong or generic (.bin, .rom), so they're a weak signal.
+const EXTENSION_CONFIDENCE: u8 = 30;
+
+#[derive(Debug)]
+pub struct Detection {
+    pub platform: Platform,
+    // How sure we are about the platform, from 0 to 100.
+    pub confidence: u8,
+}
+
+impl Rom {
+    pub fn hashes(&self) -> &hash::RomHashes {
+        self.info.hashes()
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    // Values from the header that identify the game, for checking against DATs.
+    pub fn header_values(&self) -> Vec<(&'static str, String)> {
+        self.info.header_values()
+    }
+
+    pub fn metadata(&self) -> metadata::Metadata {
+        self.info.metadata()
+    }
+
+    // The platform's own type, such as `platform::snes::Rom`, when it's that platform.
+    pub fn downcast_ref<T: RomInfo + 'static>(&self) -> Option<&T> {
+        self.info.as_ref().as_any().downcast_ref::<T>()
+    }
+}
+
+// Detects the platform by probing the file contents for known headers and magic values.
+// The file extension is only used when the contents are inconclusive.
+pub fn detect_platform<R: Read + Seek>(
+    reader: &mut R,
+    size: u64,
+    name: &Path,
+) -> Result<Option<Detection>> {
+    if let Some(detection) = detect_from_contents(reader, size)? {
+        if detection.confidence >= MIN_CONTENT_CONFIDENCE {
+            return Ok(Some(detection));
+        }
+        debug!("Content detection was inconclusive: {:?}", detection);
+    }
+
+    Ok(platform_from_path(name).map(|platform| Detection {
+        platform,
+        confidence: EXTENSION_CONFIDENCE,
+    }))
+}
+
+pub fn detect_from_contents<R: Read + Seek>(
+    reader: &mut R,
+    size: u64,
+) -> Result<Option<Detection>> {
+    let mut best: Option<Detection> = None;
+
+    for p in PLATFORMS {
+        let platform = p.platform();
+        let confidence = match p.probe(reader, size) {
+            Ok(confidence) => confidence,
+            Err(err) => {
+                debug!("Probing for {:?} failed: {}", platform, err);
+                0
+            }
+        };
+        debug!("Probed {:?} with {}% confidence", platform, confidence);
+
+        if confidence > best.as_ref().map_or(0, |d| d.confidence) {
+            best = Some(Detection {
+                platform,
+                confidence,
+            });
+        }
+    }
+
+    Ok(best)
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    GameBoy,
+    GameBoyAdvance,
+    GameGear,
+    MasterSystem,
+    MegaCD,
+    MegaDrive,
+    Nintendo64,
+    NintendoDS,
+    NES,
+    Sega32X,
+    SuperNintendo,
+}
+
+// Every label a platform can be picked by, in registry order.
+pub fn platform_labels() -> Vec<&'static str> {
+    PLATFORMS.iter().flat_map(|p| labels(*p)).collect()
+}
+
+// Labels of the platforms whose checksums can be fixed.
+pub fn fixable_platform_labels() -> Vec<&'static str> {
+    PLATFORMS
+        .iter()
+        .filter(|p| p.fixes_checksums())
+        .flat_map(|p| labels(*p))
+        .collect()
+}
+
+fn labels(p: &dyn RomPlatform) -> impl Iterator<Item = &'static str> {
+    std::iter::once(p.name()).chain(p.aliases().iter().copied())
+}
+
+pub fn parse_platform_label(label: &str) -> Option<Platform> {
+    PLATFORMS
+        .iter()
+        .find(|p| p.name() == label || p.aliases().contains(&label))
+        .map(|p| p.platform())
+}
+
+pub fn platform_from_path(path: &Path) -> Option<Platform> {
+    let ext = path.extension()?.to_ascii_lowercase();
+    let ext = ext.to_str()?;
+
+    PLATFORMS
+        .iter()
+        .find(|p| p.extensions().contains(&ext))
+        .map(|p| p.platform())
+}
+
+pub fn default_extension(platform: Platform) -> &'static str {
+    platform::find(platform).extensions()[0]
+}
+
+pub fn rom_from_file(path: &Path, platform: Platform) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size(), platform);
+    }
+
+    let mut f = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size, platform)
+}
+
+pub fn rom_from_reader<R: Read + Seek>(
+    reader: &mut R,
+    size: u64,
+    platform: Platform,
+) -> Result<Rom> {
+    let info = platform::find(platform).rom_from_reader(reader, size)?;
+
+    Ok(Rom { platform, info })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // An iNES header with one bank each of PRG and CHR ROM
+    fn nes_rom() -> Vec<u8> {
+        let mut rom = vec![0; 16 + 16 * 1024 + 8 * 1024];
+        rom[..6].copy_from_slice(b"NES\x1A\x01\x01");
+        rom
+    }
+
+    // A Master System header with a region code that doesn't say which system it's for
+    fn unknown_region_sms_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x7FF0..0x7FF8].copy_from_slice(b"TMR SEGA");
+        rom[0x7FFF] = 0x0C;
+        rom
+    }
+
+    #[test]
+    fn picks_the_most_confident_probe() {
+        // Both the Mega Drive and the 32X claim this, but the 32X is surer
+        let mut data = vec![0; 0x400];
+        data[0x100..0x110].copy_from_slice(b"SEGA 32X        ");
+        assert_eq!(
+            platform::megadrive::probe(&mut Cursor::new(&data)).unwrap(),
+            80
+        );
+
+        let detection = detect_from_contents(&mut Cursor::new(&data), data.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(detection.platform, Platform::Sega32X);
+        assert_eq!(detection.confidence, 95);
+
+        // The extension doesn't get a say when the contents are conclusive
+        let detection = detect_platform(
+            &mut Cursor::new(&data),
+            data.len() as u64,
+            Path::new("game.md"),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(detection.platform, Platform::Sega32X);
+    }
+
+    #[test]
+    fn falls_back_to_the_extension_when_contents_are_inconclusive() {
+        let data = unknown_region_sms_rom();
+        let detection = detect_from_contents(&mut Cursor::new(&data), data.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert!(detection.confidence < MIN_CONTENT_CONFIDENCE);
+
+        let detection = detect_platform(
+            &mut Cursor::new(&data),
+            data.len() as u64,
+            Path::new("GAME.NES"),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(detection.platform, Platform::NES);
+        assert_eq!(detection.confidence, EXTENSION_CONFIDENCE);
+    }
+
+    #[test]
+    fn gives_up_on_unknown_extensions() {
+        let data = unknown_region_sms_rom();
+        let detection = detect_platform(
+            &mut Cursor::new(&data),
+            data.len() as u64,
+            Path::new("game.bin"),
+        )
+        .unwrap();
+        assert!(detection.is_none());
+
+        let data = vec![0; 0x8000];
+        let detection = detect_platform(
+            &mut Cursor::new(&data),
+            data.len() as u64,
+            Path::new("game"),
+        )
+        .unwrap();
+        assert!(detection.is_none());
+    }
+
+    #[test]
+    fn downcasts_to_the_platform_type() {
+        let data = nes_rom();
+        let rom =
+            rom_from_reader(&mut Cursor::new(&data), data.len() as u64, Platform::NES).unwrap();
+
+        let nes = rom.downcast_ref::<platform::nes::Rom>().unwrap();
+        assert_eq!(nes.prg_rom_size, 16 * 1024);
+        assert!(rom.downcast_ref::<platform::snes::Rom>().is_none());
+    }
+
+    #[test]
+    fn only_lists_platforms_that_fix_checksums() {
+        let labels = fixable_platform_labels();
+
+        assert!(labels.contains(&"megadrive"));
+        assert!(labels.contains(&"snes"));
+        assert!(!labels.contains(&"nes"));
+    }
+
+    #[test]
+    fn hashes_files_that_are_not_roms() {
+        // Not a ROM for any platform, behind what looks like a copier header
+        let data: Vec<u8> = (0..1536u32).map(|i| (i % 251) as u8 | 0x80).collect();
+        let loaded = roms_from_bytes(Path::new("unknown"), data.clone(), None).unwrap();
+
+        assert!(loaded[0].rom.is_err());
+        let hashes = loaded[0].hashes().unwrap();
+        assert_eq!(hashes.file.size, 1536);
+        assert_eq!(hashes.payload().size, 1024);
+        assert_eq!(
+            hashes.payload(),
+            &hash::hash_reader(&data[512..], 0).unwrap().file
+        );
+    }
+
+    #[test]
+    fn only_hashes_raw_files_when_reading_fails() {
+        let data = nes_rom();
+        let loaded = roms_from_bytes(Path::new("game.nes"), data, None).unwrap();
+
+        assert!(loaded[0].rom.is_ok());
+        assert!(loaded[0].raw_hashes.is_none());
+    }
+
+    #[test]
+    fn keeps_the_rest_of_an_archive_with_a_broken_entry() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for name in ["a.nes", "b.nes", "c.nes"] {
+            writer
+                .start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut writer, &nes_rom()).unwrap();
+        }
+        let mut archive = writer.finish().unwrap().into_inner();
+
+        // Break the local header of the second file
+        let second = archive
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| *w == b"PK\x03\x04")
+            .nth(1)
+            .unwrap()
+            .0;
+        archive[second] = 0;
+
+        let loaded = roms_from_bytes(Path::new("games.zip"), archive, None).unwrap();
+
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded[0].entry.as_deref(), Some("a.nes"));
+        assert!(loaded[0].rom.is_ok());
+        assert!(loaded[1].entry.is_none());
+        assert!(loaded[1].rom.is_err());
+        assert_eq!(loaded[2].entry.as_deref(), Some("c.nes"));
+        assert!(loaded[2].rom.is_ok());
+    }
+}
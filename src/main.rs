@@ -1,10 +1,18 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::{Parser, Subcommand};
-use env_logger;
+use log::info;
+use rayon::prelude::*;
+use romboss::metadata::Metadata;
+use romboss::platform::{self, RomInfo};
+use romboss::{archive, audit, dat, rename};
+use romboss::{Loaded, Platform, Rom};
+use serde::ser::{SerializeSeq, Serializer};
 use serde::Serialize;
-use std::path::PathBuf;
-
-mod platform;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
 
 #[derive(Parser)]
 #[clap(name = "romboss")]
@@ -22,10 +30,162 @@ enum Commands {
         #[clap(long = "output", short = 'o', default_value = "json", possible_values = ["json", "yaml"])]
         output_format: String,
 
-        #[clap(long = "platform", short = 'p', default_value = "auto", possible_values = ["auto", "snes", "sfc", "megadrive", "genesis"])]
+        #[clap(long = "platform", short = 'p', default_value = "auto", possible_values = platform_values())]
+        platform: String,
+
+        // Add the details all platforms have in common, under the same names for each
+        #[clap(long = "normalized")]
+        normalized: bool,
+    },
+
+    FixChecksum {
+        #[clap(required = true, parse(from_os_str))]
+        path: PathBuf,
+
+        // Where to write the repaired copy. Defaults to "<name>.fixed.<ext>" beside the original.
+        #[clap(parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        #[clap(long = "platform", short = 'p', default_value = "auto", possible_values = fixable_platform_values())]
+        platform: String,
+    },
+
+    Icon {
+        #[clap(required = true, parse(from_os_str))]
+        path: PathBuf,
+
+        // Defaults to the ROM's name with a ".png" extension
+        #[clap(parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        // Write DSi animated icons as one PNG per frame instead of an APNG
+        #[clap(long = "frames")]
+        frames: bool,
+    },
+
+    ListFiles {
+        #[clap(required = true, parse(from_os_str))]
+        path: PathBuf,
+
+        #[clap(long = "output", short = 'o', default_value = "json", possible_values = ["json", "yaml"])]
+        output_format: String,
+    },
+
+    Extract {
+        #[clap(required = true, parse(from_os_str))]
+        path: PathBuf,
+
+        #[clap(required = true, parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    Pack {
+        #[clap(required = true, parse(from_os_str))]
+        input: PathBuf,
+
+        #[clap(required = true, parse(from_os_str))]
+        output: PathBuf,
+
+        // Stop at the end of the last file instead of padding out to the card size
+        #[clap(long = "trim")]
+        trim: bool,
+    },
+
+    Convert {
+        #[clap(required = true, parse(from_os_str))]
+        path: PathBuf,
+
+        // Defaults to the ROM's name with the extension for the new byte order
+        #[clap(parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        // "z64" is big-endian, "v64" byte-swapped and "n64" little-endian
+        #[clap(long = "to", short = 't', required = true, possible_values = ["z64", "v64", "n64"])]
+        byte_order: String,
+    },
+
+    Match {
+        #[clap(long = "dat", short = 'd', required = true, parse(from_os_str))]
+        dat: PathBuf,
+
+        #[clap(required = true, min_values = 1, parse(from_os_str))]
+        paths: Vec<PathBuf>,
+
+        #[clap(long = "output", short = 'o', default_value = "json", possible_values = ["json", "yaml"])]
+        output_format: String,
+
+        #[clap(long = "platform", short = 'p', default_value = "auto", possible_values = platform_values())]
+        platform: String,
+    },
+
+    Rename {
+        #[clap(long = "dat", short = 'd', required = true, parse(from_os_str))]
+        dat: PathBuf,
+
+        #[clap(required = true, min_values = 1, parse(from_os_str))]
+        paths: Vec<PathBuf>,
+
+        // Copy recognised ROMs into this directory instead of renaming them in place
+        #[clap(long = "target", short = 't', parse(from_os_str))]
+        target: Option<PathBuf>,
+
+        // Only print the planned moves
+        #[clap(long = "dry-run", short = 'n')]
+        dry_run: bool,
+
+        #[clap(long = "on-collision", default_value = "skip", possible_values = ["skip", "suffix", "overwrite"])]
+        on_collision: String,
+
+        // Defaults to "romboss-undo-<timestamp>.json" in the current directory
+        #[clap(long = "undo-log", parse(from_os_str))]
+        undo_log: Option<PathBuf>,
+
+        #[clap(long = "platform", short = 'p', default_value = "auto", possible_values = platform_values())]
         platform: String,
     },
 
+    Undo {
+        #[clap(required = true, parse(from_os_str))]
+        undo_log: PathBuf,
+    },
+
+    Audit {
+        #[clap(long = "dat", short = 'd', required = true, parse(from_os_str))]
+        dat: PathBuf,
+
+        #[clap(required = true, min_values = 1, parse(from_os_str))]
+        directories: Vec<PathBuf>,
+
+        #[clap(long = "output", short = 'o', default_value = "json", possible_values = ["json", "yaml"])]
+        output_format: String,
+
+        // Also write a CSV with one row per game or file
+        #[clap(long = "csv", parse(from_os_str))]
+        csv: Option<PathBuf>,
+
+        // Also write a CSV with the summary counts
+        #[clap(long = "summary-csv", parse(from_os_str))]
+        summary_csv: Option<PathBuf>,
+    },
+
+    Scan {
+        // Files, directories (scanned recursively) or glob patterns
+        #[clap(required = true, min_values = 1)]
+        paths: Vec<String>,
+
+        // "jsonl" writes one record per line, "json" writes a single array
+        #[clap(long = "output", short = 'o', default_value = "jsonl", possible_values = ["jsonl", "json"])]
+        output_format: String,
+
+        // Number of worker threads. Defaults to one per CPU.
+        #[clap(long = "jobs", short = 'j', default_value = "0")]
+        jobs: usize,
+
+        // Add the details all platforms have in common, under the same names for each
+        #[clap(long = "normalized")]
+        normalized: bool,
+    },
+
     Version {},
 }
 
@@ -48,98 +208,613 @@ fn main() -> Result<()> {
             path,
             output_format,
             platform: platform_label,
+            normalized,
+        } => {
+            let mut loaded = roms_from_path(path, platform_label)?;
+
+            // Archives holding several ROMs get a record for each of them
+            if loaded.len() != 1 {
+                let records: Vec<ScanRecord> = loaded
+                    .into_iter()
+                    .map(|l| ScanRecord::new(l, *normalized))
+                    .collect();
+                return print_serializable_rom(&records, output_format);
+            }
+
+            let rom = loaded.remove(0).rom?;
+
+            match normalized {
+                true => print_serializable_rom(&NormalizedRom::new(&rom), output_format),
+                false => print_serializable_rom(&rom.info, output_format),
+            }
+        }
+
+        Commands::FixChecksum {
+            path,
+            output,
+            platform: platform_label,
+        } => {
+            // A ROM in an archive is fixed in a bare copy, named after the ROM and put next
+            // to the archive.
+            let entry = archive::single_entry(path)?;
+            let rom_path = match entry.as_ref().and_then(|e| Path::new(&e.name).file_name()) {
+                Some(name) => path.with_file_name(name),
+                None => path.to_path_buf(),
+            };
+
+            let output = match output {
+                Some(output) => output.to_path_buf(),
+                None => fixed_copy_path(&rom_path),
+            };
+
+            if output.exists() && output.canonicalize()? == path.canonicalize()? {
+                bail!("The output path must be different from the original ROM");
+            }
+
+            let choice = platform_choice(platform_label)?;
+            let platform = match &entry {
+                Some(entry) => {
+                    romboss::resolve_platform(&mut entry.reader(), entry.size(), &rom_path, choice)?
+                }
+                None => {
+                    let mut f =
+                        File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+                    let size = f.metadata()?.len();
+                    romboss::resolve_platform(&mut f, size, path, choice)?
+                }
+            };
+
+            let c = platform::find(platform).fix_checksum(path, &output)?;
+
+            if c.valid {
+                println!("Checksum {:#06X} was already valid", c.declared);
+            } else {
+                println!(
+                    "Fixed checksum {:#06X} -> {:#06X}",
+                    c.declared, c.calculated
+                );
+            }
+            println!("Wrote {:?}", output);
+
+            Ok(())
+        }
+
+        Commands::Icon {
+            path,
+            output,
+            frames,
         } => {
-            let platform = match platform_label.as_str() {
-                "auto" => detect_rom_platform(&path).context(concat!(
-                    "Could not automatically determine the platform.",
-                    "Use the '-p' flag to specify a platform explicitly"
-                ))?,
-                other => parse_platform_label(other)
-                    .with_context(|| format!("Unrecognised platform label '{}'", other))?,
+            let output = match output {
+                Some(output) => output.to_path_buf(),
+                None => path.with_extension("png"),
             };
 
-            let rom = rom_from_file(&path, platform)?;
+            for written in platform::nds::export_icon(path, &output, *frames)? {
+                println!("Wrote {:?}", written);
+            }
+
+            Ok(())
+        }
 
-            // TODO: This is obviously redundant and should be solvable with generics or however
-            // Rust might let you say "here's something that implements this trait" (Serialize).
-            match rom {
-                Rom::SuperNintendo(r) => print_serializable_rom(&r, output_format)?,
-                Rom::MegaDrive(r) => print_serializable_rom(&r, output_format)?,
-                Rom::NintendoDS(r) => print_serializable_rom(&r, output_format)?,
+        Commands::ListFiles {
+            path,
+            output_format,
+        } => {
+            let fs = platform::nds::list_files(path)?;
+            print_serializable_rom(&fs, output_format)
+        }
+
+        Commands::Extract { path, output } => {
+            let fs = platform::nds::extract_files(path, output)?;
+            println!(
+                "Extracted {} files and {} overlays to {:?}",
+                fs.files.len(),
+                fs.arm9_overlays.len() + fs.arm7_overlays.len(),
+                output
+            );
+
+            Ok(())
+        }
+
+        Commands::Convert {
+            path,
+            output,
+            byte_order,
+        } => {
+            let target = platform::n64::parse_byte_order_label(byte_order)
+                .with_context(|| format!("Unrecognised byte order '{}'", byte_order))?;
+            let output = match output {
+                Some(output) => output.to_path_buf(),
+                None => path.with_extension(target.extension()),
             };
+
+            if output.exists() && output.canonicalize()? == path.canonicalize()? {
+                bail!("The output path must be different from the original ROM");
+            }
+
+            let original = platform::n64::convert(path, &output, target)?;
+            println!("Converted {:?} -> {:?}", original, target);
+            println!("Wrote {:?}", output);
+
+            Ok(())
+        }
+
+        Commands::Match {
+            dat,
+            paths,
+            output_format,
+            platform: platform_label,
+        } => {
+            let dat = dat::dat_from_file(dat)?;
+            let mut results = Vec::new();
+
+            for path in paths {
+                let loaded = match roms_from_path(path, platform_label) {
+                    Ok(loaded) => loaded,
+                    Err(err) => {
+                        results.push(dat::RomMatch::failed(path, None, &err));
+                        continue;
+                    }
+                };
+
+                // Files that couldn't be read as a ROM are matched on their raw hashes.
+                for Loaded {
+                    path,
+                    entry,
+                    rom,
+                    raw_hashes,
+                } in loaded
+                {
+                    results.push(match (rom, raw_hashes) {
+                        (Ok(rom), _) => dat::match_rom(
+                            &dat,
+                            &path,
+                            entry,
+                            rom.hashes().payload(),
+                            &rom.header_values(),
+                        ),
+                        (Err(err), Some(hashes)) => dat::RomMatch {
+                            error: Some(format!("{:#}", err)),
+                            ..dat::match_rom(&dat, &path, entry, hashes.payload(), &[])
+                        },
+                        (Err(err), None) => dat::RomMatch::failed(&path, entry, &err),
+                    });
+                }
+            }
+
+            print_serializable_rom(&results, output_format)
+        }
+
+        Commands::Rename {
+            dat,
+            paths,
+            target,
+            dry_run,
+            on_collision,
+            undo_log,
+            platform: platform_label,
+        } => {
+            let dat = dat::dat_from_file(dat)?;
+            let collision = rename::parse_collision_label(on_collision)
+                .with_context(|| format!("Unrecognised collision mode '{}'", on_collision))?;
+            let mut planner = rename::Planner::new(target.as_deref(), collision);
+
+            for path in paths {
+                let mut loaded = match roms_from_path(path, platform_label) {
+                    Ok(loaded) => loaded,
+                    Err(err) => {
+                        println!("Skipping {:?}: {:#}", path, err);
+                        continue;
+                    }
+                };
+
+                // The archive is what gets renamed, so it has to hold a single ROM
+                if loaded.len() != 1 {
+                    println!("Skipping {:?}: archive holds {} files", path, loaded.len());
+                    continue;
+                }
+
+                // Files that couldn't be read as a ROM are matched on their raw hashes.
+                let Loaded {
+                    entry,
+                    rom,
+                    raw_hashes,
+                    ..
+                } = loaded.remove(0);
+                let (hashes, platform) = match (&rom, &raw_hashes) {
+                    (Ok(rom), _) => (rom.hashes(), Some(rom.platform())),
+                    (Err(_), Some(hashes)) => (hashes, None),
+                    (Err(err), None) => {
+                        println!("Skipping {:?}: {:#}", path, err);
+                        continue;
+                    }
+                };
+
+                let game = match dat.find(hashes.payload()) {
+                    Some((game, _)) => game,
+                    None => {
+                        println!("Skipping {:?}: not in the DAT", path);
+                        continue;
+                    }
+                };
+
+                let extension = match entry {
+                    Some(entry) => archive_extension(path, &entry, platform),
+                    None => extension_for(path, platform),
+                };
+                if let Some(reason) = planner.plan(path, &game.name, &extension) {
+                    println!("Skipping {:?}: {}", path, reason);
+                }
+            }
+
+            let moves = planner.finish();
+            if *dry_run {
+                for m in &moves {
+                    println!("Would {:?} {:?} -> {:?}", m.action, m.from, m.to);
+                }
+                return Ok(());
+            }
+
+            let undo_log = match undo_log {
+                Some(undo_log) => undo_log.to_path_buf(),
+                None => {
+                    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+                    PathBuf::from(format!("romboss-undo-{}.json", now.as_secs()))
+                }
+            };
+
+            rename::execute(&moves, &undo_log)
+        }
+
+        Commands::Undo { undo_log } => {
+            let report = rename::undo(undo_log)?;
+            for m in &report.reverted {
+                println!("Reverted {:?} {:?} -> {:?}", m.action, m.from, m.to);
+                if let Some(backup) = &m.backup {
+                    println!("Restored {:?} from {:?}", m.to, backup);
+                }
+            }
+            for (m, err) in &report.failed {
+                println!("Failed to revert {:?} -> {:?}: {:#}", m.from, m.to, err);
+            }
+
+            if !report.failed.is_empty() {
+                bail!(
+                    "{} moves couldn't be reverted and are still in {:?}",
+                    report.failed.len(),
+                    undo_log
+                );
+            }
+
+            Ok(())
+        }
+
+        Commands::Audit {
+            dat,
+            directories,
+            output_format,
+            csv,
+            summary_csv,
+        } => {
+            let dat = dat::dat_from_file(dat)?;
+            let mut scanned = Vec::new();
+
+            for found in files_in(directories) {
+                scanned.extend(load(found, |path| roms_from_path(path, "auto")));
+            }
+
+            let report = audit::audit(&dat, scanned);
+
+            if let Some(csv) = csv {
+                audit::write_csv(&report, csv)?;
+            }
+
+            if let Some(csv) = summary_csv {
+                audit::write_summary_csv(&report, csv)?;
+            }
+
+            print_serializable_rom(&report, output_format)
+        }
+
+        Commands::Scan {
+            paths,
+            output_format,
+            jobs,
+            normalized,
+        } => {
+            if !["jsonl", "json"].contains(&output_format.as_str()) {
+                bail!("Unsupported format {}", output_format);
+            }
+
+            let files = expand_paths(paths)?;
+            info!("Scanning {} files", files.len());
+
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(*jobs).build()?;
+
+            // Records are written as soon as they're done, in whatever order that is, so
+            // nothing has to be held on to for the whole scan. The workers stop once the
+            // receiver is gone, such as after failing to write.
+            let (sender, receiver) = mpsc::sync_channel(SCAN_BUFFER);
+            let normalized = *normalized;
+            pool.spawn(move || {
+                let _ = files
+                    .into_par_iter()
+                    .try_for_each_with(sender, |sender, found| {
+                        load(found, read_for_scan).into_iter().try_for_each(|l| {
+                            sender.send(ScanRecord::new(l, normalized)).map_err(|_| ())
+                        })
+                    });
+            });
+
+            let mut out = io::stdout().lock();
+            match output_format.as_str() {
+                "jsonl" => {
+                    for record in receiver {
+                        serde_json::to_writer(&mut out, &record)?;
+                        writeln!(out)?;
+                    }
+                }
+                _ => {
+                    let mut serializer = serde_json::Serializer::pretty(&mut out);
+                    let mut array = serializer.serialize_seq(None)?;
+                    for record in receiver {
+                        array.serialize_element(&record)?;
+                    }
+                    SerializeSeq::end(array)?;
+                    writeln!(out)?;
+                }
+            }
+
+            Ok(())
+        }
+
+        Commands::Pack {
+            input,
+            output,
+            trim,
+        } => {
+            let summary = platform::nds::pack::pack(input, output, *trim)?;
+            println!(
+                "Packed {} files and {} overlays into {:?} ({} bytes used)",
+                summary.files, summary.overlays, output, summary.total_used_rom_size
+            );
+
             Ok(())
         }
     }
 }
 
-fn print_serializable_rom<T>(rom: &T, format: &str) -> Result<()>
-where
-    T: Serialize,
-{
-    match format {
-        "json" => println!("{}", serde_json::to_string_pretty(&rom)?),
-        "yaml" => println!("{}", serde_yaml::to_string(&rom)?),
-        fmt => bail!("Unsupported format {}", fmt),
+// Builds "game.fixed.bin" from "game.bin".
+fn fixed_copy_path(path: &Path) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(".fixed");
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
     }
 
-    Ok(())
+    path.with_file_name(name)
 }
 
-#[derive(Serialize, Debug)]
-enum Rom {
-    SuperNintendo(platform::snes::Rom),
-    MegaDrive(platform::megadrive::Rom),
-    NintendoDS(platform::nds::Rom),
+// How many finished scan records can wait to be written before the workers hold off.
+const SCAN_BUFFER: usize = 64;
+
+// Reading from this path means reading from stdin.
+const STDIN_PATH: &str = "-";
+
+// Reads the ROM at the path, or from stdin when the path is "-".
+fn roms_from_path(path: &Path, label: &str) -> Result<Vec<Loaded>> {
+    let platform = platform_choice(label)?;
+
+    if path != Path::new(STDIN_PATH) {
+        return romboss::roms_from_path(path, platform);
+    }
+
+    // Stdin can't seek, so everything is read into memory first. There's no file name to
+    // go on, so the platform is detected from the contents alone.
+    let mut data = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut data)
+        .context("Failed to read from stdin")?;
+
+    romboss::roms_from_bytes(path, data, platform)
 }
 
-fn detect_rom_platform(path: &PathBuf) -> Option<Platform> {
-    // For now, only detect from the path.
-    // A future enhancement may be detecting based on file contents, like mime magic.
-    platform_from_path(path)
+// Values accepted by "--platform". "auto" leaves the platform up to detection.
+fn platform_values() -> Vec<&'static str> {
+    let mut values = vec!["auto"];
+    values.extend(romboss::platform_labels());
+    values
 }
 
-#[derive(Debug)]
-enum Platform {
-    MegaDrive,
-    NintendoDS,
-    SuperNintendo,
+fn fixable_platform_values() -> Vec<&'static str> {
+    let mut values = vec!["auto"];
+    values.extend(romboss::fixable_platform_labels());
+    values
 }
 
-fn parse_platform_label(label: &str) -> Option<Platform> {
+// "auto" leaves the platform up to detection.
+fn platform_choice(label: &str) -> Result<Option<Platform>> {
     match label {
-        "snes" | "sfc" => return Some(Platform::SuperNintendo),
-        "megadrive" | "genesis" => return Some(Platform::MegaDrive),
-        "ds" => return Some(Platform::NintendoDS),
-        _ => None,
+        "auto" => Ok(None),
+        other => romboss::parse_platform_label(other)
+            .map(Some)
+            .with_context(|| format!("Unrecognised platform label '{}'", other)),
     }
 }
 
-fn platform_from_path(path: &PathBuf) -> Option<Platform> {
-    let ext = path.extension().unwrap().to_ascii_lowercase();
-    let ext = ext.to_str().unwrap();
+// Reads everything in one file for a scan. A bug in one of the parsers shouldn't take
+// the rest of the scan down with it, so panics become errors for that file.
+fn read_for_scan(path: &Path) -> Result<Vec<Loaded>> {
+    panic::catch_unwind(|| roms_from_path(path, "auto")).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_string());
+
+        Err(anyhow!("Crashed while reading the file: {}", message))
+    })
+}
+
+// One file's result in a scan. Failures are recorded instead of stopping the scan.
+#[derive(Serialize, Debug)]
+struct ScanRecord {
+    path: PathBuf,
+
+    // Name of the file inside the archive, when the path is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entry: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    normalized: Option<Metadata>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rom: Option<Rom>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl ScanRecord {
+    fn new(loaded: Loaded, normalized: bool) -> ScanRecord {
+        let Loaded {
+            path, entry, rom, ..
+        } = loaded;
 
-    match ext {
-        "smc" | "sfc" | "swc" => return Some(Platform::SuperNintendo),
-        "gen" | "md" | "smd" => return Some(Platform::MegaDrive),
-        "nds" => return Some(Platform::NintendoDS),
-        _ => None,
+        match rom {
+            Ok(rom) => ScanRecord {
+                path,
+                entry,
+                normalized: normalized.then(|| rom.metadata()),
+                rom: Some(rom),
+                error: None,
+            },
+            Err(err) => ScanRecord {
+                path,
+                entry,
+                normalized: None,
+                rom: None,
+                error: Some(format!("{:#}", err)),
+            },
+        }
     }
 }
 
-fn rom_from_file(path: &PathBuf, platform: Platform) -> Result<Rom> {
-    match platform {
-        Platform::SuperNintendo => {
-            let rom = platform::snes::rom_from_file(path)?;
-            Ok(Rom::SuperNintendo(rom))
+// The normalized details next to the fields the platform's header has
+#[derive(Serialize)]
+struct NormalizedRom<'a> {
+    normalized: Metadata,
+
+    // Flattened in, so the output is the same shape as without --normalized
+    #[serde(flatten)]
+    info: &'a dyn RomInfo,
+}
+
+impl NormalizedRom<'_> {
+    fn new(rom: &Rom) -> NormalizedRom<'_> {
+        NormalizedRom {
+            normalized: rom.metadata(),
+            info: rom.info.as_ref(),
         }
-        Platform::MegaDrive => {
-            let rom = platform::megadrive::rom_from_file(path)?;
-            Ok(Rom::MegaDrive(rom))
+    }
+}
+
+// A file to read. Paths that couldn't be listed keep the error instead, so they end up
+// as error records like files that couldn't be read.
+type Found = std::result::Result<PathBuf, (PathBuf, anyhow::Error)>;
+
+// Expands glob patterns and directories into the files they contain.
+// Plain file paths are passed through as-is.
+fn expand_paths(patterns: &[String]) -> Result<Vec<Found>> {
+    let mut files = Vec::new();
+
+    for pattern in patterns {
+        let paths: Vec<Found> = match pattern.contains(['*', '?', '[']) {
+            true => glob::glob(pattern)
+                .with_context(|| format!("Invalid glob pattern '{}'", pattern))?
+                .map(|path| path.map_err(|err| (err.path().to_path_buf(), err.into())))
+                .collect(),
+            false => vec![Ok(PathBuf::from(pattern))],
+        };
+
+        for found in paths {
+            match found {
+                Ok(path) if path.is_dir() => files.extend(files_in(&[path])),
+                found => files.push(found),
+            }
         }
-        Platform::NintendoDS => {
-            let rom = platform::nds::rom_from_file(path)?;
-            Ok(Rom::NintendoDS(rom))
+    }
+
+    Ok(files)
+}
+
+// Every file in the directories and their subdirectories, in a stable order.
+fn files_in(directories: &[PathBuf]) -> Vec<Found> {
+    let mut files = Vec::new();
+
+    for dir in directories {
+        for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+            match entry {
+                Ok(entry) if entry.file_type().is_file() => files.push(Ok(entry.into_path())),
+                Ok(_) => {}
+                Err(err) => files.push(Err((err.path().unwrap_or(dir).to_path_buf(), err.into()))),
+            }
+        }
+    }
+
+    files
+}
+
+// Reads a found file with `read`. Any failure becomes a record of its own.
+fn load(found: Found, read: impl Fn(&Path) -> Result<Vec<Loaded>>) -> Vec<Loaded> {
+    let (path, loaded) = match found {
+        Ok(path) => {
+            let loaded = read(&path);
+            (path, loaded)
+        }
+        Err((path, err)) => (path, Err(err)),
+    };
+
+    loaded.unwrap_or_else(|err| vec![Loaded::failed(&path, err)])
+}
+
+fn print_serializable_rom<T>(rom: &T, format: &str) -> Result<()>
+where
+    T: Serialize,
+{
+    match format {
+        "json" => println!("{}", serde_json::to_string_pretty(&rom)?),
+        "yaml" => println!("{}", serde_yaml::to_string(&rom)?),
+        fmt => bail!("Unsupported format {}", fmt),
+    }
+
+    Ok(())
+}
+
+// Keeps the current extension when it's one for this platform, since collections often
+// settle on ".smc" over ".sfc" and the like. Files that couldn't be read as a ROM have no
+// platform, so they keep whatever extension they have.
+fn extension_for(path: &Path, platform: Option<Platform>) -> String {
+    match (path.extension(), platform) {
+        (Some(ext), None) => ext.to_string_lossy().to_string(),
+        (Some(ext), Some(platform)) if romboss::platform_from_path(path) == Some(platform) => {
+            ext.to_string_lossy().to_string()
+        }
+        (_, Some(platform)) => romboss::default_extension(platform).to_string(),
+        (None, None) => "bin".to_string(),
+    }
+}
+
+// Archives keep their own extension. A gzipped ROM keeps the ROM's extension in front of
+// it as well ("game.sfc.gz"), since the name is all that says what's inside.
+fn archive_extension(path: &Path, entry: &str, platform: Option<Platform>) -> String {
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+
+    match archive::detect_format(path) {
+        Ok(Some(archive::Format::Gzip)) => {
+            format!("{}.{}", extension_for(Path::new(entry), platform), ext)
         }
-        val => bail!("Unsupported platform {:?}", val),
+        _ => ext.to_string(),
     }
 }
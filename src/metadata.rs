@@ -0,0 +1,83 @@
+use serde::Serialize;
+
+// The same details for every platform, with the same names and values.
+// Platforms leave out whatever their headers don't record.
+#[derive(Serialize, Debug, Default)]
+pub struct Metadata {
+    pub title: String,
+    pub alt_titles: Vec<String>,
+    pub regions: Vec<Region>,
+    pub publisher: Option<String>,
+    pub product_code: Option<String>,
+    pub revision: Option<String>,
+    // "YYYY-MM", or just "YYYY" when the month isn't known
+    pub release_date: Option<String>,
+    // Size of the ROM data, without any copier header
+    pub rom_size: u64,
+    pub save_type: Option<SaveType>,
+    pub video_standard: Option<VideoStandard>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Japan,
+    NorthAmerica,
+    Europe,
+    Asia,
+    Australia,
+    Brazil,
+    China,
+    Korea,
+    // Not locked to any region
+    World,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum VideoStandard {
+    NTSC,
+    PAL,
+    // Made to run on both
+    Multi,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum SaveType {
+    // Battery-backed RAM
+    SRAM,
+    EEPROM,
+    Flash,
+}
+
+impl Region {
+    // Nintendo game codes end in a letter for the region the game was released in.
+    pub fn from_game_code(game_code: &str) -> Option<Region> {
+        match game_code.chars().nth(3)? {
+            'J' => Some(Region::Japan),
+            'E' => Some(Region::NorthAmerica),
+            'P' | 'D' | 'F' | 'H' | 'I' | 'S' | 'X' | 'Y' | 'Z' | 'V' => Some(Region::Europe),
+            'U' => Some(Region::Australia),
+            'C' => Some(Region::China),
+            'K' => Some(Region::Korea),
+            'A' | 'O' => Some(Region::World),
+            _ => None,
+        }
+    }
+}
+
+impl VideoStandard {
+    // Consoles sold in Europe, Australia and China were PAL. Everywhere else a game would be
+    // sold was NTSC, or close enough that it runs NTSC games.
+    pub fn for_regions(regions: &[Region]) -> Option<VideoStandard> {
+        let pal = regions
+            .iter()
+            .filter(|r| matches!(r, Region::Europe | Region::Australia | Region::China))
+            .count();
+
+        match pal {
+            _ if regions.is_empty() || regions.contains(&Region::World) => None,
+            0 => Some(VideoStandard::NTSC),
+            n if n == regions.len() => Some(VideoStandard::PAL),
+            _ => Some(VideoStandard::Multi),
+        }
+    }
+}
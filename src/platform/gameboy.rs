@@ -0,0 +1,517 @@
+use super::{RomInfo, RomPlatform};
+use crate::archive::{self, Source};
+use crate::hash::{self, RomHashes};
+use crate::metadata::{Metadata, Region, SaveType};
+use crate::Platform;
+use anyhow::{Context, Result};
+use binread::{io::Cursor, io::Read, io::Seek, BinRead};
+use log::debug;
+use phf::phf_map;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, SeekFrom};
+use std::path::Path;
+
+// The header runs from 0x100 to 0x14F, right after the interrupt vectors.
+const HEADER_START: u64 = 0x100;
+const HEADER_SIZE: usize = 0x50;
+
+// The header checksum covers the title through the mask ROM version.
+const HEADER_CHECKSUM_START: usize = 0x34;
+const HEADER_CHECKSUM_END: usize = 0x4D;
+
+// The global checksum is stored at 0x14E and left out of its own sum.
+const GLOBAL_CHECKSUM_OFFSET: u64 = 0x14E;
+
+// The boot ROM compares this against the cartridge and refuses to start when it differs,
+// so every licensed game has it.
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Serialize, Debug)]
+pub enum ColorSupport {
+    // Original Game Boy game
+    None,
+    // Runs on both, with colour on the Game Boy Color
+    Enhanced,
+    // Game Boy Color only
+    Required,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Checksum {
+    pub declared: u16,
+    pub calculated: u16,
+    pub valid: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rom {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub color_support: ColorSupport,
+    pub super_game_boy_support: bool,
+    pub cartridge_type: String,
+    pub has_battery: bool,
+    pub rom_size: u32,
+    pub ram_size: u32,
+    pub destination: String,
+    pub licensee_code: String,
+    pub mask_rom_version: u8,
+    pub logo_valid: bool,
+    pub header_checksum: Checksum,
+    pub global_checksum: Checksum,
+    pub hashes: RomHashes,
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+#[allow(dead_code)]
+pub struct RomHeader {
+    #[br(count = 4)]
+    entry_point: Vec<u8>,
+
+    #[br(count = 48)]
+    logo: Vec<u8>,
+
+    // Later games use the end of the title for the manufacturer code and colour flag
+    #[br(count = 16)]
+    title: Vec<u8>,
+
+    #[br(count = 2)]
+    new_licensee_code: Vec<u8>,
+
+    sgb_flag: u8,
+    cartridge_type: u8,
+    rom_size: u8,
+    ram_size: u8,
+
+    // 0x00: Japan, 0x01: everywhere else
+    destination_code: u8,
+
+    // 0x33 means the new licensee code is used instead
+    old_licensee_code: u8,
+
+    mask_rom_version: u8,
+    header_checksum: u8,
+    global_checksum: u16,
+}
+
+fn bytes_to_stripped_string(bytes: &[u8]) -> String {
+    let s = String::from_utf8_lossy(bytes);
+
+    s.trim_end_matches(char::from(0x00)).trim_end().to_string()
+}
+
+impl RomHeader {
+    fn color_flag(&self) -> u8 {
+        self.title[15]
+    }
+
+    pub fn color_support(&self) -> ColorSupport {
+        match self.color_flag() {
+            0xC0 => ColorSupport::Required,
+            0x80 => ColorSupport::Enhanced,
+            _ => ColorSupport::None,
+        }
+    }
+
+    // Colour games cut the title down to 11 characters to fit in a manufacturer code.
+    // Early ones kept a 15 character title though, so the code only counts when it
+    // looks like one.
+    pub fn title(&self) -> String {
+        match self.manufacturer_code() {
+            Some(_) => bytes_to_stripped_string(&self.title[..11]),
+            None if self.color_flag() & 0x80 != 0 => bytes_to_stripped_string(&self.title[..15]),
+            None => bytes_to_stripped_string(&self.title),
+        }
+    }
+
+    pub fn manufacturer_code(&self) -> Option<String> {
+        let code = &self.title[11..15];
+        let looks_like_code = code
+            .iter()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit());
+
+        if self.color_flag() & 0x80 == 0 || !looks_like_code {
+            return None;
+        }
+
+        Some(bytes_to_stripped_string(code))
+    }
+
+    pub fn licensee_code(&self) -> String {
+        match self.old_licensee_code {
+            0x33 => bytes_to_stripped_string(&self.new_licensee_code),
+            code => format!("{:02X}", code),
+        }
+    }
+
+    // The Super Game Boy only enables its features when the old licensee code says the
+    // new one is in use as well.
+    pub fn super_game_boy_support(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+
+    pub fn cartridge_type_description(&self) -> String {
+        static CARTRIDGE_TYPES: phf::Map<u8, &'static str> = phf_map! {
+            0x00u8 => "ROM only",
+            0x01u8 => "MBC1",
+            0x02u8 => "MBC1+RAM",
+            0x03u8 => "MBC1+RAM+Battery",
+            0x05u8 => "MBC2",
+            0x06u8 => "MBC2+Battery",
+            0x08u8 => "ROM+RAM",
+            0x09u8 => "ROM+RAM+Battery",
+            0x0Bu8 => "MMM01",
+            0x0Cu8 => "MMM01+RAM",
+            0x0Du8 => "MMM01+RAM+Battery",
+            0x0Fu8 => "MBC3+Timer+Battery",
+            0x10u8 => "MBC3+Timer+RAM+Battery",
+            0x11u8 => "MBC3",
+            0x12u8 => "MBC3+RAM",
+            0x13u8 => "MBC3+RAM+Battery",
+            0x19u8 => "MBC5",
+            0x1Au8 => "MBC5+RAM",
+            0x1Bu8 => "MBC5+RAM+Battery",
+            0x1Cu8 => "MBC5+Rumble",
+            0x1Du8 => "MBC5+Rumble+RAM",
+            0x1Eu8 => "MBC5+Rumble+RAM+Battery",
+            0x20u8 => "MBC6",
+            0x22u8 => "MBC7+Sensor+Rumble+RAM+Battery",
+            0xFCu8 => "Pocket Camera",
+            0xFDu8 => "Bandai TAMA5",
+            0xFEu8 => "HuC3",
+            0xFFu8 => "HuC1+RAM+Battery",
+        };
+
+        match CARTRIDGE_TYPES.get(&self.cartridge_type) {
+            Some(desc) => desc.to_string(),
+            _ => format!("Unknown {:#x}", self.cartridge_type),
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        const BATTERY_TYPES: [u8; 13] = [
+            0x03, 0x06, 0x09, 0x0D, 0x0F, 0x10, 0x13, 0x1B, 0x1E, 0x22, 0xFC, 0xFE, 0xFF,
+        ];
+
+        BATTERY_TYPES.contains(&self.cartridge_type)
+    }
+
+    // Stored as a shift of 32 kB. A few odd sizes from the early days have their own codes.
+    pub fn rom_size(&self) -> u32 {
+        match self.rom_size {
+            0x52 => 72 * 0x4000,
+            0x53 => 80 * 0x4000,
+            0x54 => 96 * 0x4000,
+            code => 0x8000u32.checked_shl(code.into()).unwrap_or(0),
+        }
+    }
+
+    pub fn ram_size(&self) -> u32 {
+        match self.ram_size {
+            0x01 => 2 * 1024,
+            0x02 => 8 * 1024,
+            0x03 => 32 * 1024,
+            0x04 => 128 * 1024,
+            0x05 => 64 * 1024,
+            _ => 0,
+        }
+    }
+
+    pub fn destination(&self) -> String {
+        match self.destination_code {
+            0x00 => "Japan".to_string(),
+            0x01 => "Overseas".to_string(),
+            other => format!("Unknown {:#x}", other),
+        }
+    }
+
+    pub fn logo_checks_out(&self) -> bool {
+        self.logo == NINTENDO_LOGO
+    }
+}
+
+pub struct GameBoy;
+
+impl RomPlatform for GameBoy {
+    fn platform(&self) -> Platform {
+        Platform::GameBoy
+    }
+
+    fn name(&self) -> &'static str {
+        "gb"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["gbc"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["gb", "gbc"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        Ok(Box::new(rom_from_reader(&mut reader, size)?))
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        vec![("title", self.title.to_string())]
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            title: self.title.to_string(),
+            alt_titles: vec![],
+            regions: match self.destination.as_str() {
+                "Japan" => vec![Region::Japan],
+                _ => vec![],
+            },
+            publisher: Some(self.licensee_code.to_string()),
+            product_code: self.manufacturer_code.clone(),
+            revision: Some(self.mask_rom_version.to_string()),
+            release_date: None,
+            rom_size: self.hashes.payload().size,
+            save_type: self.has_battery.then_some(SaveType::SRAM),
+            video_standard: None,
+        }
+    }
+}
+
+// Estimates how likely it is that the file is a Game Boy ROM, from 0 to 100.
+//
+// Licensed games can't run without an exact copy of the Nintendo logo in the header.
+pub fn probe<R: Read + Seek>(file: &mut R) -> Result<u8> {
+    let mut buffer = [0; 48];
+    file.seek(SeekFrom::Start(0x104))?;
+    file.read_exact(&mut buffer)?;
+
+    if buffer == NINTENDO_LOGO {
+        return Ok(95);
+    }
+
+    Ok(0)
+}
+
+pub fn rom_from_file(path: &Path) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size());
+    }
+
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size)
+}
+
+// Reads the ROM from the first `size` bytes of the reader.
+pub fn rom_from_reader<R: Read + Seek>(f: &mut R, size: u64) -> Result<Rom> {
+    let mut buffer = [0; HEADER_SIZE];
+    f.seek(SeekFrom::Start(HEADER_START))?;
+    f.read_exact(&mut buffer)?;
+
+    debug!("Read header bytes: {:?}", buffer);
+    let header =
+        RomHeader::read(&mut Cursor::new(&buffer)).context("Failed to parse ROM header")?;
+    debug!("Read ROM header: {:?}", header);
+
+    let header_checksum = calculate_header_checksum(&buffer);
+    let global_checksum = calculate_global_checksum(f, size)?;
+
+    f.seek(SeekFrom::Start(0))?;
+    let hashes = hash::hash_reader(f.take(size), 0)?;
+
+    Ok(rom_from_header(
+        &header,
+        header_checksum,
+        global_checksum,
+        hashes,
+    ))
+}
+
+// Starts at zero and subtracts each byte plus one. The boot ROM locks up when it's wrong.
+fn calculate_header_checksum(header: &[u8]) -> u8 {
+    header[HEADER_CHECKSUM_START..HEADER_CHECKSUM_END]
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1))
+}
+
+// Sums every byte in the ROM except the checksum itself. Nothing checks it on real
+// hardware, so it's only a hint about whether the dump is intact.
+pub fn calculate_global_checksum<R: Read + Seek>(file: &mut R, size: u64) -> Result<u16> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file.take(size));
+    let mut buffer = [0; 8192];
+    let mut sum: u16 = 0;
+    let mut pos = 0u64;
+
+    loop {
+        let len = reader.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+
+        for &byte in &buffer[..len] {
+            if pos != GLOBAL_CHECKSUM_OFFSET && pos != GLOBAL_CHECKSUM_OFFSET + 1 {
+                sum = sum.wrapping_add(byte as u16);
+            }
+            pos += 1;
+        }
+    }
+
+    Ok(sum)
+}
+
+fn rom_from_header(
+    header: &RomHeader,
+    header_checksum: u8,
+    global_checksum: u16,
+    hashes: RomHashes,
+) -> Rom {
+    Rom {
+        title: header.title(),
+        manufacturer_code: header.manufacturer_code(),
+        color_support: header.color_support(),
+        super_game_boy_support: header.super_game_boy_support(),
+        cartridge_type: header.cartridge_type_description(),
+        has_battery: header.has_battery(),
+        rom_size: header.rom_size(),
+        ram_size: header.ram_size(),
+        destination: header.destination(),
+        licensee_code: header.licensee_code(),
+        mask_rom_version: header.mask_rom_version,
+        logo_valid: header.logo_checks_out(),
+        header_checksum: Checksum {
+            declared: header.header_checksum.into(),
+            calculated: header_checksum.into(),
+            valid: header.header_checksum == header_checksum,
+        },
+        global_checksum: Checksum {
+            declared: header.global_checksum,
+            calculated: global_checksum,
+            valid: header.global_checksum == global_checksum,
+        },
+        hashes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A 32 kB cartridge with the logo and the given title field, and correct checksums
+    fn cartridge(title: &[u8; 16]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x134..0x144].copy_from_slice(title);
+        rom[0x14B] = 0x01;
+
+        let header = &rom[HEADER_START as usize..HEADER_START as usize + HEADER_SIZE];
+        rom[0x14D] = calculate_header_checksum(header);
+        let global = calculate_global_checksum(&mut Cursor::new(&rom), 0x8000).unwrap();
+        rom[0x14E..0x150].copy_from_slice(&global.to_be_bytes());
+
+        rom
+    }
+
+    fn read(rom: &[u8]) -> Rom {
+        rom_from_reader(&mut Cursor::new(rom), rom.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn calculates_header_checksum() {
+        // Tetris (World) (Rev 1)
+        let mut header = [0; HEADER_SIZE];
+        header[0x34..0x3A].copy_from_slice(b"TETRIS");
+        header[0x4B] = 0x01;
+        header[0x4C] = 0x01;
+
+        assert_eq!(calculate_header_checksum(&header), 0x0A);
+
+        // Only the title through the version count
+        header[0x4D..].fill(0xFF);
+        header[..0x34].fill(0xFF);
+        assert_eq!(calculate_header_checksum(&header), 0x0A);
+    }
+
+    #[test]
+    fn leaves_the_global_checksum_out_of_its_own_sum() {
+        let mut rom = vec![1; 0x8000];
+        rom[0x14E] = 0xFF;
+        rom[0x14F] = 0xFF;
+
+        let sum = calculate_global_checksum(&mut Cursor::new(&rom), 0x8000).unwrap();
+        assert_eq!(sum, 0x8000 - 2);
+    }
+
+    #[test]
+    fn validates_checksums() {
+        let mut data = cartridge(b"TETRIS\0\0\0\0\0\0\0\0\0\0");
+        let rom = read(&data);
+        assert!(rom.header_checksum.valid);
+        assert!(rom.global_checksum.valid);
+
+        data[0x7FFF] = 0x55;
+        let rom = read(&data);
+        assert!(rom.header_checksum.valid);
+        assert!(!rom.global_checksum.valid);
+    }
+
+    #[test]
+    fn splits_the_manufacturer_code_off_colour_titles() {
+        let rom = read(&cartridge(b"POKEMON_SLVAAXJ\x80"));
+
+        assert_eq!(rom.title, "POKEMON_SLV");
+        assert_eq!(rom.manufacturer_code.as_deref(), Some("AAXJ"));
+        assert!(matches!(rom.color_support, ColorSupport::Enhanced));
+    }
+
+    #[test]
+    fn keeps_15_character_titles_without_a_code() {
+        let rom = read(&cartridge(b"ZELDA\0\0\0\0\0\0\0\0\0\0\xC0"));
+
+        assert_eq!(rom.title, "ZELDA");
+        assert_eq!(rom.manufacturer_code, None);
+        assert!(matches!(rom.color_support, ColorSupport::Required));
+
+        // Lowercase doesn't look like a code either
+        let rom = read(&cartridge(b"MARIO TENNISabc\x80"));
+        assert_eq!(rom.title, "MARIO TENNISabc");
+        assert_eq!(rom.manufacturer_code, None);
+    }
+
+    #[test]
+    fn uses_all_16_characters_on_original_game_boy_titles() {
+        let rom = read(&cartridge(b"SUPER MARIOLANDX"));
+
+        assert_eq!(rom.title, "SUPER MARIOLANDX");
+        assert_eq!(rom.manufacturer_code, None);
+        assert!(matches!(rom.color_support, ColorSupport::None));
+    }
+
+    #[test]
+    fn probes_for_the_logo() {
+        let mut data = cartridge(b"TETRIS\0\0\0\0\0\0\0\0\0\0");
+        assert_eq!(probe(&mut Cursor::new(&data)).unwrap(), 95);
+        assert!(read(&data).logo_valid);
+
+        data[0x120] ^= 0x01;
+        assert_eq!(probe(&mut Cursor::new(&data)).unwrap(), 0);
+        assert!(!read(&data).logo_valid);
+    }
+}
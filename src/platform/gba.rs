@@ -0,0 +1,408 @@
+use super::{nds, RomInfo, RomPlatform};
+use crate::archive::{self, Source};
+use crate::hash::{self, RomHashes};
+use crate::metadata::{self, Metadata, Region};
+use crate::Platform;
+use anyhow::{Context, Result};
+use binread::{io::Cursor, io::Read, io::Seek, BinRead};
+use log::debug;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, SeekFrom};
+use std::path::Path;
+
+const HEADER_SIZE: usize = 0xC0;
+
+// The complement check covers the title through the software version.
+const COMPLEMENT_START: usize = 0xA0;
+const COMPLEMENT_END: usize = 0xBD;
+
+// Every cartridge has this at 0xB2.
+const FIXED_VALUE: u8 = 0x96;
+
+// Nintendo's save libraries embed their name and version, such as "FLASH1M_V103", which
+// emulators and flash carts rely on to work out the save type. Longer names come first so
+// "FLASH512_V" isn't mistaken for something shorter.
+const SAVE_LIBRARIES: [(&[u8], SaveType); 6] = [
+    (b"FLASH512_V", SaveType::Flash512),
+    (b"FLASH1M_V", SaveType::Flash1M),
+    (b"FLASH_V", SaveType::Flash512),
+    (b"EEPROM_V", SaveType::EEPROM),
+    (b"SRAM_F_V", SaveType::SRAM),
+    (b"SRAM_V", SaveType::SRAM),
+];
+
+// Three digits follow the "_V" in the library name.
+const SAVE_LIBRARY_VERSION_LEN: usize = 3;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum SaveType {
+    // 512 bytes or 8 kB. The library is the same for both.
+    EEPROM,
+    // 32 kB
+    SRAM,
+    // 64 kB
+    Flash512,
+    // 128 kB
+    Flash1M,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Save {
+    pub save_type: SaveType,
+    // The library name and version found in the ROM, like "FLASH1M_V103"
+    pub library: String,
+    pub size: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ComplementCheck {
+    pub declared: u8,
+    pub calculated: u8,
+    pub valid: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rom {
+    pub title: String,
+    pub game_code: String,
+    pub maker_code: String,
+    pub unit_code: u8,
+    pub device_type: u8,
+    pub software_version: u8,
+    pub logo_valid: bool,
+    pub complement_check: ComplementCheck,
+    pub save: Option<Save>,
+    pub hashes: RomHashes,
+}
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+#[allow(dead_code)]
+pub struct RomHeader {
+    // An ARM branch past the header
+    entry_point: u32,
+
+    #[br(count = 156)]
+    logo: Vec<u8>,
+
+    #[br(count = 12, map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    title: String,
+
+    #[br(count = 4, map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    game_code: String,
+
+    #[br(count = 2, map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    maker_code: String,
+
+    fixed_value: u8,
+
+    unit_code: u8,
+
+    device_type: u8,
+
+    #[br(pad_before = 7)]
+    software_version: u8,
+
+    complement_check: u8,
+}
+
+fn bytes_to_stripped_string(bytes: &[u8]) -> String {
+    let s = String::from_utf8_lossy(bytes);
+
+    s.trim_end_matches(char::from(0x00)).trim_end().to_string()
+}
+
+impl RomHeader {
+    // The logo is the same bitmap the DS uses, so it has the same CRC.
+    pub fn logo_checks_out(&self) -> bool {
+        nds::crc16(&self.logo) == nds::LOGO_CRC
+    }
+}
+
+impl SaveType {
+    pub fn size(&self) -> Option<u32> {
+        match self {
+            SaveType::EEPROM => None,
+            SaveType::SRAM => Some(32 * 1024),
+            SaveType::Flash512 => Some(64 * 1024),
+            SaveType::Flash1M => Some(128 * 1024),
+        }
+    }
+}
+
+pub struct GameBoyAdvance;
+
+impl RomPlatform for GameBoyAdvance {
+    fn platform(&self) -> Platform {
+        Platform::GameBoyAdvance
+    }
+
+    fn name(&self) -> &'static str {
+        "gba"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["gba"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        Ok(Box::new(rom_from_reader(&mut reader, size)?))
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        vec![("game_code", self.game_code.to_string())]
+    }
+
+    fn metadata(&self) -> Metadata {
+        let save_type = self.save.as_ref().map(|save| match save.save_type {
+            SaveType::EEPROM => metadata::SaveType::EEPROM,
+            SaveType::SRAM => metadata::SaveType::SRAM,
+            SaveType::Flash512 | SaveType::Flash1M => metadata::SaveType::Flash,
+        });
+
+        Metadata {
+            title: self.title.to_string(),
+            alt_titles: vec![],
+            regions: Region::from_game_code(&self.game_code)
+                .into_iter()
+                .collect(),
+            publisher: Some(self.maker_code.to_string()),
+            product_code: Some(self.game_code.to_string()),
+            revision: Some(self.software_version.to_string()),
+            release_date: None,
+            rom_size: self.hashes.payload().size,
+            save_type,
+            video_standard: None,
+        }
+    }
+}
+
+// Estimates how likely it is that the file is a Game Boy Advance ROM, from 0 to 100.
+//
+// The BIOS won't boot a cartridge without the logo and the fixed value, so those together
+// are a sure sign. The fixed value and complement check agreeing is a good one too.
+pub fn probe<R: Read + Seek>(file: &mut R) -> Result<u8> {
+    let mut buffer = [0; HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut buffer)?;
+
+    let header = RomHeader::read(&mut Cursor::new(&buffer))?;
+    if header.fixed_value != FIXED_VALUE {
+        return Ok(0);
+    }
+
+    if header.logo_checks_out() {
+        return Ok(95);
+    }
+
+    if header.complement_check == calculate_complement(&buffer) {
+        return Ok(70);
+    }
+
+    Ok(0)
+}
+
+pub fn rom_from_file(path: &Path) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size());
+    }
+
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size)
+}
+
+// Reads the ROM from the first `size` bytes of the reader.
+pub fn rom_from_reader<R: Read + Seek>(f: &mut R, size: u64) -> Result<Rom> {
+    let mut buffer = [0; HEADER_SIZE];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_exact(&mut buffer)?;
+
+    debug!("Read header bytes: {:?}", buffer);
+    let header =
+        RomHeader::read(&mut Cursor::new(&buffer)).context("Failed to parse ROM header")?;
+    debug!("Read ROM header: {:?}", header);
+
+    let complement = calculate_complement(&buffer);
+    let save = find_save_library(f, size)?;
+
+    f.seek(SeekFrom::Start(0))?;
+    let hashes = hash::hash_reader(f.take(size), 0)?;
+
+    Ok(rom_from_header(header, complement, save, hashes))
+}
+
+// Subtracts every byte from zero, then 0x19 more.
+fn calculate_complement(header: &[u8]) -> u8 {
+    header[COMPLEMENT_START..COMPLEMENT_END]
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_sub(b))
+        .wrapping_sub(0x19)
+}
+
+// Scans the ROM for the name of a save library. Games without one don't save, or use
+// something custom that can only be found with a game database.
+pub fn find_save_library<R: Read + Seek>(file: &mut R, size: u64) -> Result<Option<Save>> {
+    const LONGEST_NAME: usize = 10 + SAVE_LIBRARY_VERSION_LEN;
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file.take(size));
+    let mut buffer = vec![0; 64 * 1024];
+    // Bytes carried over from the end of the last chunk, in case a name spans two of them
+    let mut carried = 0;
+
+    loop {
+        let len = reader.read(&mut buffer[carried..])?;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let end = carried + len;
+        let chunk = &buffer[..end];
+
+        for start in 0..end {
+            if let Some(save) = save_library_at(&chunk[start..]) {
+                debug!("Found save library {:?} at chunk offset {}", save, start);
+                return Ok(Some(save));
+            }
+        }
+
+        carried = end.min(LONGEST_NAME - 1);
+        buffer.copy_within(end - carried..end, 0);
+    }
+}
+
+fn save_library_at(bytes: &[u8]) -> Option<Save> {
+    let (name, save_type) = SAVE_LIBRARIES
+        .iter()
+        .find(|(name, _)| bytes.starts_with(name))?;
+
+    let version = bytes.get(name.len()..name.len() + SAVE_LIBRARY_VERSION_LEN)?;
+    if !version.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    Some(Save {
+        save_type: *save_type,
+        library: String::from_utf8_lossy(&bytes[..name.len() + version.len()]).to_string(),
+        size: save_type.size(),
+    })
+}
+
+fn rom_from_header(
+    header: RomHeader,
+    complement: u8,
+    save: Option<Save>,
+    hashes: RomHashes,
+) -> Rom {
+    Rom {
+        logo_valid: header.logo_checks_out(),
+        complement_check: ComplementCheck {
+            declared: header.complement_check,
+            calculated: complement,
+            valid: header.complement_check == complement,
+        },
+        title: header.title,
+        game_code: header.game_code,
+        maker_code: header.maker_code,
+        unit_code: header.unit_code,
+        device_type: header.device_type,
+        software_version: header.software_version,
+        save,
+        hashes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cartridge with a header that passes the complement check but has no logo
+    fn cartridge(size: usize) -> Vec<u8> {
+        let mut rom = vec![0xFF; size];
+        rom[..HEADER_SIZE].fill(0);
+        rom[0xA0..0xAC].copy_from_slice(b"TEST TITLE\0\0");
+        rom[0xAC..0xB0].copy_from_slice(b"ATSE");
+        rom[0xB0..0xB2].copy_from_slice(b"01");
+        rom[0xB2] = FIXED_VALUE;
+        rom[0xBD] = calculate_complement(&rom);
+        rom
+    }
+
+    fn read(rom: &[u8]) -> Rom {
+        rom_from_reader(&mut Cursor::new(rom), rom.len() as u64).unwrap()
+    }
+
+    fn save_in(rom: &[u8]) -> Option<Save> {
+        find_save_library(&mut Cursor::new(rom), rom.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn finds_each_save_type() {
+        let libraries = [
+            ("EEPROM_V124", SaveType::EEPROM, None),
+            ("SRAM_V113", SaveType::SRAM, Some(0x8000)),
+            ("SRAM_F_V102", SaveType::SRAM, Some(0x8000)),
+            ("FLASH_V126", SaveType::Flash512, Some(0x10000)),
+            ("FLASH512_V131", SaveType::Flash512, Some(0x10000)),
+            ("FLASH1M_V103", SaveType::Flash1M, Some(0x20000)),
+        ];
+
+        for (library, save_type, size) in libraries {
+            let mut rom = cartridge(0x1000);
+            rom[0x800..0x800 + library.len()].copy_from_slice(library.as_bytes());
+
+            let save = save_in(&rom).unwrap();
+            assert_eq!(save.save_type, save_type);
+            assert_eq!(save.library, library);
+            assert_eq!(save.size, size);
+        }
+    }
+
+    #[test]
+    fn needs_a_version_after_the_library_name() {
+        let mut rom = cartridge(0x1000);
+        rom[0x800..0x80A].copy_from_slice(b"FLASH_V1xx");
+
+        assert!(save_in(&rom).is_none());
+    }
+
+    #[test]
+    fn finds_library_names_across_chunks() {
+        // The first chunk is 64 kB, so the name starts in it and ends in the next one
+        let mut rom = cartridge(0x20000);
+        rom[0xFFFB..0x10007].copy_from_slice(b"FLASH1M_V103");
+
+        let save = read(&rom).save.unwrap();
+        assert_eq!(save.save_type, SaveType::Flash1M);
+        assert_eq!(save.library, "FLASH1M_V103");
+    }
+
+    #[test]
+    fn checks_the_complement() {
+        let mut rom = cartridge(0x1000);
+        assert_eq!(probe(&mut Cursor::new(&rom)).unwrap(), 70);
+
+        let read_rom = read(&rom);
+        assert!(read_rom.complement_check.valid);
+        assert!(!read_rom.logo_valid);
+        assert_eq!(read_rom.title, "TEST TITLE");
+        assert_eq!(read_rom.game_code, "ATSE");
+
+        rom[0xA0] ^= 0x01;
+        assert_eq!(probe(&mut Cursor::new(&rom)).unwrap(), 0);
+        assert!(!read(&rom).complement_check.valid);
+    }
+}
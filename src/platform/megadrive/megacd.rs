@@ -0,0 +1,288 @@
+use super::{
+    bytes_to_stripped_string, read_header, software_metadata, Region, ReleaseDate, RomHeader,
+    SoftwareTitle, HEADER_OFFSET,
+};
+use crate::archive::{self, Source};
+use crate::hash::{self, RomHashes};
+use crate::metadata::Metadata;
+use crate::platform::{RomInfo, RomPlatform};
+use crate::Platform;
+use anyhow::{Context, Result};
+use binread::{io::Read, io::Seek, BinRead};
+use log::debug;
+use serde::Serialize;
+use std::fs::File;
+use std::io::SeekFrom;
+use std::path::Path;
+
+// The boot sector is the start of the first data sector. Disc images ripped with raw
+// 2352-byte sectors put a sync pattern and sector header in front of it.
+const DATA_OFFSETS: [u64; 2] = [0, 0x10];
+
+const DISC_TYPE: &[u8] = b"SEGADISCSYSTEM";
+
+#[derive(Serialize, Debug)]
+pub struct ProgramArea {
+    // Where the program is on the disc, relative to the boot sector
+    pub offset: u32,
+    pub size: u32,
+    pub entry_point: u32,
+    pub work_ram_size: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rom {
+    pub volume_name: String,
+    pub system_name: String,
+    pub system_version: u16,
+    // The initial program, run by the Mega Drive's 68000
+    pub initial_program: ProgramArea,
+    // The system program, run by the Mega-CD's own 68000
+    pub system_program: ProgramArea,
+    pub software_title: SoftwareTitle,
+    pub software_type: String,
+    pub supported_devices: Vec<&'static str>,
+    pub supported_regions: Vec<Region>,
+    pub system_type: String,
+    pub publisher: String,
+    pub release_date: ReleaseDate,
+    pub serial_number: String,
+    pub revision: String,
+    pub hashes: RomHashes,
+}
+
+// The system area at the very start of the boot sector, before the usual header at 0x100
+#[derive(BinRead, Debug)]
+#[br(big, magic = b"SEGADISCSYSTEM")]
+#[allow(dead_code)]
+pub struct SystemArea {
+    #[br(pad_before = 2, count = 11, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    volume_name: String,
+
+    #[br(pad_before = 1)]
+    volume_system: u16,
+    volume_type: u16,
+
+    #[br(count = 11, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    system_name: String,
+
+    #[br(pad_before = 1)]
+    system_version: u16,
+    system_type: u16,
+
+    ip_offset: u32,
+    ip_size: u32,
+    ip_entry_point: u32,
+    ip_work_ram_size: u32,
+
+    sp_offset: u32,
+    sp_size: u32,
+    sp_entry_point: u32,
+    sp_work_ram_size: u32,
+}
+
+pub struct MegaCD;
+
+impl RomPlatform for MegaCD {
+    fn platform(&self) -> Platform {
+        Platform::MegaCD
+    }
+
+    fn name(&self) -> &'static str {
+        "megacd"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["segacd"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["iso"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        Ok(Box::new(rom_from_reader(&mut reader, size)?))
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        vec![("serial_number", self.serial_number.to_string())]
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            rom_size: self.hashes.payload().size,
+            ..software_metadata(
+                &self.software_title,
+                &self.supported_regions,
+                &self.publisher,
+                &self.serial_number,
+                &self.revision,
+                &self.release_date,
+            )
+        }
+    }
+}
+
+// Estimates how likely it is that the file is a Mega-CD disc image, from 0 to 100.
+//
+// The BIOS won't boot a disc without the disc type at the start of the boot sector.
+pub fn probe<R: Read + Seek>(file: &mut R) -> Result<u8> {
+    match find_data_offset(file)? {
+        Some(_) => Ok(95),
+        None => Ok(0),
+    }
+}
+
+// Finds where the boot sector starts, if there is one.
+pub fn find_data_offset<R: Read + Seek>(file: &mut R) -> Result<Option<u64>> {
+    let mut buffer = [0; DISC_TYPE.len()];
+
+    for offset in DATA_OFFSETS {
+        file.seek(SeekFrom::Start(offset))?;
+        if file.read_exact(&mut buffer).is_err() {
+            continue;
+        }
+
+        if buffer == DISC_TYPE {
+            return Ok(Some(offset));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn rom_from_file(path: &Path) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size());
+    }
+
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size)
+}
+
+// Reads the disc image from the first `size` bytes of the reader.
+pub fn rom_from_reader<R: Read + Seek>(f: &mut R, size: u64) -> Result<Rom> {
+    let offset = find_data_offset(f)?
+        .context("Could not find the boot sector. This may not be a Mega-CD disc image.")?;
+
+    f.seek(SeekFrom::Start(offset))?;
+    let system_area = SystemArea::read(f).context("Failed to parse system area")?;
+    debug!("Read system area: {:?}", system_area);
+
+    let header = read_header(f, offset + HEADER_OFFSET)?;
+
+    f.seek(SeekFrom::Start(0))?;
+    let hashes = hash::hash_reader(f.take(size), 0)?;
+
+    Ok(rom_from_header(&system_area, &header, hashes))
+}
+
+fn rom_from_header(system_area: &SystemArea, header: &RomHeader, hashes: RomHashes) -> Rom {
+    Rom {
+        volume_name: system_area.volume_name.to_string(),
+        system_name: system_area.system_name.to_string(),
+        system_version: system_area.system_version,
+        initial_program: ProgramArea {
+            offset: system_area.ip_offset,
+            size: system_area.ip_size,
+            entry_point: system_area.ip_entry_point,
+            work_ram_size: system_area.ip_work_ram_size,
+        },
+        system_program: ProgramArea {
+            offset: system_area.sp_offset,
+            size: system_area.sp_size,
+            entry_point: system_area.sp_entry_point,
+            work_ram_size: system_area.sp_work_ram_size,
+        },
+        software_title: SoftwareTitle {
+            domestic: header.game_title_domestic.to_string(),
+            overseas: header.game_title_overseas.to_string(),
+        },
+        software_type: header.software_type(),
+        supported_devices: header.supported_devices(),
+        supported_regions: header.supported_regions(),
+        system_type: header.system_type.to_string(),
+        publisher: header.publisher.to_string(),
+        release_date: ReleaseDate {
+            year: header.release_year(),
+            month: header.release_month(),
+        },
+        serial_number: header.serial_number.to_string(),
+        revision: header.revision.to_string(),
+        hashes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detect_from_contents;
+    use std::io::Cursor;
+
+    // A boot sector with a system area and a cartridge-style header, preceded by `offset`
+    // bytes of sector header.
+    fn disc_image(offset: usize) -> Vec<u8> {
+        let mut image = vec![0; offset + 0x800];
+        let sector = &mut image[offset..];
+
+        sector[..0x10].copy_from_slice(b"SEGADISCSYSTEM  ");
+        sector[0x10..0x1B].copy_from_slice(b"SEGAIPSAMP ");
+        sector[0x20..0x2B].copy_from_slice(b"SEGASYSTEM ");
+        sector[0x30..0x34].copy_from_slice(&0x800u32.to_be_bytes());
+        sector[0x40..0x44].copy_from_slice(&0x1000u32.to_be_bytes());
+
+        let header = &mut sector[0x100..0x200];
+        header.fill(b' ');
+        header[..0x10].copy_from_slice(b"SEGA MEGA DRIVE ");
+        header[0x10..0x20].copy_from_slice(b"(C)SEGA 1993.OCT");
+        header[0x80..0x82].copy_from_slice(b"GM");
+        header[0xF0..0xF3].copy_from_slice(b"JUE");
+
+        image
+    }
+
+    #[test]
+    fn detects_iso_boot_sector() {
+        let image = disc_image(0);
+        let detection = detect_from_contents(&mut Cursor::new(&image), image.len() as u64)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(detection.platform, Platform::MegaCD);
+    }
+
+    #[test]
+    fn detects_raw_sector_boot_sector() {
+        let image = disc_image(0x10);
+        let detection = detect_from_contents(&mut Cursor::new(&image), image.len() as u64)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(detection.platform, Platform::MegaCD);
+    }
+
+    #[test]
+    fn reads_system_area() {
+        let image = disc_image(0x10);
+        let rom = rom_from_reader(&mut Cursor::new(&image), image.len() as u64).unwrap();
+
+        assert_eq!(rom.volume_name, "SEGAIPSAMP");
+        assert_eq!(rom.system_name, "SEGASYSTEM");
+        assert_eq!(rom.initial_program.offset, 0x800);
+        assert_eq!(rom.system_program.offset, 0x1000);
+        assert_eq!(rom.release_date.year, Some(1993));
+        assert_eq!(rom.release_date.month, 10);
+    }
+}
@@ -0,0 +1,814 @@
+use super::{FixedChecksum, RomInfo, RomPlatform};
+use crate::archive::{self, Source};
+use crate::hash::{self, RomHashes};
+use crate::metadata::{self, Metadata, VideoStandard};
+use crate::Platform;
+use anyhow::{Context, Result};
+use binread::{io::Cursor, io::Read, io::Seek, BinRead};
+use encoding::codec::japanese::Windows31JEncoding;
+use encoding::{DecoderTrap, Encoding};
+use log::{debug, warn};
+use phf::phf_map;
+use regex::Regex;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, SeekFrom, Write};
+use std::path::Path;
+
+pub mod megacd;
+pub mod sega32x;
+
+// The checksum covers everything after the header.
+const CHECKSUM_START: u64 = 0x200;
+const CHECKSUM_OFFSET: u64 = 0x18E;
+
+const HEADER_OFFSET: u64 = 0x100;
+const HEADER_SIZE: usize = 255;
+
+#[derive(Serialize, Debug)]
+pub enum Region {
+    Japan,
+    Americas,
+    Europe,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SoftwareTitle {
+    pub domestic: String,
+    pub overseas: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReleaseDate {
+    pub month: u8,
+    // Missing when the header's year isn't a number
+    pub year: Option<u16>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Checksum {
+    pub declared: u16,
+    pub calculated: u16,
+    pub valid: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum SaveType {
+    SRAM,
+    EEPROM,
+}
+
+// Which bytes of the 68000's 16-bit bus the save chip is wired to
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum SaveBus {
+    Both,
+    Even,
+    Odd,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Save {
+    pub save_type: SaveType,
+    pub bus: SaveBus,
+    // Without a battery, the RAM is only there for the game to use while it runs.
+    pub battery_backed: bool,
+    pub start_address: u32,
+    pub end_address: u32,
+    pub size: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rom {
+    pub software_title: SoftwareTitle,
+    pub software_type: String,
+    pub supported_devices: Vec<&'static str>,
+    pub supported_regions: Vec<Region>,
+    pub system_type: String,
+    pub publisher: String,
+    pub release_date: ReleaseDate,
+    pub serial_number: String,
+    pub revision: String,
+    pub rom_start_address: u32,
+    pub rom_end_address: u32,
+    // Whether the end address agrees with the size of the file. Overdumps and trimmed
+    // ROMs don't.
+    pub rom_end_matches_size: bool,
+    pub ram_start_address: u32,
+    pub ram_end_address: u32,
+    pub save: Option<Save>,
+    pub checksum: Checksum,
+    pub hashes: RomHashes,
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+#[allow(dead_code)]
+pub struct RomHeader {
+    #[br(count = 16, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    pub system_type: String,
+
+    #[br(pad_before = 3, count = 4, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    pub publisher: String,
+
+    #[br(pad_before = 1, count = 4, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    release_year: String,
+
+    #[br(pad_before = 1, count = 3, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    release_month: String,
+
+    #[br(count = 48, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    pub game_title_domestic: String,
+
+    #[br(count = 48, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    pub game_title_overseas: String,
+
+    #[br(count = 2, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    software_type: String,
+
+    #[br(pad_before = 1, count = 8, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    pub serial_number: String,
+
+    #[br(pad_before = 1, count = 2, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    pub revision: String,
+
+    checksum: u16,
+
+    #[br(count = 16, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    supported_devices: String,
+
+    rom_start_address: u32,
+    rom_end_address: u32,
+    ram_start_address: u32,
+    ram_end_address: u32,
+
+    #[br(count = 12)]
+    extra_memory: Vec<u8>,
+
+    #[br(count = 12, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    pub modem_support: String,
+
+    // We don't want to strip this string. The spaces can be helpful when figuring out
+    // if it's the old or new type of region identifier.
+    #[br(pad_before = 40, count = 3, try_map = |c: Vec<u8>| String::from_utf8(c))]
+    supported_regions: String,
+}
+
+// Converts a series of bytes to a string.
+// Header fields are fixed-length and padded with strings, so trim those off.
+// Some values have internal padding as well, like
+fn bytes_to_stripped_string(bytes: &[u8]) -> Result<String> {
+    let s = Windows31JEncoding
+        .decode(bytes, DecoderTrap::Ignore)
+        .unwrap();
+    let trimmed = s.trim_end().to_string();
+    let squished = Regex::new(r"\s{2,}").unwrap().replace_all(&trimmed, " ");
+
+    Ok(squished.to_string())
+}
+
+pub struct MegaDrive;
+
+impl RomPlatform for MegaDrive {
+    fn platform(&self) -> Platform {
+        Platform::MegaDrive
+    }
+
+    fn name(&self) -> &'static str {
+        "megadrive"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["genesis"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["md", "gen", "smd"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        Ok(Box::new(rom_from_reader(&mut reader, size)?))
+    }
+
+    fn fixes_checksums(&self) -> bool {
+        true
+    }
+
+    fn fix_checksum(&self, source: &Path, dest: &Path) -> Result<FixedChecksum> {
+        let c = fix_checksum(source, dest)?;
+        Ok(FixedChecksum {
+            declared: c.declared,
+            calculated: c.calculated,
+            valid: c.valid,
+        })
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        vec![("serial_number", self.serial_number.to_string())]
+    }
+
+    fn metadata(&self) -> Metadata {
+        let save_type = self.save.as_ref().map(|save| match save.save_type {
+            SaveType::SRAM => metadata::SaveType::SRAM,
+            SaveType::EEPROM => metadata::SaveType::EEPROM,
+        });
+
+        Metadata {
+            rom_size: self.hashes.payload().size,
+            save_type,
+            ..software_metadata(
+                &self.software_title,
+                &self.supported_regions,
+                &self.publisher,
+                &self.serial_number,
+                &self.revision,
+                &self.release_date,
+            )
+        }
+    }
+}
+
+// The metadata held in the header, which the 32X and Mega-CD share with the Mega Drive.
+fn software_metadata(
+    title: &SoftwareTitle,
+    supported_regions: &[Region],
+    publisher: &str,
+    serial_number: &str,
+    revision: &str,
+    release_date: &ReleaseDate,
+) -> Metadata {
+    let regions: Vec<metadata::Region> = supported_regions
+        .iter()
+        .map(|r| match r {
+            Region::Japan => metadata::Region::Japan,
+            Region::Americas => metadata::Region::NorthAmerica,
+            Region::Europe => metadata::Region::Europe,
+        })
+        .collect();
+
+    let release_date = release_date.year.map(|year| match release_date.month {
+        0 => format!("{:04}", year),
+        month => format!("{:04}-{:02}", year, month),
+    });
+
+    Metadata {
+        title: match title.overseas.is_empty() {
+            true => title.domestic.to_string(),
+            false => title.overseas.to_string(),
+        },
+        alt_titles: match title.domestic != title.overseas && !title.overseas.is_empty() {
+            true => vec![title.domestic.to_string()],
+            false => vec![],
+        },
+        video_standard: VideoStandard::for_regions(&regions),
+        regions,
+        publisher: Some(publisher.to_string()).filter(|p| !p.is_empty()),
+        product_code: Some(serial_number.to_string()),
+        revision: Some(revision.to_string()),
+        release_date,
+        rom_size: 0,
+        save_type: None,
+    }
+}
+
+// Estimates how likely it is that the file is a Mega Drive ROM, from 0 to 100.
+//
+// Every licensed cartridge starts its header at 0x100 with the system type, which
+// begins with "SEGA". A handful of games pad it with a leading space.
+pub fn probe<R: Read + Seek>(file: &mut R) -> Result<u8> {
+    // Mega-CD boot sectors have the same header at 0x100, after their own system area.
+    if megacd::find_data_offset(file)?.is_some() {
+        return Ok(0);
+    }
+
+    let mut buffer = [0; 16];
+    file.seek(std::io::SeekFrom::Start(HEADER_OFFSET))?;
+    file.read_exact(&mut buffer)?;
+
+    if buffer.starts_with(b"SEGA MEGA DRIVE") || buffer.starts_with(b"SEGA GENESIS") {
+        return Ok(95);
+    }
+
+    if buffer.starts_with(b"SEGA") || buffer.starts_with(b" SEGA") {
+        return Ok(80);
+    }
+
+    Ok(0)
+}
+
+pub fn rom_from_file(path: &Path) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size());
+    }
+
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size)
+}
+
+// Reads the ROM from the first `size` bytes of the reader.
+pub fn rom_from_reader<R: Read + Seek>(f: &mut R, size: u64) -> Result<Rom> {
+    let header = read_header(f, HEADER_OFFSET)?;
+
+    if !header.rom_end_matches(size) {
+        warn!(
+            "The header says the ROM ends at {:#x}, but it is {:#x} bytes long",
+            header.rom_end_address, size
+        );
+    }
+
+    let calculated = calculate_checksum(f, size)?;
+
+    f.seek(SeekFrom::Start(0))?;
+    let hashes = hash::hash_reader(f.take(size), 0)?;
+
+    Ok(rom_from_header(&header, calculated, hashes))
+}
+
+// Reads the header found at `offset`, which is 0x100 on cartridges.
+fn read_header<R: Read + Seek>(f: &mut R, offset: u64) -> Result<RomHeader> {
+    let mut buffer = [0; HEADER_SIZE];
+    f.seek(SeekFrom::Start(offset))?;
+    f.read_exact(&mut buffer)?;
+
+    debug!("Read header bytes: {:?}", buffer);
+    let mut cursor = Cursor::new(&mut buffer);
+
+    let header = RomHeader::read(&mut cursor).context("Failed to parse ROM header")?;
+    debug!("Read ROM header: {:?}", header);
+
+    Ok(header)
+}
+
+// Sums every big-endian 16-bit word from 0x200 to the end of the ROM, `size` bytes in.
+// Reads in chunks so large ROMs aren't loaded into memory at once.
+pub fn calculate_checksum<R: Read + Seek>(file: &mut R, size: u64) -> Result<u16> {
+    file.seek(SeekFrom::Start(CHECKSUM_START))?;
+    let mut reader = BufReader::new(file.take(size.saturating_sub(CHECKSUM_START)));
+    let mut buffer = [0; 8192];
+    let mut sum: u16 = 0;
+    let mut leftover: Option<u8> = None;
+
+    loop {
+        let len = reader.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+
+        for &byte in &buffer[..len] {
+            match leftover.take() {
+                Some(high) => sum = sum.wrapping_add(u16::from_be_bytes([high, byte])),
+                None => leftover = Some(byte),
+            }
+        }
+    }
+
+    // An odd-sized ROM is padded with a zero byte.
+    if let Some(high) = leftover {
+        sum = sum.wrapping_add(u16::from_be_bytes([high, 0]));
+    }
+
+    Ok(sum)
+}
+
+// Copies the ROM to `dest` and writes the calculated checksum into the copy's header.
+// A ROM in an archive is copied out of it first. Returns the checksum as it was before
+// the fix.
+pub fn fix_checksum(source: &Path, dest: &Path) -> Result<Checksum> {
+    archive::patch_copy(source, dest, |f| {
+        let size = f.metadata()?.len();
+        let rom = rom_from_reader(f, size)?;
+
+        if !rom.checksum.valid {
+            f.seek(SeekFrom::Start(CHECKSUM_OFFSET))?;
+            f.write_all(&rom.checksum.calculated.to_be_bytes())?;
+        }
+
+        Ok(rom.checksum)
+    })
+}
+
+fn rom_from_header(header: &RomHeader, calculated_checksum: u16, hashes: RomHashes) -> Rom {
+    Rom {
+        rom_end_matches_size: header.rom_end_matches(hashes.file.size),
+        hashes,
+        checksum: Checksum {
+            declared: header.checksum,
+            calculated: calculated_checksum,
+            valid: header.checksum == calculated_checksum,
+        },
+        release_date: ReleaseDate {
+            year: header.release_year(),
+            month: header.release_month(),
+        },
+        software_title: SoftwareTitle {
+            domestic: header.game_title_domestic.to_string(),
+            overseas: header.game_title_overseas.to_string(),
+        },
+        revision: header.revision.to_string(),
+        serial_number: header.serial_number.to_string(),
+        software_type: header.software_type(),
+        supported_devices: header.supported_devices(),
+        supported_regions: header.supported_regions(),
+        system_type: header.system_type.to_string(),
+        publisher: header.publisher.to_string(),
+        rom_start_address: header.rom_start_address,
+        rom_end_address: header.rom_end_address,
+        ram_start_address: header.ram_start_address,
+        ram_end_address: header.ram_end_address,
+        save: header.save(),
+    }
+}
+
+impl RomHeader {
+    // The end address is the last byte of the ROM, so it should be one less than the size.
+    pub fn rom_end_matches(&self, size: u64) -> bool {
+        self.rom_end_address as u64 + 1 == size
+    }
+
+    // Cartridges with extra memory describe it with "RA", a type byte, a byte that's 0x20
+    // for RAM or 0x40 for EEPROM, then the start and end addresses.
+    //
+    // The type byte is 0b1x1yz000, where x is set when the memory is battery-backed, and yz
+    // is 00 for both bytes of the bus, 10 for even bytes only and 11 for odd bytes only.
+    // EEPROM games use 0xE8 instead.
+    pub fn save(&self) -> Option<Save> {
+        let extra = &self.extra_memory;
+        if !extra.starts_with(b"RA") {
+            return None;
+        }
+
+        let flags = extra[2];
+        let save_type = match extra[3] {
+            0x40 => SaveType::EEPROM,
+            _ => SaveType::SRAM,
+        };
+        let bus = match (flags >> 3) & 0x03 {
+            0b10 => SaveBus::Even,
+            0b11 => SaveBus::Odd,
+            _ => SaveBus::Both,
+        };
+
+        let start_address = u32::from_be_bytes([extra[4], extra[5], extra[6], extra[7]]);
+        let end_address = u32::from_be_bytes([extra[8], extra[9], extra[10], extra[11]]);
+
+        // The EEPROM addresses are just the ports it's read and written through.
+        let span = end_address.checked_sub(start_address);
+        let size = match (save_type, bus) {
+            (SaveType::EEPROM, _) => None,
+            (SaveType::SRAM, SaveBus::Both) => span.map(|span| span + 1),
+            (SaveType::SRAM, _) => span.map(|span| span / 2 + 1),
+        };
+
+        Some(Save {
+            save_type,
+            bus,
+            battery_backed: flags & 0x40 != 0,
+            start_address,
+            end_address,
+            size,
+        })
+    }
+
+    pub fn supported_devices(&self) -> Vec<&'static str> {
+        static DEVICES: phf::Map<char, &'static str> = phf_map! {
+            'J' => "3-button controller",
+            '6' => "6-button controller",
+            '0' => "Master System controller",
+            'A' => "Analog joystick",
+            '4' => "Multitap",
+            'G' => "Lightgun",
+            'L' => "Activator",
+            'M' => "Mouse",
+            'B' => "Trackball",
+            'T' => "Tablet",
+            'V' => "Paddle",
+            'K' => "Keyboard",
+            'R' => "RS-232 (Serial)",
+            'P' => "Printer",
+            'C' => "CD-ROM (Sega CD)",
+            'F' => "Floppy drive",
+            'D' => "Download",
+        };
+
+        let mut result = Vec::new();
+        let device_codes: Vec<char> = self.supported_devices.chars().collect();
+
+        for code in device_codes {
+            if let Some(&desc) = DEVICES.get(&code) {
+                result.push(desc);
+            }
+        }
+
+        result
+    }
+
+    // List of regions supported by the ROM.
+    //
+    // There are 3 bytes used to store the values and two different formats.
+    // The "old" format is the characters 'J', 'U' and 'E' for Japan, US, Europe.
+    // The "new" format is a single char as a hex digit, e.g. "F" and bitmasking
+    // reveals the regions supported.
+    pub fn supported_regions(&self) -> Vec<Region> {
+        const HEX_CHARS: [char; 16] = [
+            '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'A', 'B', 'C', 'D', 'E', 'F',
+        ];
+
+        // unique case where it might be the old or new format, but it's probably the old.
+        // if it were actually the new format, you would just be missing the Americas.
+        if self.supported_regions == "E  " {
+            return vec![Region::Europe];
+        }
+
+        let chars: Vec<char> = self.supported_regions.chars().collect();
+        let first_char = chars[0];
+
+        // all-in-one way to see if it's a hex code, and if so, convert to its numeric value
+        match HEX_CHARS.iter().position(|&c| c == first_char) {
+            Some(pos) => new_region_code(pos as u8),
+            None => old_region_code(&chars),
+        }
+    }
+
+    pub fn software_type(&self) -> String {
+        match self.software_type.as_str() {
+            "GM" => "Game".to_string(),
+            "AI" => "Aid".to_string(),
+            "OS" => "Boot ROM (TMSS)".to_string(),
+            "BR" => "Boot ROM (Sega CD)".to_string(),
+            other => format!("Unknown '{}'", other),
+        }
+    }
+
+    pub fn release_year(&self) -> Option<u16> {
+        self.release_year.parse::<u16>().ok()
+    }
+
+    pub fn release_month(&self) -> u8 {
+        const MONTHS: [&str; 12] = [
+            "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+        ];
+
+        // Months count from 1, leaving 0 for when it's missing
+        match MONTHS.iter().position(|m| m == &self.release_month) {
+            Some(pos) => pos as u8 + 1,
+            None => 0,
+        }
+    }
+}
+
+// The "old" region format is 3 chars in any order: J, E, U
+fn old_region_code(codes: &[char]) -> Vec<Region> {
+    let mut result = Vec::new();
+
+    if codes.contains(&'J') {
+        result.push(Region::Japan)
+    }
+
+    if codes.contains(&'U') {
+        result.push(Region::Americas)
+    }
+
+    if codes.contains(&'E') {
+        result.push(Region::Europe)
+    }
+
+    result
+}
+
+// The "new" region coding system is derived from a single byte that uses bitmasking.
+// Multiple regions are represented with 1 = JP, 4 = US, 8 = EU. 2 is unused.
+fn new_region_code(code: u8) -> Vec<Region> {
+    let mut result = Vec::new();
+
+    if code & 0x01 != 0 {
+        result.push(Region::Japan)
+    }
+
+    if code & 0x04 != 0 {
+        result.push(Region::Americas)
+    }
+
+    if code & 0x08 != 0 {
+        result.push(Region::Europe)
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A cartridge with a header at 0x100 and a correct checksum
+    fn cartridge(size: usize) -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..size).map(|i| (i % 253) as u8).collect();
+        let header = &mut rom[0x100..0x200];
+        header.fill(b' ');
+        header[..0x10].copy_from_slice(b"SEGA MEGA DRIVE ");
+        header[0x10..0x20].copy_from_slice(b"(C)SEGA 1991.JUN");
+        header[0x20..0x25].copy_from_slice(b"SONIC");
+        header[0x80..0x8E].copy_from_slice(b"GM 00001009-00");
+        header[0x90] = b'J';
+        header[0xA0..0xA4].fill(0);
+        header[0xA4..0xA8].copy_from_slice(&(size as u32 - 1).to_be_bytes());
+        header[0xF0..0xF3].copy_from_slice(b"JUE");
+
+        let checksum = calculate_checksum(&mut Cursor::new(&rom), size as u64).unwrap();
+        rom[CHECKSUM_OFFSET as usize..CHECKSUM_OFFSET as usize + 2]
+            .copy_from_slice(&checksum.to_be_bytes());
+
+        rom
+    }
+
+    fn read(rom: &[u8]) -> Rom {
+        rom_from_reader(&mut Cursor::new(rom), rom.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn sums_words_after_the_header() {
+        let mut rom = vec![0xFF; 0x200];
+        rom.extend([0x12, 0x34, 0xFF, 0xFF, 0x00, 0x02]);
+
+        // 0x1234 + 0xFFFF + 0x0002, wrapping
+        let sum = calculate_checksum(&mut Cursor::new(&rom), rom.len() as u64).unwrap();
+        assert_eq!(sum, 0x1235);
+    }
+
+    #[test]
+    fn pads_odd_sizes_with_a_zero_byte() {
+        let mut rom = vec![0; 0x200];
+        rom.extend([0x01, 0x00, 0x02]);
+
+        let sum = calculate_checksum(&mut Cursor::new(&rom), rom.len() as u64).unwrap();
+        assert_eq!(sum, 0x0300);
+    }
+
+    #[test]
+    fn validates_checksum() {
+        let rom = read(&cartridge(0x20000));
+
+        assert!(rom.checksum.valid);
+        assert!(rom.rom_end_matches_size);
+        assert_eq!(rom.release_date.year, Some(1991));
+        assert_eq!(rom.release_date.month, 6);
+    }
+
+    #[test]
+    fn notices_when_the_end_address_is_off() {
+        // An overdump, and a ROM trimmed short of the end address
+        let mut data = cartridge(0x20000);
+        data.extend_from_slice(&[0xFF; 0x20000]);
+        assert!(!read(&data).rom_end_matches_size);
+
+        let data = &cartridge(0x20000)[..0x10000];
+        let rom = read(data);
+        assert!(!rom.rom_end_matches_size);
+        assert_eq!(rom.rom_end_address, 0x1FFFF);
+    }
+
+    // Writes an "RA" extra memory field with the given type bytes and addresses
+    fn with_extra_memory(flags: u8, kind: u8, start: u32, end: u32) -> Rom {
+        let mut data = cartridge(0x20000);
+        let extra = &mut data[0x1B0..0x1BC];
+        extra[..4].copy_from_slice(&[b'R', b'A', flags, kind]);
+        extra[4..8].copy_from_slice(&start.to_be_bytes());
+        extra[8..].copy_from_slice(&end.to_be_bytes());
+
+        read(&data)
+    }
+
+    #[test]
+    fn reads_battery_backed_sram() {
+        // Odd bytes only, like most games with a save
+        let save = with_extra_memory(0xF8, 0x20, 0x20_0001, 0x20_3FFF)
+            .save
+            .unwrap();
+        assert_eq!(save.save_type, SaveType::SRAM);
+        assert_eq!(save.bus, SaveBus::Odd);
+        assert!(save.battery_backed);
+        assert_eq!(save.start_address, 0x20_0001);
+        assert_eq!(save.size, Some(0x2000));
+
+        let save = with_extra_memory(0xE0, 0x20, 0x20_0000, 0x20_FFFF)
+            .save
+            .unwrap();
+        assert_eq!(save.bus, SaveBus::Both);
+        assert!(save.battery_backed);
+        assert_eq!(save.size, Some(0x10000));
+    }
+
+    #[test]
+    fn reads_ram_without_a_battery() {
+        let save = with_extra_memory(0xB0, 0x20, 0x20_0000, 0x20_3FFE)
+            .save
+            .unwrap();
+
+        assert_eq!(save.save_type, SaveType::SRAM);
+        assert_eq!(save.bus, SaveBus::Even);
+        assert!(!save.battery_backed);
+        assert_eq!(save.size, Some(0x2000));
+    }
+
+    #[test]
+    fn reads_eeprom() {
+        let rom = with_extra_memory(0xE8, 0x40, 0x20_0001, 0x20_0001);
+        let save = rom.save.as_ref().unwrap();
+
+        assert_eq!(save.save_type, SaveType::EEPROM);
+        assert!(save.battery_backed);
+        assert_eq!(save.size, None);
+        assert!(matches!(
+            rom.metadata().save_type,
+            Some(metadata::SaveType::EEPROM)
+        ));
+    }
+
+    #[test]
+    fn needs_ra_for_extra_memory() {
+        assert!(read(&cartridge(0x20000)).save.is_none());
+        assert!(with_extra_memory(0xF8, 0x20, 0x20_0001, 0x20_3FFF)
+            .save
+            .is_some());
+
+        let mut data = cartridge(0x20000);
+        data[0x1B0..0x1BC].copy_from_slice(b"XA\xF8\x20\x00\x20\x00\x01\x00\x20\x3F\xFF");
+        assert!(read(&data).save.is_none());
+    }
+
+    #[test]
+    fn reads_headers_with_a_broken_year() {
+        let mut data = cartridge(0x20000);
+        data[0x118..0x11C].copy_from_slice(b"XXXX");
+
+        let rom = read(&data);
+        assert_eq!(rom.release_date.year, None);
+        assert_eq!(rom.metadata().release_date, None);
+    }
+
+    #[test]
+    fn fixes_checksum_in_a_copy() {
+        let dir = std::env::temp_dir().join(format!("romboss-md-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("broken.md");
+        let dest = dir.join("fixed.md");
+
+        let mut data = cartridge(0x20000);
+        let expected = read(&data).checksum.calculated;
+        data[CHECKSUM_OFFSET as usize..CHECKSUM_OFFSET as usize + 2].copy_from_slice(&[0, 0]);
+        std::fs::write(&source, &data).unwrap();
+
+        let before = fix_checksum(&source, &dest).unwrap();
+        assert_eq!(before.declared, 0);
+        assert_eq!(before.calculated, expected);
+        assert!(!before.valid);
+
+        let fixed = read(&std::fs::read(&dest).unwrap());
+        assert!(fixed.checksum.valid);
+        assert_eq!(std::fs::read(&source).unwrap(), data);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fixes_checksum_of_a_zipped_rom_in_a_bare_copy() {
+        let dir = std::env::temp_dir().join(format!("romboss-md-zip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("broken.zip");
+        let dest = dir.join("fixed.md");
+
+        let mut data = cartridge(0x20000);
+        let expected = read(&data).checksum.calculated;
+        data[CHECKSUM_OFFSET as usize..CHECKSUM_OFFSET as usize + 2].copy_from_slice(&[0, 0]);
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("broken.md", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(&data).unwrap();
+        let archive = zip.finish().unwrap().into_inner();
+        std::fs::write(&source, &archive).unwrap();
+
+        let before = fix_checksum(&source, &dest).unwrap();
+        assert_eq!(before.calculated, expected);
+        assert!(!before.valid);
+
+        // The ROM is fixed outside of the archive, which is left alone
+        let fixed = std::fs::read(&dest).unwrap();
+        assert_eq!(fixed.len(), data.len());
+        assert!(read(&fixed).checksum.valid);
+        assert_eq!(std::fs::read(&source).unwrap(), archive);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+use super::{bytes_to_stripped_string, HEADER_OFFSET};
+use crate::archive::{self, Source};
+use crate::hash::RomHashes;
+use crate::metadata::Metadata;
+use crate::platform::{FixedChecksum, RomInfo, RomPlatform};
+use crate::Platform;
+use anyhow::{Context, Result};
+use binread::{io::Read, io::Seek, BinRead};
+use log::debug;
+use serde::Serialize;
+use std::fs::File;
+use std::io::SeekFrom;
+use std::path::Path;
+
+// The 32X reads this to find the SH-2 programs and copy them into SDRAM.
+const MARS_HEADER_OFFSET: u64 = 0x3C0;
+
+// Both SH-2s boot from SDRAM, which they see at 0x06000000.
+const SDRAM_START: u32 = 0x0600_0000;
+const SDRAM_END: u32 = 0x0604_0000;
+
+#[derive(Serialize, Debug)]
+pub struct Rom {
+    pub mars: MarsHeader,
+
+    // Everything at 0x100 is laid out the same as on a Mega Drive cartridge.
+    #[serde(flatten)]
+    pub cartridge: super::Rom,
+}
+
+#[derive(BinRead, Serialize, Debug)]
+#[br(big)]
+pub struct MarsHeader {
+    #[br(count = 16, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    pub module_name: String,
+
+    pub version: u32,
+
+    // Where the SH-2 code is in the ROM, and where in SDRAM it's copied to
+    pub source_address: u32,
+    pub destination_address: u32,
+    pub size: u32,
+
+    pub master_entry_point: u32,
+    pub slave_entry_point: u32,
+    pub master_vbr: u32,
+    pub slave_vbr: u32,
+}
+
+impl MarsHeader {
+    pub fn entry_points_in_sdram(&self) -> bool {
+        let in_sdram = |address| (SDRAM_START..SDRAM_END).contains(&address);
+
+        in_sdram(self.master_entry_point) && in_sdram(self.slave_entry_point)
+    }
+}
+
+pub struct Sega32X;
+
+impl RomPlatform for Sega32X {
+    fn platform(&self) -> Platform {
+        Platform::Sega32X
+    }
+
+    fn name(&self) -> &'static str {
+        "32x"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["sega32x"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["32x"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        Ok(Box::new(rom_from_reader(&mut reader, size)?))
+    }
+
+    // 32X cartridges are checksummed the same way as Mega Drive ones.
+    fn fixes_checksums(&self) -> bool {
+        true
+    }
+
+    fn fix_checksum(&self, source: &Path, dest: &Path) -> Result<FixedChecksum> {
+        super::MegaDrive.fix_checksum(source, dest)
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.cartridge.hashes
+    }
+
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        self.cartridge.header_values()
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.cartridge.metadata()
+    }
+}
+
+// Estimates how likely it is that the file is a 32X ROM, from 0 to 100.
+//
+// 32X cartridges use the Mega Drive header, with their own system type. Seeing both SH-2
+// entry points in SDRAM as well makes it certain.
+pub fn probe<R: Read + Seek>(file: &mut R) -> Result<u8> {
+    let mut buffer = [0; 16];
+    file.seek(SeekFrom::Start(HEADER_OFFSET))?;
+    file.read_exact(&mut buffer)?;
+
+    if !buffer.starts_with(b"SEGA 32X") && !buffer.starts_with(b" SEGA 32X") {
+        return Ok(0);
+    }
+
+    match read_mars_header(file) {
+        Ok(mars) if mars.entry_points_in_sdram() => Ok(98),
+        _ => Ok(95),
+    }
+}
+
+pub fn rom_from_file(path: &Path) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size());
+    }
+
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size)
+}
+
+// Reads the ROM from the first `size` bytes of the reader.
+pub fn rom_from_reader<R: Read + Seek>(f: &mut R, size: u64) -> Result<Rom> {
+    let mars = read_mars_header(f).context("Failed to parse MARS header")?;
+    debug!("Read MARS header: {:?}", mars);
+
+    Ok(Rom {
+        mars,
+        cartridge: super::rom_from_reader(f, size)?,
+    })
+}
+
+fn read_mars_header<R: Read + Seek>(f: &mut R) -> Result<MarsHeader> {
+    f.seek(SeekFrom::Start(MARS_HEADER_OFFSET))?;
+
+    Ok(MarsHeader::read(f)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detect_from_contents;
+    use std::io::Cursor;
+
+    // A cartridge with the given system type and a MARS header with the given SH-2 entry
+    // points.
+    fn cartridge(system_type: &[u8; 16], master: u32, slave: u32) -> Vec<u8> {
+        let mut rom = vec![0; 0x1000];
+
+        let header = &mut rom[0x100..0x200];
+        header.fill(b' ');
+        header[..0x10].copy_from_slice(system_type);
+        header[0x10..0x20].copy_from_slice(b"(C)SEGA 1994.NOV");
+        header[0x20..0x2B].copy_from_slice(b"VIRTUA RACE");
+        header[0x80..0x8E].copy_from_slice(b"GM MK-84600-00");
+        header[0xF0..0xF3].copy_from_slice(b"JUE");
+
+        let mars = &mut rom[0x3C0..0x3F0];
+        mars[..0x10].copy_from_slice(b"MARS CHECK MODE ");
+        mars[0x14..0x18].copy_from_slice(&0x400u32.to_be_bytes());
+        mars[0x18..0x1C].copy_from_slice(&SDRAM_START.to_be_bytes());
+        mars[0x1C..0x20].copy_from_slice(&0x800u32.to_be_bytes());
+        mars[0x20..0x24].copy_from_slice(&master.to_be_bytes());
+        mars[0x24..0x28].copy_from_slice(&slave.to_be_bytes());
+
+        rom
+    }
+
+    fn probe_rom(rom: &[u8]) -> u8 {
+        probe(&mut Cursor::new(rom)).unwrap()
+    }
+
+    #[test]
+    fn probes_system_type_and_entry_points() {
+        let rom = cartridge(b"SEGA 32X        ", 0x0600_0120, 0x0600_0124);
+        assert_eq!(probe_rom(&rom), 98);
+
+        // A leading space is seen on some cartridges
+        let rom = cartridge(b" SEGA 32X       ", 0x0600_0120, 0x0600_0124);
+        assert_eq!(probe_rom(&rom), 98);
+
+        let rom = cartridge(b"SEGA 32X        ", 0x0200_0000, 0x0600_0124);
+        assert_eq!(probe_rom(&rom), 95);
+
+        let detection = detect_from_contents(&mut Cursor::new(&rom), rom.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(detection.platform, Platform::Sega32X);
+    }
+
+    #[test]
+    fn leaves_mega_drive_cartridges_alone() {
+        let rom = cartridge(b"SEGA MEGA DRIVE ", 0x0600_0120, 0x0600_0124);
+        assert_eq!(probe_rom(&rom), 0);
+
+        let detection = detect_from_contents(&mut Cursor::new(&rom), rom.len() as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(detection.platform, Platform::MegaDrive);
+    }
+
+    #[test]
+    fn checks_entry_points_are_in_sdram() {
+        let entry_points = [
+            (0x0600_0000, 0x0603_FFFC, true),
+            (0x0600_0120, 0x0604_0000, false),
+            (0x05FF_FFFC, 0x0600_0120, false),
+            (0x2200_0000, 0x2200_0000, false),
+        ];
+
+        for (master, slave, in_sdram) in entry_points {
+            let rom = cartridge(b"SEGA 32X        ", master, slave);
+            let mars = read_mars_header(&mut Cursor::new(&rom)).unwrap();
+            assert_eq!(
+                mars.entry_points_in_sdram(),
+                in_sdram,
+                "{:#x} {:#x}",
+                master,
+                slave
+            );
+        }
+    }
+
+    #[test]
+    fn reads_mars_and_cartridge_headers() {
+        let rom = cartridge(b"SEGA 32X        ", 0x0600_0120, 0x0600_0124);
+        let rom = rom_from_reader(&mut Cursor::new(&rom), rom.len() as u64).unwrap();
+
+        assert_eq!(rom.mars.module_name, "MARS CHECK MODE");
+        assert_eq!(rom.mars.source_address, 0x400);
+        assert_eq!(rom.mars.destination_address, SDRAM_START);
+        assert_eq!(rom.mars.size, 0x800);
+        assert_eq!(rom.mars.master_entry_point, 0x0600_0120);
+        assert_eq!(rom.mars.slave_entry_point, 0x0600_0124);
+        assert_eq!(rom.cartridge.software_title.domestic, "VIRTUA RACE");
+        assert_eq!(rom.cartridge.system_type, "SEGA 32X");
+        assert_eq!(rom.cartridge.release_date.year, Some(1994));
+    }
+}
@@ -1,3 +1,107 @@
+use crate::archive::Source;
+use crate::hash::RomHashes;
+use crate::metadata::Metadata;
+use crate::Platform;
+use anyhow::{bail, Result};
+use std::any::Any;
+use std::fmt::Debug;
+use std::path::Path;
+
+pub mod gameboy;
+pub mod gba;
 pub mod megadrive;
+pub mod n64;
 pub mod nds;
+pub mod nes;
+pub mod sms;
 pub mod snes;
+
+// Every supported platform. Content probes run in this order, and the first one wins a tie.
+pub static PLATFORMS: &[&dyn RomPlatform] = &[
+    &gameboy::GameBoy,
+    &gba::GameBoyAdvance,
+    &megadrive::MegaDrive,
+    &megadrive::megacd::MegaCD,
+    &megadrive::sega32x::Sega32X,
+    &n64::Nintendo64,
+    &nds::NintendoDS,
+    &nes::NES,
+    &sms::MasterSystem,
+    &sms::GameGear,
+    &snes::SuperNintendo,
+];
+
+// Everything needed to recognise and read the ROMs of one platform.
+pub trait RomPlatform: Sync {
+    fn platform(&self) -> Platform;
+
+    // The label used to pick the platform, such as on the command line.
+    fn name(&self) -> &'static str;
+
+    // Other labels accepted for the platform.
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    // File extensions used for the platform's ROMs. The first one is the preferred one.
+    fn extensions(&self) -> &'static [&'static str];
+
+    // Estimates how likely it is that the contents are a ROM for this platform, from 0 to 100.
+    fn probe(&self, reader: &mut dyn Source, size: u64) -> Result<u8>;
+
+    // Reads the ROM from the first `size` bytes of the reader.
+    fn rom_from_reader(&self, reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>>;
+
+    // Whether `fix_checksum` is supported.
+    fn fixes_checksums(&self) -> bool {
+        false
+    }
+
+    // Copies the ROM to `dest` and writes the calculated checksum into the copy's header.
+    // Returns the checksum as it was before the fix.
+    fn fix_checksum(&self, _source: &Path, _dest: &Path) -> Result<FixedChecksum> {
+        bail!(
+            "Fixing checksums is not supported for {:?}",
+            self.platform()
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct FixedChecksum {
+    pub declared: u16,
+    pub calculated: u16,
+    // Whether it was already right, in which case the copy is unchanged
+    pub valid: bool,
+}
+
+// What was read from a ROM. The fields differ for every platform.
+pub trait RomInfo: erased_serde::Serialize + Debug + Send + AsAny {
+    fn hashes(&self) -> &RomHashes;
+
+    // Values from the header that identify the game, for checking against DATs.
+    fn header_values(&self) -> Vec<(&'static str, String)>;
+
+    // The details every platform has in common, in the same shape for all of them.
+    fn metadata(&self) -> Metadata;
+}
+
+erased_serde::serialize_trait_object!(RomInfo);
+
+// Gets the platform's own type back out of a `dyn RomInfo`, such as a `snes::Rom`.
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub fn find(platform: Platform) -> &'static dyn RomPlatform {
+    *PLATFORMS
+        .iter()
+        .find(|p| p.platform() == platform)
+        .expect("every platform is registered")
+}
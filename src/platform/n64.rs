@@ -0,0 +1,552 @@
+use super::{RomInfo, RomPlatform};
+use crate::archive::{self, Source};
+use crate::hash::{self, RomHashes};
+use crate::metadata::{Metadata, Region, VideoStandard};
+use crate::Platform;
+use anyhow::{bail, Context, Result};
+use binread::{io::Cursor, io::Read, io::Seek, BinRead};
+use encoding::codec::japanese::Windows31JEncoding;
+use encoding::{DecoderTrap, Encoding};
+use log::debug;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: usize = 0x40;
+
+// The boot code (IPL3) follows the header. The CIC chip on the cartridge only lets it run
+// when it matches, so its CRC tells which chip the game was made for.
+const BOOT_CODE_START: usize = 0x40;
+const BOOT_CODE_END: usize = 0x1000;
+
+// CRC1 and CRC2 cover the first megabyte after the boot code.
+const CHECKSUM_START: usize = 0x1000;
+const CHECKSUM_LENGTH: usize = 0x100000;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// How the ROM is laid out, going by the first word of the header (0x80371240).
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum ByteOrder {
+    // .z64, as the cartridge stores it
+    BigEndian,
+    // .v64, every pair of bytes swapped
+    ByteSwapped,
+    // .n64, every four bytes reversed
+    LittleEndian,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Crc {
+    pub declared: u32,
+    // Not known for boot code we can't identify, or ROMs too short to cover
+    pub calculated: Option<u32>,
+    pub valid: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rom {
+    pub byte_order: ByteOrder,
+    pub title: String,
+    pub game_id: String,
+    pub category_code: String,
+    pub destination: String,
+    pub version: u8,
+    pub clock_rate: u32,
+    pub boot_address: u32,
+    pub libultra_version: u32,
+    pub cic: Option<&'static str>,
+    pub crc1: Crc,
+    pub crc2: Crc,
+    pub hashes: RomHashes,
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+#[allow(dead_code)]
+pub struct RomHeader {
+    pi_settings: u32,
+    clock_rate: u32,
+    boot_address: u32,
+    libultra_version: u32,
+    crc1: u32,
+    crc2: u32,
+
+    #[br(pad_before = 8, count = 20, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    title: String,
+
+    // The game ID is the category, a two-letter code for the game and the destination
+    #[br(pad_before = 7, count = 4, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    game_id: String,
+
+    version: u8,
+}
+
+// Japanese titles are in Shift JIS.
+fn bytes_to_stripped_string(bytes: &[u8]) -> Result<String> {
+    let s = Windows31JEncoding
+        .decode(bytes, DecoderTrap::Ignore)
+        .unwrap();
+
+    Ok(s.trim_end_matches(char::from(0x00)).trim_end().to_string())
+}
+
+impl ByteOrder {
+    pub fn detect(first_word: &[u8]) -> Option<ByteOrder> {
+        match first_word {
+            [0x80, 0x37, 0x12, 0x40] => Some(ByteOrder::BigEndian),
+            [0x37, 0x80, 0x40, 0x12] => Some(ByteOrder::ByteSwapped),
+            [0x40, 0x12, 0x37, 0x80] => Some(ByteOrder::LittleEndian),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ByteOrder::BigEndian => "z64",
+            ByteOrder::ByteSwapped => "v64",
+            ByteOrder::LittleEndian => "n64",
+        }
+    }
+
+    // Converts between this order and big-endian, in place. Swapping works the same in
+    // both directions. A trailing partial word is left alone.
+    pub fn swap(&self, bytes: &mut [u8]) {
+        match self {
+            ByteOrder::BigEndian => (),
+            ByteOrder::ByteSwapped => bytes.chunks_exact_mut(2).for_each(|c| c.swap(0, 1)),
+            ByteOrder::LittleEndian => bytes.chunks_exact_mut(4).for_each(|c| c.reverse()),
+        }
+    }
+}
+
+pub fn parse_byte_order_label(label: &str) -> Option<ByteOrder> {
+    match label {
+        "z64" => Some(ByteOrder::BigEndian),
+        "v64" => Some(ByteOrder::ByteSwapped),
+        "n64" => Some(ByteOrder::LittleEndian),
+        _ => None,
+    }
+}
+
+// The boot code variants, by the CRC32 of the boot code.
+fn identify_cic(boot_code: &[u8]) -> Option<&'static str> {
+    match crc32fast::hash(boot_code) {
+        0x6170A4A1 => Some("6101"),
+        0x009E9EA3 => Some("7102"),
+        0x90BB6CB5 => Some("6102"),
+        0x0B050EE0 => Some("6103"),
+        0x98BC2C86 => Some("6105"),
+        0xACC8580A => Some("6106"),
+        0x0E018159 => Some("8303"),
+        _ => None,
+    }
+}
+
+impl RomHeader {
+    pub fn destination(&self) -> String {
+        let name = match self.game_id.chars().nth(3) {
+            Some('7') => "Beta",
+            Some('A') => "Asia",
+            Some('B') => "Brazil",
+            Some('C') => "China",
+            Some('D') => "Germany",
+            Some('E') => "North America",
+            Some('F') => "France",
+            Some('G') => "Gateway 64 (NTSC)",
+            Some('H') => "Netherlands",
+            Some('I') => "Italy",
+            Some('J') => "Japan",
+            Some('K') => "Korea",
+            Some('L') => "Gateway 64 (PAL)",
+            Some('N') => "Canada",
+            Some('P') | Some('X') | Some('Y') | Some('Z') => "Europe",
+            Some('S') => "Spain",
+            Some('U') => "Australia",
+            Some('W') => "Scandinavia",
+            Some(other) => return format!("Unknown '{}'", other),
+            None => return "Unknown".to_string(),
+        };
+
+        name.to_string()
+    }
+}
+
+// The last character of the game ID is the destination.
+fn destination_region(game_id: &str) -> Option<Region> {
+    match game_id.chars().nth(3)? {
+        'A' => Some(Region::Asia),
+        'B' => Some(Region::Brazil),
+        'C' => Some(Region::China),
+        'E' | 'G' | 'N' => Some(Region::NorthAmerica),
+        'J' => Some(Region::Japan),
+        'K' => Some(Region::Korea),
+        'U' => Some(Region::Australia),
+        'D' | 'F' | 'H' | 'I' | 'L' | 'P' | 'S' | 'W' | 'X' | 'Y' | 'Z' => Some(Region::Europe),
+        _ => None,
+    }
+}
+
+pub struct Nintendo64;
+
+impl RomPlatform for Nintendo64 {
+    fn platform(&self) -> Platform {
+        Platform::Nintendo64
+    }
+
+    fn name(&self) -> &'static str {
+        "n64"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["z64", "v64", "n64"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        Ok(Box::new(rom_from_reader(&mut reader, size)?))
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        vec![("game_id", self.game_id.to_string())]
+    }
+
+    fn metadata(&self) -> Metadata {
+        let regions: Vec<Region> = destination_region(&self.game_id).into_iter().collect();
+
+        Metadata {
+            title: self.title.to_string(),
+            video_standard: VideoStandard::for_regions(&regions),
+            regions,
+            product_code: Some(self.game_id.to_string()),
+            revision: Some(self.version.to_string()),
+            rom_size: self.hashes.payload().size,
+            ..Default::default()
+        }
+    }
+}
+
+// Estimates how likely it is that the file is a Nintendo 64 ROM, from 0 to 100.
+//
+// Every cartridge starts with the same PI bus settings, in whichever byte order.
+pub fn probe<R: Read + Seek>(file: &mut R) -> Result<u8> {
+    let mut buffer = [0; 4];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut buffer)?;
+
+    match ByteOrder::detect(&buffer) {
+        Some(_) => Ok(95),
+        None => Ok(0),
+    }
+}
+
+pub fn rom_from_file(path: &Path) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size());
+    }
+
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size)
+}
+
+// Reads the ROM from the first `size` bytes of the reader.
+pub fn rom_from_reader<R: Read + Seek>(f: &mut R, size: u64) -> Result<Rom> {
+    // Everything the header, boot code and checksums need, in big-endian order
+    let mut data = vec![0; CHECKSUM_START + CHECKSUM_LENGTH];
+    f.seek(SeekFrom::Start(0))?;
+    let len = read_full(&mut f.take(size), &mut data)?;
+    data.truncate(len);
+
+    if data.len() < BOOT_CODE_END {
+        bail!(
+            "At {} bytes, this is too small to be a Nintendo 64 ROM",
+            size
+        );
+    }
+
+    let byte_order = ByteOrder::detect(&data[..4])
+        .context("Unrecognised byte order. This may not be a Nintendo 64 ROM.")?;
+    byte_order.swap(&mut data);
+    debug!("Byte order is {:?}", byte_order);
+
+    let header = RomHeader::read(&mut Cursor::new(&data[..HEADER_SIZE]))
+        .context("Failed to parse ROM header")?;
+    debug!("Read ROM header: {:?}", header);
+
+    let cic = identify_cic(&data[BOOT_CODE_START..BOOT_CODE_END]);
+    let calculated = cic.and_then(|cic| calculate_crcs(&data, cic));
+
+    f.seek(SeekFrom::Start(0))?;
+    let mut hashes = hash::hash_reader(f.take(size), 0)?;
+    if byte_order != ByteOrder::BigEndian {
+        f.seek(SeekFrom::Start(0))?;
+        let reader = BigEndianReader {
+            inner: f.take(size),
+            byte_order,
+        };
+        hashes.big_endian = Some(hash::hash_reader(reader, 0)?.file);
+    }
+
+    Ok(rom_from_header(header, byte_order, cic, calculated, hashes))
+}
+
+// Calculates CRC1 and CRC2 the way the boot code does. Each CIC seeds the calculation
+// differently, and a few of them mix the results differently too.
+//
+// Returns nothing for the 64DD, which doesn't use them, or when the ROM is too short.
+pub fn calculate_crcs(data: &[u8], cic: &str) -> Option<(u32, u32)> {
+    let seed: u32 = match cic {
+        "6101" | "6102" | "7102" => 0xF8CA4DDC,
+        "6103" => 0xA3886759,
+        "6105" => 0xDF26F436,
+        "6106" => 0x1FEA617A,
+        _ => return None,
+    };
+
+    let words = data.get(CHECKSUM_START..CHECKSUM_START + CHECKSUM_LENGTH)?;
+    let word_at = |bytes: &[u8], i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+
+    let (mut t1, mut t2, mut t3, mut t4, mut t5, mut t6) = (seed, seed, seed, seed, seed, seed);
+
+    for i in (0..CHECKSUM_LENGTH).step_by(4) {
+        let d = word_at(words, i);
+
+        let (sum, overflowed) = t6.overflowing_add(d);
+        if overflowed {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = sum;
+
+        t3 ^= d;
+
+        let r = d.rotate_left(d & 0x1F);
+        t5 = t5.wrapping_add(r);
+
+        if t2 > d {
+            t2 ^= r;
+        } else {
+            t2 ^= t6 ^ d;
+        }
+
+        // The 6105 mixes in part of its own boot code
+        if cic == "6105" {
+            t1 = t1.wrapping_add(word_at(data, 0x750 + (i & 0xFF)) ^ d);
+        } else {
+            t1 = t1.wrapping_add(t5 ^ d);
+        }
+    }
+
+    Some(match cic {
+        "6103" => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+        "6106" => (
+            t6.wrapping_mul(t4).wrapping_add(t3),
+            t5.wrapping_mul(t2).wrapping_add(t1),
+        ),
+        _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
+    })
+}
+
+// Writes a copy of the ROM in the given byte order. Returns the order it was in before.
+pub fn convert(source: &Path, dest: &Path, target: ByteOrder) -> Result<ByteOrder> {
+    let mut input = File::open(source).with_context(|| format!("Failed to open {:?}", source))?;
+
+    let mut first_word = [0; 4];
+    input.read_exact(&mut first_word)?;
+    let byte_order = ByteOrder::detect(&first_word)
+        .context("Unrecognised byte order. This may not be a Nintendo 64 ROM.")?;
+    input.seek(SeekFrom::Start(0))?;
+
+    let mut output = File::create(dest).with_context(|| format!("Failed to create {:?}", dest))?;
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    loop {
+        let len = read_full(&mut input, &mut buffer)?;
+        if len == 0 {
+            break;
+        }
+
+        let chunk = &mut buffer[..len];
+        byte_order.swap(chunk);
+        target.swap(chunk);
+        output.write_all(chunk)?;
+    }
+
+    Ok(byte_order)
+}
+
+// Reads until the buffer is full or there's nothing left, so chunks stay word-aligned.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            len => filled += len,
+        }
+    }
+
+    Ok(filled)
+}
+
+// Reads a ROM stored in another byte order as big-endian, for hashing it the way DATs do.
+struct BigEndianReader<R> {
+    inner: R,
+    byte_order: ByteOrder,
+}
+
+impl<R: Read> Read for BigEndianReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Only whole words can be swapped
+        let len = buf.len() - buf.len() % 4;
+        if len == 0 {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+
+        let read = read_full(&mut self.inner, &mut buf[..len])?;
+        self.byte_order.swap(&mut buf[..read]);
+
+        Ok(read)
+    }
+}
+
+fn rom_from_header(
+    header: RomHeader,
+    byte_order: ByteOrder,
+    cic: Option<&'static str>,
+    calculated: Option<(u32, u32)>,
+    hashes: RomHashes,
+) -> Rom {
+    let (crc1, crc2) = match calculated {
+        Some((crc1, crc2)) => (Some(crc1), Some(crc2)),
+        None => (None, None),
+    };
+
+    Rom {
+        byte_order,
+        destination: header.destination(),
+        category_code: header.game_id.chars().take(1).collect(),
+        crc1: Crc {
+            declared: header.crc1,
+            calculated: crc1,
+            valid: crc1 == Some(header.crc1),
+        },
+        crc2: Crc {
+            declared: header.crc2,
+            calculated: crc2,
+            valid: crc2 == Some(header.crc2),
+        },
+        title: header.title,
+        game_id: header.game_id,
+        version: header.version,
+        clock_rate: header.clock_rate,
+        boot_address: header.boot_address,
+        libultra_version: header.libultra_version,
+        cic,
+        hashes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A big-endian ROM with a header, boot code nobody recognises, and enough data for the
+    // checksums to cover
+    fn rom() -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..CHECKSUM_START + CHECKSUM_LENGTH)
+            .map(|i| (i * 13) as u8)
+            .collect();
+        rom[..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+        rom[0x20..0x34].copy_from_slice(b"TEST TITLE          ");
+        rom[0x3B..0x3F].copy_from_slice(b"NTEE");
+        rom[0x3F] = 2;
+
+        rom
+    }
+
+    #[test]
+    fn calculates_crcs_for_blank_data() {
+        let data = vec![0; CHECKSUM_START + CHECKSUM_LENGTH];
+
+        // With nothing to add, only the running total of t5 builds up in t1.
+        let seed: u32 = 0xF8CA4DDC;
+        let t1 = seed.wrapping_mul(CHECKSUM_LENGTH as u32 / 4 + 1);
+        assert_eq!(calculate_crcs(&data, "6102"), Some((seed, t1)));
+        assert_eq!(calculate_crcs(&data, "7102"), Some((seed, t1)));
+
+        // The 6105 adds in its blank boot code instead.
+        let seed: u32 = 0xDF26F436;
+        assert_eq!(calculate_crcs(&data, "6105"), Some((seed, seed)));
+    }
+
+    #[test]
+    fn seeds_crcs_by_cic() {
+        let data = rom();
+
+        let crcs: Vec<_> = ["6102", "6103", "6105", "6106"]
+            .iter()
+            .map(|cic| calculate_crcs(&data, cic).unwrap())
+            .collect();
+
+        for (i, a) in crcs.iter().enumerate() {
+            for b in &crcs[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn skips_crcs_it_cannot_calculate() {
+        let data = rom();
+
+        assert_eq!(calculate_crcs(&data, "8303"), None);
+        assert_eq!(calculate_crcs(&data[..CHECKSUM_START + 4], "6102"), None);
+    }
+
+    #[test]
+    fn swaps_byte_orders() {
+        let data = rom();
+
+        for byte_order in [ByteOrder::ByteSwapped, ByteOrder::LittleEndian] {
+            let mut swapped = data[..8].to_vec();
+            byte_order.swap(&mut swapped);
+            assert_eq!(ByteOrder::detect(&swapped[..4]), Some(byte_order));
+
+            byte_order.swap(&mut swapped);
+            assert_eq!(swapped, &data[..8]);
+        }
+    }
+
+    #[test]
+    fn reads_header_in_any_byte_order() {
+        let data = rom();
+        let z64 = rom_from_reader(&mut Cursor::new(&data), data.len() as u64).unwrap();
+
+        let mut swapped = data.clone();
+        ByteOrder::ByteSwapped.swap(&mut swapped);
+        let v64 = rom_from_reader(&mut Cursor::new(&swapped), swapped.len() as u64).unwrap();
+
+        assert_eq!(v64.byte_order, ByteOrder::ByteSwapped);
+        assert_eq!(v64.title, "TEST TITLE");
+        assert_eq!(v64.game_id, "NTEE");
+        assert_eq!(v64.version, 2);
+        assert_eq!(v64.hashes.big_endian, Some(z64.hashes.file));
+
+        // The boot code is made up, so there's nothing to check the CRCs against.
+        assert_eq!(v64.cic, None);
+        assert_eq!(v64.crc1.calculated, None);
+        assert!(!v64.crc1.valid);
+    }
+}
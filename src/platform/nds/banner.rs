@@ -0,0 +1,456 @@
+use anyhow::{bail, Context, Result};
+use binread::{io::Cursor, BinRead};
+use log::debug;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const ICON_SIZE: u32 = 32;
+
+// Offsets within the banner
+const TITLES_START: usize = 0x240;
+const TITLE_SIZE: usize = 0x100;
+const ANIMATED_BITMAPS_START: usize = 0x1240;
+const ANIMATED_PALETTES_START: usize = 0x2240;
+const ANIMATION_SEQUENCE_START: usize = 0x2340;
+const ANIMATION_SEQUENCE_LEN: usize = 64;
+
+// The order titles are stored in the banner. Newer banner versions append languages.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Language {
+    Japanese,
+    English,
+    French,
+    German,
+    Italian,
+    Spanish,
+    Chinese,
+    Korean,
+}
+
+const LANGUAGES: [Language; 8] = [
+    Language::Japanese,
+    Language::English,
+    Language::French,
+    Language::German,
+    Language::Italian,
+    Language::Spanish,
+    Language::Chinese,
+    Language::Korean,
+];
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+#[allow(dead_code)]
+struct BannerHeader {
+    version: u16,
+
+    #[br(pad_after = 0x16)]
+    crcs: [u16; 4],
+
+    // 32x32 pixels at 4 bits per pixel, laid out in 8x8 tiles.
+    #[br(count = 0x200)]
+    bitmap: Vec<u8>,
+
+    // 16 BGR555 colours. The first is always transparent.
+    #[br(count = 16)]
+    palette: Vec<u16>,
+}
+
+// A single step in a DSi animated icon.
+#[derive(Debug)]
+pub struct SequenceStep {
+    // How long to show the frame, in 60 Hz ticks.
+    pub duration: u8,
+    pub bitmap: usize,
+    pub palette: usize,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+}
+
+#[derive(Debug)]
+pub struct Animation {
+    bitmaps: Vec<Vec<u8>>,
+    palettes: Vec<Vec<u16>>,
+    pub sequence: Vec<SequenceStep>,
+}
+
+#[derive(Debug)]
+pub struct Banner {
+    pub titles: BTreeMap<Language, String>,
+    bitmap: Vec<u8>,
+    palette: Vec<u16>,
+    pub animation: Option<Animation>,
+}
+
+// An icon decoded to 8-bit RGBA.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+// How many bytes of the banner exist for each version.
+pub fn banner_size(version: u16) -> Result<usize> {
+    match version {
+        0x0001 => Ok(0x840),
+        0x0002 => Ok(0x940),
+        0x0003 => Ok(0xA40),
+        0x0103 => Ok(0x23C0),
+        other => bail!("Unknown banner version {:#06x}", other),
+    }
+}
+
+// Recalculates the banner's CRCs after it's been edited.
+//
+// Each banner version adds a CRC covering its additions, stored one after another.
+pub fn update_crcs(banner: &mut [u8]) -> Result<()> {
+    let version = match banner {
+        [low, high, ..] => u16::from_le_bytes([*low, *high]),
+        _ => bail!("Banner is truncated"),
+    };
+    if banner.len() < banner_size(version)? {
+        bail!("Banner is truncated");
+    }
+
+    let ranges = [
+        (0x0001, 0x20, 0x840),
+        (0x0002, 0x20, 0x940),
+        (0x0003, 0x20, 0xA40),
+        (0x0103, ANIMATED_BITMAPS_START, 0x23C0),
+    ];
+
+    for (i, &(_, start, end)) in ranges.iter().filter(|(v, _, _)| version >= *v).enumerate() {
+        let crc = super::crc16(&banner[start..end]);
+        banner[2 + i * 2..4 + i * 2].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    Ok(())
+}
+
+pub fn read_banner<R: Read + Seek>(file: &mut R, offset: u32) -> Result<Banner> {
+    let mut version = [0; 2];
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.read_exact(&mut version)?;
+    let version = u16::from_le_bytes(version);
+
+    let mut buffer = vec![0; banner_size(version)?];
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.read_exact(&mut buffer)
+        .context("Banner is truncated")?;
+
+    let header = BannerHeader::read(&mut Cursor::new(&buffer))?;
+    debug!("Read banner header: {:?}", header);
+
+    let mut titles = BTreeMap::new();
+    for (i, &language) in LANGUAGES.iter().enumerate() {
+        let start = TITLES_START + i * TITLE_SIZE;
+        if start + TITLE_SIZE > buffer.len() {
+            break;
+        }
+
+        let title = utf16le_to_string(&buffer[start..start + TITLE_SIZE]);
+        if !title.is_empty() {
+            titles.insert(language, title);
+        }
+    }
+
+    let animation = match version {
+        0x0103 => read_animation(&buffer),
+        _ => None,
+    };
+
+    Ok(Banner {
+        titles,
+        bitmap: header.bitmap,
+        palette: header.palette,
+        animation,
+    })
+}
+
+// Titles are fixed-length UTF-16LE strings padded with nulls.
+fn utf16le_to_string(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&u| u != 0)
+        .collect();
+
+    String::from_utf16_lossy(&units).trim_end().to_string()
+}
+
+fn read_animation(buffer: &[u8]) -> Option<Animation> {
+    let bitmaps = buffer[ANIMATED_BITMAPS_START..ANIMATED_PALETTES_START]
+        .chunks_exact(0x200)
+        .map(|c| c.to_vec())
+        .collect();
+
+    let palettes = buffer[ANIMATED_PALETTES_START..ANIMATION_SEQUENCE_START]
+        .chunks_exact(0x20)
+        .map(|c| {
+            c.chunks_exact(2)
+                .map(|p| u16::from_le_bytes([p[0], p[1]]))
+                .collect()
+        })
+        .collect();
+
+    // The sequence ends at the first zeroed entry.
+    let sequence: Vec<SequenceStep> = buffer[ANIMATION_SEQUENCE_START..]
+        .chunks_exact(2)
+        .take(ANIMATION_SEQUENCE_LEN)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&entry| entry != 0)
+        .map(|entry| SequenceStep {
+            duration: (entry & 0xFF) as u8,
+            bitmap: ((entry >> 8) & 0x07) as usize,
+            palette: ((entry >> 11) & 0x07) as usize,
+            flip_horizontal: entry & 0x4000 != 0,
+            flip_vertical: entry & 0x8000 != 0,
+        })
+        .collect();
+
+    if sequence.is_empty() {
+        return None;
+    }
+
+    Some(Animation {
+        bitmaps,
+        palettes,
+        sequence,
+    })
+}
+
+impl Banner {
+    pub fn icon(&self) -> Image {
+        decode_icon(&self.bitmap, &self.palette, false, false)
+    }
+
+    // Every step of the animation decoded, paired with its duration in 60 Hz ticks.
+    pub fn animation_frames(&self) -> Option<Vec<(Image, u8)>> {
+        let animation = self.animation.as_ref()?;
+
+        let frames = animation
+            .sequence
+            .iter()
+            .map(|step| {
+                let image = decode_icon(
+                    &animation.bitmaps[step.bitmap],
+                    &animation.palettes[step.palette],
+                    step.flip_horizontal,
+                    step.flip_vertical,
+                );
+                (image, step.duration)
+            })
+            .collect();
+
+        Some(frames)
+    }
+}
+
+// Decodes a 4bpp tiled bitmap into RGBA.
+//
+// The 32x32 icon is made of 4x4 tiles that are 8x8 pixels each. Each byte holds two pixels,
+// with the left one in the low nibble.
+fn decode_icon(bitmap: &[u8], palette: &[u16], flip_h: bool, flip_v: bool) -> Image {
+    let size = ICON_SIZE as usize;
+    let mut rgba = vec![0; size * size * 4];
+
+    for (i, &byte) in bitmap.iter().enumerate() {
+        let tile = i / 32;
+        let tile_x = (tile % 4) * 8;
+        let tile_y = (tile / 4) * 8;
+        let y = tile_y + (i % 32) / 4;
+
+        for nibble in 0..2 {
+            let x = tile_x + (i % 4) * 2 + nibble;
+            let index = ((byte >> (nibble * 4)) & 0x0F) as usize;
+
+            let out_x = if flip_h { size - 1 - x } else { x };
+            let out_y = if flip_v { size - 1 - y } else { y };
+            let pos = (out_y * size + out_x) * 4;
+
+            // Index 0 is transparent, so leave it zeroed out.
+            if index != 0 {
+                rgba[pos..pos + 4].copy_from_slice(&bgr555_to_rgba(palette[index]));
+            }
+        }
+    }
+
+    Image {
+        width: ICON_SIZE,
+        height: ICON_SIZE,
+        rgba,
+    }
+}
+
+// Scales 5-bit colour channels to 8 bits by repeating the high bits in the low bits.
+fn bgr555_to_rgba(colour: u16) -> [u8; 4] {
+    let scale = |c: u16| -> u8 {
+        let c = (c & 0x1F) as u8;
+        (c << 3) | (c >> 2)
+    };
+
+    [scale(colour), scale(colour >> 5), scale(colour >> 10), 0xFF]
+}
+
+pub fn write_png(path: &Path, image: &Image) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), image.width, image.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image.rgba)?;
+
+    Ok(())
+}
+
+// Writes the frames as an animated PNG that loops forever.
+pub fn write_apng(path: &Path, frames: &[(Image, u8)]) -> Result<()> {
+    let first = match frames.first() {
+        Some((image, _)) => image,
+        None => bail!("Cannot write an animation without frames"),
+    };
+
+    let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), first.width, first.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+
+    let mut writer = encoder.write_header()?;
+    for (image, duration) in frames {
+        writer.set_frame_delay(*duration as u16, 60)?;
+        writer.write_image_data(&image.rgba)?;
+    }
+    writer.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A banner of the given version with a title for each language in order, and an icon
+    // with a few pixels set
+    fn banner(version: u16, titles: &[&str]) -> Vec<u8> {
+        let mut data = vec![0; banner_size(version).unwrap()];
+        data[..2].copy_from_slice(&version.to_le_bytes());
+
+        for (i, title) in titles.iter().enumerate() {
+            let start = TITLES_START + i * TITLE_SIZE;
+            for (j, unit) in title.encode_utf16().enumerate() {
+                data[start + j * 2..start + j * 2 + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+
+        // The first two pixels, the first one in the second tile row, and the last one
+        data[0x20] = 0x21;
+        data[0x24] = 0x03;
+        data[0x20 + 0x1FF] = 0x10;
+        // Red, blue and green
+        for (i, colour) in [0x001Fu16, 0x7C00, 0x03E0].iter().enumerate() {
+            let start = 0x220 + (i + 1) * 2;
+            data[start..start + 2].copy_from_slice(&colour.to_le_bytes());
+        }
+
+        update_crcs(&mut data).unwrap();
+        data
+    }
+
+    fn read(data: &[u8]) -> Banner {
+        read_banner(&mut Cursor::new(data), 0).unwrap()
+    }
+
+    fn pixel(image: &Image, x: usize, y: usize) -> [u8; 4] {
+        let pos = (y * image.width as usize + x) * 4;
+        image.rgba[pos..pos + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn decodes_utf16_titles() {
+        let data = banner(1, &["ポケモン\nNintendo", "Pokémon\nNintendo"]);
+
+        let titles = read(&data).titles;
+        assert_eq!(titles[&Language::Japanese], "ポケモン\nNintendo");
+        assert_eq!(titles[&Language::English], "Pokémon\nNintendo");
+    }
+
+    #[test]
+    fn keys_titles_by_language() {
+        let data = banner(1, &["", "Title", "", "Titel"]);
+
+        let titles = read(&data).titles;
+        assert_eq!(
+            titles.into_iter().collect::<Vec<_>>(),
+            vec![
+                (Language::English, "Title".to_string()),
+                (Language::German, "Titel".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_the_languages_of_each_version() {
+        let all = ["JA", "EN", "FR", "DE", "IT", "ES", "ZH", "KO"];
+
+        // Chinese and Korean past the end of a version 1 banner aren't part of it
+        let mut data = banner(3, &all);
+        data[..2].copy_from_slice(&1u16.to_le_bytes());
+        let titles = read(&data).titles;
+        assert_eq!(titles.len(), 6);
+        assert!(!titles.contains_key(&Language::Chinese));
+
+        data[..2].copy_from_slice(&2u16.to_le_bytes());
+        let titles = read(&data).titles;
+        assert_eq!(titles.len(), 7);
+        assert_eq!(titles[&Language::Chinese], "ZH");
+
+        data[..2].copy_from_slice(&3u16.to_le_bytes());
+        let titles = read(&data).titles;
+        assert_eq!(titles.len(), 8);
+        assert_eq!(titles[&Language::Korean], "KO");
+    }
+
+    #[test]
+    fn decodes_tiled_icons() {
+        let icon = read(&banner(1, &["", "Title"])).icon();
+
+        assert_eq!((icon.width, icon.height), (32, 32));
+        assert_eq!(pixel(&icon, 0, 0), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(pixel(&icon, 1, 0), [0, 0, 0xFF, 0xFF]);
+        assert_eq!(pixel(&icon, 0, 1), [0, 0xFF, 0, 0xFF]);
+        assert_eq!(pixel(&icon, 31, 31), [0xFF, 0, 0, 0xFF]);
+        // Index 0 is transparent
+        assert_eq!(pixel(&icon, 2, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(&icon, 8, 0), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn round_trips_a_banner_through_a_png() {
+        let data = banner(3, &["", "Title"]);
+
+        // One CRC per version, each over what that version added
+        for (i, end) in [0x840, 0x940, 0xA40].into_iter().enumerate() {
+            let declared = u16::from_le_bytes([data[2 + i * 2], data[3 + i * 2]]);
+            assert_eq!(declared, crate::platform::nds::crc16(&data[0x20..end]));
+        }
+
+        let icon = read(&data).icon();
+        let path = std::env::temp_dir().join(format!("romboss-banner-{}.png", std::process::id()));
+        write_png(&path, &icon).unwrap();
+
+        let mut reader = png::Decoder::new(File::open(&path).unwrap())
+            .read_info()
+            .unwrap();
+        let mut rgba = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgba).unwrap();
+        assert_eq!((info.width, info.height), (32, 32));
+        assert_eq!(rgba, icon.rgba);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
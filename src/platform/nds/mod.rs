@@ -0,0 +1,531 @@
+use super::{RomInfo, RomPlatform};
+use crate::archive::{self, Source};
+use crate::hash::{self, RomHashes};
+use crate::metadata::{Metadata, Region};
+use crate::Platform;
+use anyhow::{bail, Context, Result};
+use binread::{io::Cursor, io::Read, io::Seek, BinRead};
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+pub mod banner;
+pub mod nitrofs;
+pub mod pack;
+
+use banner::Language;
+
+// Size of the header as stored on the cartridge. Only the first 0x180 bytes are used
+// by DS games; the rest is zero-filled or holds DSi extensions.
+const HEADER_SIZE: usize = 0x200;
+
+// The header CRC covers everything before the CRC itself.
+const HEADER_CRC_OFFSET: usize = 0x15E;
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+#[allow(dead_code)]
+pub struct RomHeader {
+    #[br(count = 12, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    game_title: String,
+
+    #[br(count = 4, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    game_code: String,
+
+    #[br(count = 2, try_map = |c: Vec<u8>| bytes_to_stripped_string(&c))]
+    maker_code: String,
+
+    unit_code: u8,
+
+    device_type: u8,
+
+    // 128 kB << VAL, so 2^(17 + VAL) bytes
+    card_size: u8,
+
+    #[br(pad_before = 8)]
+    flags: u8,
+
+    rom_version: u8,
+
+    autostart: u8,
+
+    arm9: BinaryHeader,
+
+    arm7: BinaryHeader,
+
+    fnt_offset: u32,
+    fnt_size: u32,
+
+    fat_offset: u32,
+    fat_size: u32,
+
+    arm9_overlay_offset: u32,
+    arm9_overlay_size: u32,
+
+    arm7_overlay_offset: u32,
+    arm7_overlay_size: u32,
+
+    normal_card_control: u32,
+    secure_card_control: u32,
+
+    icon_banner_offset: u32,
+
+    secure_area_crc: u16,
+    secure_transfer_timeout: u16,
+
+    arm9_autoload: u32,
+    arm7_autoload: u32,
+
+    secure_disable: u64,
+
+    total_used_rom_size: u32,
+
+    header_size: u32,
+
+    #[br(pad_before = 0x38, count = 156)]
+    logo: Vec<u8>,
+
+    logo_crc: u16,
+
+    header_crc: u16,
+}
+
+// Where one of the CPU binaries lives in the ROM and where it gets loaded to in RAM.
+#[derive(BinRead, Serialize, Debug)]
+#[br(little)]
+pub struct BinaryHeader {
+    pub rom_offset: u32,
+    pub entry_address: u32,
+    pub ram_address: u32,
+    pub size: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub enum Device {
+    DS,
+    DSi,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Section {
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Crc {
+    pub declared: u16,
+    pub calculated: u16,
+    pub valid: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rom {
+    pub software_title: String,
+    pub game_code: String,
+    pub maker_code: String,
+    pub supported_devices: Vec<Device>,
+    pub rom_version: u8,
+    pub card_size: u64,
+    pub arm9: BinaryHeader,
+    pub arm7: BinaryHeader,
+    pub file_name_table: Section,
+    pub file_allocation_table: Section,
+    pub arm9_overlay_table: Section,
+    pub arm7_overlay_table: Section,
+    pub icon_banner_offset: u32,
+    pub secure_area_crc: u16,
+    pub total_used_rom_size: u32,
+    pub header_size: u32,
+    pub logo_crc: Crc,
+    pub header_crc: Crc,
+    pub titles: BTreeMap<Language, String>,
+    pub hashes: RomHashes,
+}
+
+fn bytes_to_stripped_string(bytes: &[u8]) -> Result<String> {
+    let s = String::from_utf8(bytes.to_vec())?;
+
+    Ok(s.trim_end().trim_matches(char::from(0x00)).to_string())
+}
+
+impl RomHeader {
+    fn supported_devices(&self) -> Vec<Device> {
+        if self.unit_code == 3 {
+            return vec![Device::DSi];
+        }
+
+        if self.unit_code == 2 {
+            return vec![Device::DS, Device::DSi];
+        }
+
+        vec![Device::DS]
+    }
+
+    // The card capacity is stored as a shift of 128 kB.
+    fn card_size(&self) -> u64 {
+        (128 * 1024u64)
+            .checked_shl(self.card_size.into())
+            .unwrap_or(0)
+    }
+
+    fn logo_crc(&self) -> Crc {
+        let calculated = crc16(&self.logo);
+
+        Crc {
+            declared: self.logo_crc,
+            calculated,
+            valid: self.logo_crc == calculated && calculated == LOGO_CRC,
+        }
+    }
+}
+
+// CRC16 of the Nintendo logo bitmap. It's identical on every retail cartridge
+// because the console refuses to boot anything with a different logo.
+pub const LOGO_CRC: u16 = 0xCF56;
+
+// The CRC16 variant used throughout the DS header (also known as CRC-16/MODBUS).
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+
+    for &byte in bytes {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+
+    crc
+}
+
+pub struct NintendoDS;
+
+impl RomPlatform for NintendoDS {
+    fn platform(&self) -> Platform {
+        Platform::NintendoDS
+    }
+
+    fn name(&self) -> &'static str {
+        "ds"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["nds"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        Ok(Box::new(rom_from_reader(&mut reader, size)?))
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        vec![("game_code", self.game_code.to_string())]
+    }
+
+    // The banner has the full title, where the header only fits 12 characters. The banner's
+    // first line is the title, and the ones after it are a subtitle and the publisher.
+    fn metadata(&self) -> Metadata {
+        let mut titles: Vec<String> = Vec::new();
+        let banner_titles = self
+            .titles
+            .get(&Language::English)
+            .into_iter()
+            .chain(self.titles.values());
+
+        for title in banner_titles.chain(std::iter::once(&self.software_title)) {
+            let first_line = title.lines().next().unwrap_or_default().trim().to_string();
+            if !first_line.is_empty() && !titles.contains(&first_line) {
+                titles.push(first_line);
+            }
+        }
+
+        Metadata {
+            title: titles.first().cloned().unwrap_or_default(),
+            alt_titles: titles.into_iter().skip(1).collect(),
+            regions: Region::from_game_code(&self.game_code)
+                .into_iter()
+                .collect(),
+            publisher: Some(self.maker_code.to_string()),
+            product_code: Some(self.game_code.to_string()),
+            revision: Some(self.rom_version.to_string()),
+            release_date: None,
+            rom_size: self.hashes.payload().size,
+            save_type: None,
+            video_standard: None,
+        }
+    }
+}
+
+// Estimates how likely it is that the file is a Nintendo DS ROM, from 0 to 100.
+pub fn probe<R: Read + Seek>(file: &mut R) -> Result<u8> {
+    let mut buffer = [0; 2];
+    file.seek(std::io::SeekFrom::Start(0x15C))?;
+    file.read_exact(&mut buffer)?;
+
+    if u16::from_le_bytes(buffer) == LOGO_CRC {
+        return Ok(95);
+    }
+
+    Ok(0)
+}
+
+pub fn rom_from_file(path: &Path) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size());
+    }
+
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size)
+}
+
+// Reads the ROM from the first `size` bytes of the reader.
+pub fn rom_from_reader<R: Read + Seek>(f: &mut R, size: u64) -> Result<Rom> {
+    let (header, raw_header) = read_header(f)?;
+
+    // A broken banner shouldn't stop us from reporting on the rest of the ROM.
+    let titles = match header.icon_banner_offset {
+        0 => BTreeMap::new(),
+        offset => match banner::read_banner(f, offset) {
+            Ok(banner) => banner.titles,
+            Err(err) => {
+                warn!("Failed to read banner: {}", err);
+                BTreeMap::new()
+            }
+        },
+    };
+
+    f.seek(std::io::SeekFrom::Start(0))?;
+    let hashes = hash::hash_reader(f.take(size), 0)?;
+
+    Ok(rom_from_header(header, &raw_header, titles, hashes))
+}
+
+fn read_header<R: Read + Seek>(file: &mut R) -> Result<(RomHeader, [u8; HEADER_SIZE])> {
+    let mut buffer = [0; HEADER_SIZE];
+    file.seek(std::io::SeekFrom::Start(0))?;
+    file.read_exact(&mut buffer)?;
+
+    debug!("Read header bytes: {:?}", buffer);
+    let mut cursor = Cursor::new(&mut buffer);
+
+    let header = RomHeader::read(&mut cursor).context("Failed to parse ROM header")?;
+    debug!("Read ROM header: {:?}", header);
+
+    Ok((header, buffer))
+}
+
+// Writes the banner icon as a PNG. DSi animated icons are written as an APNG, or when
+// `as_frames` is set, as one PNG per step of the animation, numbered after `output`.
+//
+// Returns the paths that were written.
+pub fn export_icon(path: &Path, output: &Path, as_frames: bool) -> Result<Vec<PathBuf>> {
+    let mut f = File::open(path)?;
+    let (header, _) = read_header(&mut f)?;
+
+    if header.icon_banner_offset == 0 {
+        bail!("This ROM does not have a banner");
+    }
+
+    let banner = banner::read_banner(&mut f, header.icon_banner_offset)?;
+
+    match banner.animation_frames() {
+        Some(frames) if as_frames => {
+            let mut written = Vec::new();
+            for (i, (image, _)) in frames.iter().enumerate() {
+                let frame_path = numbered_path(output, i);
+                banner::write_png(&frame_path, image)?;
+                written.push(frame_path);
+            }
+            Ok(written)
+        }
+        Some(frames) => {
+            banner::write_apng(output, &frames)?;
+            Ok(vec![output.to_path_buf()])
+        }
+        None => {
+            banner::write_png(output, &banner.icon())?;
+            Ok(vec![output.to_path_buf()])
+        }
+    }
+}
+
+pub fn list_files(path: &Path) -> Result<nitrofs::FileSystem> {
+    let mut f = File::open(path)?;
+    let (header, _) = read_header(&mut f)?;
+
+    nitrofs::read_filesystem(&mut f, &header)
+}
+
+pub fn extract_files(path: &Path, output: &Path) -> Result<nitrofs::FileSystem> {
+    let mut f = File::open(path)?;
+    let (header, _) = read_header(&mut f)?;
+
+    nitrofs::extract(&mut f, &header, output)
+}
+
+// Builds "icon.003.png" from "icon.png"
+fn numbered_path(path: &Path, number: usize) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".{:03}", number));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+
+    path.with_file_name(name)
+}
+
+fn rom_from_header(
+    header: RomHeader,
+    raw_header: &[u8],
+    titles: BTreeMap<Language, String>,
+    hashes: RomHashes,
+) -> Rom {
+    let header_crc = crc16(&raw_header[..HEADER_CRC_OFFSET]);
+
+    Rom {
+        software_title: header.game_title.to_string(),
+        game_code: header.game_code.to_string(),
+        maker_code: header.maker_code.to_string(),
+        supported_devices: header.supported_devices(),
+        rom_version: header.rom_version,
+        card_size: header.card_size(),
+        file_name_table: Section {
+            offset: header.fnt_offset,
+            size: header.fnt_size,
+        },
+        file_allocation_table: Section {
+            offset: header.fat_offset,
+            size: header.fat_size,
+        },
+        arm9_overlay_table: Section {
+            offset: header.arm9_overlay_offset,
+            size: header.arm9_overlay_size,
+        },
+        arm7_overlay_table: Section {
+            offset: header.arm7_overlay_offset,
+            size: header.arm7_overlay_size,
+        },
+        icon_banner_offset: header.icon_banner_offset,
+        secure_area_crc: header.secure_area_crc,
+        total_used_rom_size: header.total_used_rom_size,
+        header_size: header.header_size,
+        logo_crc: header.logo_crc(),
+        header_crc: Crc {
+            declared: header.header_crc,
+            calculated: header_crc,
+            valid: header.header_crc == header_crc,
+        },
+        arm9: header.arm9,
+        arm7: header.arm7,
+        titles,
+        hashes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The logo bitmap every cartridge carries at 0xC0
+    const LOGO: [u8; 156] = [
+        0x24, 0xFF, 0xAE, 0x51, 0x69, 0x9A, 0xA2, 0x21, 0x3D, 0x84, 0x82, 0x0A, 0x84, 0xE4, 0x09,
+        0xAD, 0x11, 0x24, 0x8B, 0x98, 0xC0, 0x81, 0x7F, 0x21, 0xA3, 0x52, 0xBE, 0x19, 0x93, 0x09,
+        0xCE, 0x20, 0x10, 0x46, 0x4A, 0x4A, 0xF8, 0x27, 0x31, 0xEC, 0x58, 0xC7, 0xE8, 0x33, 0x82,
+        0xE3, 0xCE, 0xBF, 0x85, 0xF4, 0xDF, 0x94, 0xCE, 0x4B, 0x09, 0xC1, 0x94, 0x56, 0x8A, 0xC0,
+        0x13, 0x72, 0xA7, 0xFC, 0x9F, 0x84, 0x4D, 0x73, 0xA3, 0xCA, 0x9A, 0x61, 0x58, 0x97, 0xA3,
+        0x27, 0xFC, 0x03, 0x98, 0x76, 0x23, 0x1D, 0xC7, 0x61, 0x03, 0x04, 0xAE, 0x56, 0xBF, 0x38,
+        0x84, 0x00, 0x40, 0xA7, 0x0E, 0xFD, 0xFF, 0x52, 0xFE, 0x03, 0x6F, 0x95, 0x30, 0xF1, 0x97,
+        0xFB, 0xC0, 0x85, 0x60, 0xD6, 0x80, 0x25, 0xA9, 0x63, 0xBE, 0x03, 0x01, 0x4E, 0x38, 0xE2,
+        0xF9, 0xA2, 0x34, 0xFF, 0xBB, 0x3E, 0x03, 0x44, 0x78, 0x00, 0x90, 0xCB, 0x88, 0x11, 0x3A,
+        0x94, 0x65, 0xC0, 0x7C, 0x63, 0x87, 0xF0, 0x3C, 0xAF, 0xD6, 0x25, 0xE4, 0x8B, 0x38, 0x0A,
+        0xAC, 0x72, 0x21, 0xD4, 0xF8, 0x07,
+    ];
+
+    // Just a header, with both CRCs filled in
+    fn cartridge() -> Vec<u8> {
+        let mut rom = vec![0; HEADER_SIZE];
+        rom[..12].copy_from_slice(b"TEST TITLE\0\0");
+        rom[0x0C..0x10].copy_from_slice(b"ATSE");
+        rom[0x10..0x12].copy_from_slice(b"01");
+        rom[0x14] = 7;
+        rom[0xC0..0x15C].copy_from_slice(&LOGO);
+        rom[0x15C..0x15E].copy_from_slice(&LOGO_CRC.to_le_bytes());
+        let header_crc = crc16(&rom[..HEADER_CRC_OFFSET]);
+        rom[0x15E..0x160].copy_from_slice(&header_crc.to_le_bytes());
+        rom
+    }
+
+    fn read(rom: &[u8]) -> Rom {
+        rom_from_reader(&mut Cursor::new(rom), rom.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn calculates_crc16() {
+        // The standard check value for CRC-16/MODBUS
+        assert_eq!(crc16(b"123456789"), 0x4B37);
+        assert_eq!(crc16(&LOGO), LOGO_CRC);
+    }
+
+    #[test]
+    fn validates_crcs() {
+        let data = cartridge();
+        assert_eq!(probe(&mut Cursor::new(&data)).unwrap(), 95);
+
+        let rom = read(&data);
+        assert!(rom.logo_crc.valid);
+        assert!(rom.header_crc.valid);
+        assert_eq!(rom.software_title, "TEST TITLE");
+        assert_eq!(rom.game_code, "ATSE");
+        assert_eq!(rom.card_size, 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn catches_a_changed_header() {
+        let mut data = cartridge();
+        data[0] ^= 0x01;
+
+        let rom = read(&data);
+        assert!(rom.logo_crc.valid);
+        assert!(!rom.header_crc.valid);
+        assert_eq!(
+            rom.header_crc.declared,
+            crc16(&cartridge()[..HEADER_CRC_OFFSET])
+        );
+    }
+
+    #[test]
+    fn catches_a_changed_logo() {
+        let mut data = cartridge();
+        data[0x100] ^= 0x01;
+
+        let rom = read(&data);
+        assert!(!rom.logo_crc.valid);
+        assert_eq!(rom.logo_crc.declared, LOGO_CRC);
+        assert_ne!(rom.logo_crc.calculated, LOGO_CRC);
+        // The logo is covered by the header CRC as well
+        assert!(!rom.header_crc.valid);
+
+        // A matching declared CRC doesn't make up for a logo that isn't Nintendo's
+        let crc = crc16(&data[0xC0..0x15C]);
+        data[0x15C..0x15E].copy_from_slice(&crc.to_le_bytes());
+        assert!(!read(&data).logo_crc.valid);
+    }
+}
@@ -0,0 +1,473 @@
+use super::{banner, RomHeader, Section};
+use anyhow::{bail, Context, Result};
+use binread::{io::Cursor, BinRead};
+use log::debug;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Directory IDs in the FNT are offset by this, so they can't be confused with file IDs.
+const ROOT_DIRECTORY_ID: u16 = 0xF000;
+
+// Some SDKs append a footer after the ARM9 binary that isn't counted in its size.
+// It's needed to rebuild the ROM, so it's kept with the binary when extracting.
+pub const ARM9_FOOTER_MAGIC: u32 = 0xDEC0_0621;
+pub const ARM9_FOOTER_SIZE: u32 = 12;
+
+// Unused space in a ROM is filled with this.
+pub const PADDING: u8 = 0xFF;
+
+// Names of the extracted pieces, relative to the output directory.
+pub const HEADER_FILE: &str = "header.bin";
+pub const ARM9_FILE: &str = "arm9.bin";
+pub const ARM7_FILE: &str = "arm7.bin";
+pub const ARM9_OVERLAY_TABLE_FILE: &str = "y9.bin";
+pub const ARM7_OVERLAY_TABLE_FILE: &str = "y7.bin";
+pub const BANNER_FILE: &str = "banner.bin";
+pub const TRAILER_FILE: &str = "trailer.bin";
+pub const FNT_FILE: &str = "fnt.bin";
+pub const ARM9_OVERLAY_DIR: &str = "overlay9";
+pub const ARM7_OVERLAY_DIR: &str = "overlay7";
+pub const DATA_DIR: &str = "data";
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+#[allow(dead_code)]
+struct DirectoryEntry {
+    // Offset of this directory's listing, relative to the start of the FNT.
+    subtable_offset: u32,
+    first_file_id: u16,
+    // For the root directory, this is the total number of directories instead.
+    parent_id: u16,
+}
+
+#[derive(BinRead, Serialize, Debug)]
+#[br(little)]
+pub struct Overlay {
+    pub id: u32,
+    pub ram_address: u32,
+    pub ram_size: u32,
+    pub bss_size: u32,
+    pub static_init_start: u32,
+    pub static_init_end: u32,
+    pub file_id: u32,
+    pub flags: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileEntry {
+    pub id: u16,
+    pub path: String,
+    pub offset: u32,
+    pub size: u32,
+}
+
+// What the FNT lists, with each file's ID
+#[derive(Debug)]
+pub struct Listing {
+    pub directories: Vec<String>,
+    pub files: Vec<(u16, String)>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct OverlayEntry {
+    #[serde(flatten)]
+    pub overlay: Overlay,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileSystem {
+    pub arm9: Section,
+    pub arm7: Section,
+    pub arm9_overlays: Vec<OverlayEntry>,
+    pub arm7_overlays: Vec<OverlayEntry>,
+    pub directories: Vec<String>,
+    pub files: Vec<FileEntry>,
+}
+
+pub fn read_filesystem(file: &mut File, header: &RomHeader) -> Result<FileSystem> {
+    let fat = read_fat(file, header)?;
+    let fnt =
+        read_section(file, header.fnt_offset, header.fnt_size).context("Failed to read FNT")?;
+
+    let listing = read_fnt(&fnt)?;
+
+    let mut files = Vec::new();
+    for (id, path) in listing.files {
+        let (offset, size) = fat_entry(&fat, id as u32)?;
+        files.push(FileEntry {
+            id,
+            path,
+            offset,
+            size,
+        });
+    }
+
+    let arm9_overlays = read_overlays(
+        file,
+        header.arm9_overlay_offset,
+        header.arm9_overlay_size,
+        &fat,
+    )?;
+    let arm7_overlays = read_overlays(
+        file,
+        header.arm7_overlay_offset,
+        header.arm7_overlay_size,
+        &fat,
+    )?;
+
+    Ok(FileSystem {
+        arm9: Section {
+            offset: header.arm9.rom_offset,
+            size: arm9_size_with_footer(file, header)?,
+        },
+        arm7: Section {
+            offset: header.arm7.rom_offset,
+            size: header.arm7.size,
+        },
+        arm9_overlays,
+        arm7_overlays,
+        directories: listing.directories,
+        files,
+    })
+}
+
+fn read_section(file: &mut File, offset: u32, size: u32) -> Result<Vec<u8>> {
+    // Sizes come from the header, so check them before allocating anything.
+    if offset as u64 + size as u64 > file.metadata()?.len() {
+        bail!(
+            "Section at {:#x} of {:#x} bytes is past the end of the ROM",
+            offset,
+            size
+        );
+    }
+
+    let mut buffer = vec![0; size as usize];
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.read_exact(&mut buffer)?;
+
+    Ok(buffer)
+}
+
+// The FAT is a list of start and end offsets, indexed by file ID.
+fn read_fat(file: &mut File, header: &RomHeader) -> Result<Vec<(u32, u32)>> {
+    let fat =
+        read_section(file, header.fat_offset, header.fat_size).context("Failed to read FAT")?;
+
+    Ok(fat
+        .chunks_exact(8)
+        .map(|c| {
+            (
+                u32::from_le_bytes([c[0], c[1], c[2], c[3]]),
+                u32::from_le_bytes([c[4], c[5], c[6], c[7]]),
+            )
+        })
+        .collect())
+}
+
+fn fat_entry(fat: &[(u32, u32)], id: u32) -> Result<(u32, u32)> {
+    match fat.get(id as usize) {
+        Some(&(start, end)) if end >= start => Ok((start, end - start)),
+        Some(entry) => bail!("FAT entry {} is invalid: {:?}", id, entry),
+        None => bail!("File ID {} is not in the FAT", id),
+    }
+}
+
+fn read_overlays(
+    file: &mut File,
+    offset: u32,
+    size: u32,
+    fat: &[(u32, u32)],
+) -> Result<Vec<OverlayEntry>> {
+    let table = read_section(file, offset, size).context("Failed to read overlay table")?;
+    let mut cursor = Cursor::new(&table);
+    let mut overlays = Vec::new();
+
+    for _ in 0..(size / 32) {
+        let overlay = Overlay::read(&mut cursor)?;
+        let (offset, size) = fat_entry(fat, overlay.file_id)?;
+        overlays.push(OverlayEntry {
+            overlay,
+            offset,
+            size,
+        });
+    }
+
+    Ok(overlays)
+}
+
+// Lists the directories and files in the FNT, with the ID of each file, in the order
+// they're listed.
+pub fn read_fnt(fnt: &[u8]) -> Result<Listing> {
+    let mut directories = Vec::new();
+    let mut files = Vec::new();
+    walk_directory(fnt, ROOT_DIRECTORY_ID, "", &mut directories, &mut files, 0)?;
+
+    Ok(Listing { directories, files })
+}
+
+// Walks a directory's listing in the FNT, collecting the paths of everything in it.
+//
+// Each listing is a series of entries that start with a byte holding the length of the
+// name, with the high bit set for subdirectories. Subdirectories are followed by their ID.
+// A zero byte ends the listing. Files get consecutive IDs in the order they're listed.
+fn walk_directory(
+    fnt: &[u8],
+    directory_id: u16,
+    path: &str,
+    directories: &mut Vec<String>,
+    files: &mut Vec<(u16, String)>,
+    depth: usize,
+) -> Result<()> {
+    // Deeper than this means the FNT refers to itself somewhere.
+    const MAX_DEPTH: usize = 64;
+
+    if depth > MAX_DEPTH {
+        bail!("Directory tree in the FNT is too deep; it may be corrupt");
+    }
+
+    let index = (directory_id - ROOT_DIRECTORY_ID) as usize;
+    let entry_start = index * 8;
+    if entry_start + 8 > fnt.len() {
+        bail!("Directory {:#06x} is not in the FNT", directory_id);
+    }
+
+    let entry = DirectoryEntry::read(&mut Cursor::new(&fnt[entry_start..entry_start + 8]))?;
+    debug!("Reading directory {:?} {:?}", path, entry);
+
+    let mut pos = entry.subtable_offset as usize;
+    let mut file_id = entry.first_file_id;
+
+    loop {
+        let type_len = *fnt.get(pos).context("FNT listing is truncated")?;
+        pos += 1;
+
+        if type_len == 0x00 {
+            break;
+        }
+
+        let name_len = (type_len & 0x7F) as usize;
+        let name = fnt
+            .get(pos..pos + name_len)
+            .context("FNT listing is truncated")?;
+        let name = entry_name(name)?;
+        pos += name_len;
+
+        let entry_path = match path {
+            "" => name,
+            parent => format!("{}/{}", parent, name),
+        };
+
+        if type_len & 0x80 != 0 {
+            let id = fnt.get(pos..pos + 2).context("FNT listing is truncated")?;
+            let id = u16::from_le_bytes([id[0], id[1]]);
+            pos += 2;
+
+            if id < ROOT_DIRECTORY_ID {
+                bail!("Invalid directory ID {:#06x} for {:?}", id, entry_path);
+            }
+
+            directories.push(entry_path.clone());
+            walk_directory(fnt, id, &entry_path, directories, files, depth + 1)?;
+        } else {
+            files.push((file_id, entry_path));
+            file_id = file_id
+                .checked_add(1)
+                .context("FNT lists more files than there are IDs")?;
+        }
+    }
+
+    Ok(())
+}
+
+// Names are used as paths on disk when extracting, so make sure they can't escape.
+fn entry_name(bytes: &[u8]) -> Result<String> {
+    let name = String::from_utf8_lossy(bytes).to_string();
+
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        bail!("Invalid name in FNT: {:?}", name);
+    }
+
+    Ok(name)
+}
+
+fn arm9_size_with_footer(file: &mut File, header: &RomHeader) -> Result<u32> {
+    let mut magic = [0; 4];
+    file.seek(SeekFrom::Start(
+        header.arm9.rom_offset as u64 + header.arm9.size as u64,
+    ))?;
+
+    if file.read_exact(&mut magic).is_ok() && u32::from_le_bytes(magic) == ARM9_FOOTER_MAGIC {
+        return header
+            .arm9
+            .size
+            .checked_add(ARM9_FOOTER_SIZE)
+            .context("The ARM9 binary is too large to have a footer");
+    }
+
+    Ok(header.arm9.size)
+}
+
+// Extracts the binaries, overlays, banner and every NitroFS file into `output`.
+//
+// The header is kept along with everything before the ARM9 binary so that the
+// directory can be packed back into a ROM. So is the FNT, which keeps the order of the
+// listings and the file and directory IDs that the rebuilt ROM needs to match.
+pub fn extract(file: &mut File, header: &RomHeader, output: &Path) -> Result<FileSystem> {
+    let fs = read_filesystem(file, header)?;
+
+    std::fs::create_dir_all(output)
+        .with_context(|| format!("Failed to create directory {:?}", output))?;
+
+    copy_section(file, 0, header.header_size, &output.join(HEADER_FILE))?;
+    copy_section(file, fs.arm9.offset, fs.arm9.size, &output.join(ARM9_FILE))?;
+    copy_section(file, fs.arm7.offset, fs.arm7.size, &output.join(ARM7_FILE))?;
+    copy_section(
+        file,
+        header.fnt_offset,
+        header.fnt_size,
+        &output.join(FNT_FILE),
+    )?;
+
+    if header.arm9_overlay_size > 0 {
+        let path = output.join(ARM9_OVERLAY_TABLE_FILE);
+        copy_section(
+            file,
+            header.arm9_overlay_offset,
+            header.arm9_overlay_size,
+            &path,
+        )?;
+        extract_overlays(file, &fs.arm9_overlays, &output.join(ARM9_OVERLAY_DIR))?;
+    }
+
+    if header.arm7_overlay_size > 0 {
+        let path = output.join(ARM7_OVERLAY_TABLE_FILE);
+        copy_section(
+            file,
+            header.arm7_overlay_offset,
+            header.arm7_overlay_size,
+            &path,
+        )?;
+        extract_overlays(file, &fs.arm7_overlays, &output.join(ARM7_OVERLAY_DIR))?;
+    }
+
+    let mut banner_end = 0;
+    if header.icon_banner_offset != 0 {
+        let mut version = [0; 2];
+        file.seek(SeekFrom::Start(header.icon_banner_offset as u64))?;
+        file.read_exact(&mut version)?;
+        let size = banner::banner_size(u16::from_le_bytes(version))?;
+
+        copy_section(
+            file,
+            header.icon_banner_offset,
+            size as u32,
+            &output.join(BANNER_FILE),
+        )?;
+        banner_end = header.icon_banner_offset as u64 + size as u64;
+    }
+
+    // Whatever follows the last section, like the RSA signature on later cartridges, is
+    // kept so it can be put back. The padding out to the card size isn't.
+    let trailer_start = sections_end(header, &fs).max(banner_end);
+    let trailer_size = trailer_size(file, trailer_start)?;
+    if trailer_size > 0 {
+        let offset = u32::try_from(trailer_start).context("ROM is too large")?;
+        copy_section(file, offset, trailer_size, &output.join(TRAILER_FILE))?;
+    }
+
+    let data_dir = output.join(DATA_DIR);
+    std::fs::create_dir_all(&data_dir)?;
+
+    for directory in &fs.directories {
+        std::fs::create_dir_all(data_dir.join(directory))?;
+    }
+
+    for entry in &fs.files {
+        copy_section(file, entry.offset, entry.size, &data_dir.join(&entry.path))?;
+    }
+
+    Ok(fs)
+}
+
+// Where the last section listed in the header or the FAT ends.
+fn sections_end(header: &RomHeader, fs: &FileSystem) -> u64 {
+    let end = |offset: u32, size: u32| offset as u64 + size as u64;
+
+    let fixed = [
+        end(fs.arm9.offset, fs.arm9.size),
+        end(fs.arm7.offset, fs.arm7.size),
+        end(header.fnt_offset, header.fnt_size),
+        end(header.fat_offset, header.fat_size),
+        end(header.arm9_overlay_offset, header.arm9_overlay_size),
+        end(header.arm7_overlay_offset, header.arm7_overlay_size),
+    ];
+    let overlays = fs.arm9_overlays.iter().chain(fs.arm7_overlays.iter());
+
+    fixed
+        .into_iter()
+        .chain(overlays.map(|entry| end(entry.offset, entry.size)))
+        .chain(fs.files.iter().map(|entry| end(entry.offset, entry.size)))
+        .max()
+        .unwrap_or(0)
+}
+
+// How much of the ROM after `start` is left once the padding at the end is dropped.
+fn trailer_size(file: &mut File, start: u64) -> Result<u32> {
+    let mut end = file.metadata()?.len();
+    let mut buffer = vec![0; 64 * 1024];
+
+    while end > start {
+        let len = (end - start).min(buffer.len() as u64) as usize;
+        file.seek(SeekFrom::Start(end - len as u64))?;
+        file.read_exact(&mut buffer[..len])?;
+
+        match buffer[..len].iter().rposition(|&b| b != PADDING) {
+            Some(last) => {
+                end -= (len - last - 1) as u64;
+                break;
+            }
+            None => end -= len as u64,
+        }
+    }
+
+    u32::try_from(end.saturating_sub(start)).context("ROM is too large")
+}
+
+pub fn overlay_file_name(id: u32) -> String {
+    format!("overlay_{:04}.bin", id)
+}
+
+fn extract_overlays(file: &mut File, overlays: &[OverlayEntry], dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    for entry in overlays {
+        let path = dir.join(overlay_file_name(entry.overlay.id));
+        copy_section(file, entry.offset, entry.size, &path)?;
+    }
+
+    Ok(())
+}
+
+fn copy_section(file: &mut File, offset: u32, size: u32, dest: &Path) -> Result<()> {
+    debug!("Extracting {} bytes at {:#x} to {:?}", size, offset, dest);
+
+    file.seek(SeekFrom::Start(offset as u64))?;
+    let mut out =
+        BufWriter::new(File::create(dest).with_context(|| format!("Failed to create {:?}", dest))?);
+    let copied = std::io::copy(&mut file.take(size as u64), &mut out)?;
+
+    if copied != size as u64 {
+        bail!(
+            "ROM is truncated; expected {} bytes at {:#x} for {:?}",
+            size,
+            offset,
+            dest
+        );
+    }
+
+    Ok(())
+}
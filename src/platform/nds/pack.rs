@@ -0,0 +1,720 @@
+use super::nitrofs::{
+    overlay_file_name, ARM7_FILE, ARM7_OVERLAY_DIR, ARM7_OVERLAY_TABLE_FILE, ARM9_FILE,
+    ARM9_FOOTER_MAGIC, ARM9_FOOTER_SIZE, ARM9_OVERLAY_DIR, ARM9_OVERLAY_TABLE_FILE, BANNER_FILE,
+    DATA_DIR, FNT_FILE, HEADER_FILE, PADDING, TRAILER_FILE,
+};
+use super::{banner, crc16, HEADER_CRC_OFFSET, HEADER_SIZE};
+use anyhow::{bail, Context, Result};
+use log::debug;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Everything in the ROM starts on a 512 byte boundary, padded with 0xFF.
+const ALIGNMENT: u64 = 0x200;
+
+// NitroFS directory IDs start here, which leaves room for 4096 directories.
+const MAX_DIRECTORIES: usize = 0x1000;
+
+// Offsets of the header fields that depend on the layout.
+const CARD_SIZE_OFFSET: usize = 0x14;
+const ARM9_OFFSET: usize = 0x20;
+const ARM9_SIZE_OFFSET: usize = 0x2C;
+const ARM7_OFFSET: usize = 0x30;
+const ARM7_SIZE_OFFSET: usize = 0x3C;
+const FNT_OFFSET: usize = 0x40;
+const FAT_OFFSET: usize = 0x48;
+const ARM9_OVERLAY_OFFSET: usize = 0x50;
+const ARM7_OVERLAY_OFFSET: usize = 0x58;
+const BANNER_OFFSET: usize = 0x68;
+const TOTAL_USED_ROM_SIZE_OFFSET: usize = 0x80;
+
+#[derive(Debug)]
+pub struct PackSummary {
+    pub files: usize,
+    pub overlays: usize,
+    pub total_used_rom_size: u32,
+}
+
+// A directory in the NitroFS being packed. Directories are numbered depth-first
+// from the root, which is how they're stored in the FNT.
+struct Directory {
+    path: PathBuf,
+    parent: usize,
+    files: Vec<String>,
+    subdirectories: Vec<(String, usize)>,
+}
+
+// The FNT to write, and the NitroFS files with their IDs, in ID order.
+struct Fnt {
+    bytes: Vec<u8>,
+    files: Vec<(u16, PathBuf)>,
+}
+
+// Tracks the write position so everything can be laid out as it's written.
+struct RomWriter {
+    out: BufWriter<File>,
+    pos: u64,
+}
+
+impl RomWriter {
+    fn write(&mut self, bytes: &[u8]) -> Result<u32> {
+        let start = self.pos;
+        self.out.write_all(bytes)?;
+        self.pos += bytes.len() as u64;
+
+        to_u32(start)
+    }
+
+    fn write_file(&mut self, path: &Path) -> Result<(u32, u32)> {
+        let mut f = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let start = self.pos;
+        self.pos += std::io::copy(&mut f, &mut self.out)?;
+
+        Ok((to_u32(start)?, to_u32(self.pos)?))
+    }
+
+    fn pad_to(&mut self, pos: u64) -> Result<()> {
+        while self.pos < pos {
+            let len = (pos - self.pos).min(8192) as usize;
+            self.write(&vec![PADDING; len])?;
+        }
+
+        Ok(())
+    }
+
+    fn align(&mut self) -> Result<()> {
+        self.pad_to(align(self.pos))
+    }
+
+    fn overwrite(&mut self, pos: u64, bytes: &[u8]) -> Result<()> {
+        self.out.seek(SeekFrom::Start(pos))?;
+        self.out.write_all(bytes)?;
+        self.out.seek(SeekFrom::Start(self.pos))?;
+
+        Ok(())
+    }
+}
+
+fn align(pos: u64) -> u64 {
+    pos.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+fn to_u32(pos: u64) -> Result<u32> {
+    u32::try_from(pos).context("ROM is too large")
+}
+
+// Rebuilds a ROM from a directory created by `extract`.
+//
+// Sections are laid out the same way Nintendo's tools do it: ARM9 and its overlays, ARM7
+// and its overlays, FNT, FAT, banner, the NitroFS files in ID order, and then whatever
+// `extract` found after the last file, such as an RSA signature. The FNT saved by
+// `extract` is reused, so every file and directory keeps its ID and listings keep their
+// order. An unmodified ROM that was built that way comes back out identical.
+//
+// Without a saved FNT, a new one is built with listings sorted by name and files before
+// subdirectories.
+//
+// Unless `trim` is set, the ROM is padded out to the card capacity like a full dump.
+pub fn pack(input: &Path, output: &Path, trim: bool) -> Result<PackSummary> {
+    let mut header = std::fs::read(input.join(HEADER_FILE))
+        .with_context(|| format!("Failed to read {}", HEADER_FILE))?;
+
+    if header.len() < HEADER_SIZE {
+        bail!("{} is too short to be a header", HEADER_FILE);
+    }
+
+    // A non-zero unit code means there's a DSi header with its own binaries to lay out.
+    if header[0x12] != 0 {
+        bail!("Packing DSi-enhanced or DSi-exclusive ROMs is not supported");
+    }
+
+    let arm9_overlays = read_overlay_table(input, ARM9_OVERLAY_TABLE_FILE)?;
+    let arm7_overlays = read_overlay_table(input, ARM7_OVERLAY_TABLE_FILE)?;
+
+    // NitroFS file IDs pick up after the overlays.
+    let first_file_id = arm9_overlays
+        .iter()
+        .chain(arm7_overlays.iter())
+        .map(|&(_, file_id)| file_id + 1)
+        .max()
+        .unwrap_or(0);
+
+    let data_dir = input.join(DATA_DIR);
+    let mut directories = Vec::new();
+    collect_directory(&data_dir, PathBuf::new(), 0, &mut directories)?;
+
+    if directories.len() > MAX_DIRECTORIES {
+        bail!(
+            "NitroFS can't hold more than {} directories",
+            MAX_DIRECTORIES
+        );
+    }
+
+    let fnt_path = input.join(FNT_FILE);
+    let fnt = match fnt_path.exists() {
+        true => reuse_fnt(&fnt_path, &directories, first_file_id)?,
+        false => build_fnt(&directories, first_file_id)?,
+    };
+    let file_count = fnt
+        .files
+        .last()
+        .map_or(first_file_id, |&(id, _)| id as u32 + 1) as usize;
+    let mut fat = vec![(0u32, 0u32); file_count];
+
+    let out = File::create(output).with_context(|| format!("Failed to create {:?}", output))?;
+    let mut rom = RomWriter {
+        out: BufWriter::new(out),
+        pos: 0,
+    };
+
+    // Everything before the ARM9 binary is copied as-is and patched at the end.
+    rom.write(&header)?;
+    rom.align()?;
+
+    let (arm9_start, arm9_end) = rom.write_file(&input.join(ARM9_FILE))?;
+    let arm9_size = arm9_size_without_footer(&input.join(ARM9_FILE), arm9_end - arm9_start)?;
+    set_u32(&mut header, ARM9_OFFSET, arm9_start);
+    set_u32(&mut header, ARM9_SIZE_OFFSET, arm9_size);
+
+    let (offset, size) = write_overlays(
+        &mut rom,
+        input,
+        ARM9_OVERLAY_TABLE_FILE,
+        ARM9_OVERLAY_DIR,
+        &arm9_overlays,
+        &mut fat,
+    )?;
+    set_u32(&mut header, ARM9_OVERLAY_OFFSET, offset);
+    set_u32(&mut header, ARM9_OVERLAY_OFFSET + 4, size);
+
+    rom.align()?;
+    let (arm7_start, arm7_end) = rom.write_file(&input.join(ARM7_FILE))?;
+    set_u32(&mut header, ARM7_OFFSET, arm7_start);
+    set_u32(&mut header, ARM7_SIZE_OFFSET, arm7_end - arm7_start);
+
+    let (offset, size) = write_overlays(
+        &mut rom,
+        input,
+        ARM7_OVERLAY_TABLE_FILE,
+        ARM7_OVERLAY_DIR,
+        &arm7_overlays,
+        &mut fat,
+    )?;
+    set_u32(&mut header, ARM7_OVERLAY_OFFSET, offset);
+    set_u32(&mut header, ARM7_OVERLAY_OFFSET + 4, size);
+
+    rom.align()?;
+    let fnt_start = rom.write(&fnt.bytes)?;
+    set_u32(&mut header, FNT_OFFSET, fnt_start);
+    set_u32(&mut header, FNT_OFFSET + 4, fnt.bytes.len() as u32);
+
+    // The FAT is filled in once the files have been written.
+    rom.align()?;
+    let fat_start = rom.write(&vec![0; file_count * 8])?;
+    set_u32(&mut header, FAT_OFFSET, fat_start);
+    set_u32(&mut header, FAT_OFFSET + 4, (file_count * 8) as u32);
+
+    let banner_path = input.join(BANNER_FILE);
+    if banner_path.exists() {
+        let mut banner = std::fs::read(&banner_path)?;
+        banner::update_crcs(&mut banner)?;
+
+        rom.align()?;
+        let banner_start = rom.write(&banner)?;
+        set_u32(&mut header, BANNER_OFFSET, banner_start);
+    } else {
+        set_u32(&mut header, BANNER_OFFSET, 0);
+    }
+
+    for (id, path) in &fnt.files {
+        rom.align()?;
+        fat[*id as usize] = rom.write_file(&data_dir.join(path))?;
+    }
+
+    let total_used_rom_size = to_u32(rom.pos)?;
+    set_u32(&mut header, TOTAL_USED_ROM_SIZE_OFFSET, total_used_rom_size);
+
+    let trailer_path = input.join(TRAILER_FILE);
+    if trailer_path.exists() {
+        rom.write_file(&trailer_path)?;
+    }
+
+    // Grow the card if everything no longer fits.
+    while card_capacity(header[CARD_SIZE_OFFSET])? < rom.pos {
+        header[CARD_SIZE_OFFSET] = header[CARD_SIZE_OFFSET]
+            .checked_add(1)
+            .context("ROM is too large for any card")?;
+    }
+
+    if !trim {
+        rom.pad_to(card_capacity(header[CARD_SIZE_OFFSET])?)?;
+    }
+
+    let crc = crc16(&header[..HEADER_CRC_OFFSET]);
+    header[HEADER_CRC_OFFSET..HEADER_CRC_OFFSET + 2].copy_from_slice(&crc.to_le_bytes());
+
+    let fat: Vec<u8> = fat
+        .iter()
+        .flat_map(|&(start, end)| [start.to_le_bytes(), end.to_le_bytes()].concat())
+        .collect();
+    rom.overwrite(fat_start as u64, &fat)?;
+    rom.overwrite(0, &header)?;
+    rom.out.flush()?;
+
+    Ok(PackSummary {
+        files: fnt.files.len(),
+        overlays: arm9_overlays.len() + arm7_overlays.len(),
+        total_used_rom_size,
+    })
+}
+
+// The header stores the card size as 128 kB shifted left by the code.
+fn card_capacity(code: u8) -> Result<u64> {
+    1u64.checked_shl(17 + code as u32)
+        .with_context(|| format!("Invalid card size {:#04x} in {}", code, HEADER_FILE))
+}
+
+fn set_u32(header: &mut [u8], offset: usize, value: u32) {
+    header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// The header's ARM9 size doesn't include the SDK footer, if there is one.
+fn arm9_size_without_footer(path: &Path, size: u32) -> Result<u32> {
+    if size < ARM9_FOOTER_SIZE {
+        return Ok(size);
+    }
+
+    let arm9 = std::fs::read(path)?;
+    let footer = (size - ARM9_FOOTER_SIZE) as usize;
+    let magic = u32::from_le_bytes([
+        arm9[footer],
+        arm9[footer + 1],
+        arm9[footer + 2],
+        arm9[footer + 3],
+    ]);
+
+    if magic == ARM9_FOOTER_MAGIC {
+        return Ok(size - ARM9_FOOTER_SIZE);
+    }
+
+    Ok(size)
+}
+
+// Reads the overlay and file IDs from an overlay table, if the ROM has one.
+fn read_overlay_table(input: &Path, name: &str) -> Result<Vec<(u32, u32)>> {
+    let path = input.join(name);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let table = std::fs::read(&path)?;
+    if table.len() % 32 != 0 {
+        bail!("{} is not a valid overlay table", name);
+    }
+
+    let read_u32 = |entry: &[u8], at: usize| {
+        u32::from_le_bytes([entry[at], entry[at + 1], entry[at + 2], entry[at + 3]])
+    };
+
+    Ok(table
+        .chunks_exact(32)
+        .map(|entry| (read_u32(entry, 0), read_u32(entry, 24)))
+        .collect())
+}
+
+// Writes an overlay table followed by its overlays. Returns the table's offset and size.
+fn write_overlays(
+    rom: &mut RomWriter,
+    input: &Path,
+    table_name: &str,
+    dir_name: &str,
+    overlays: &[(u32, u32)],
+    fat: &mut [(u32, u32)],
+) -> Result<(u32, u32)> {
+    if overlays.is_empty() {
+        return Ok((0, 0));
+    }
+
+    rom.align()?;
+    let (table_start, table_end) = rom.write_file(&input.join(table_name))?;
+
+    for &(id, file_id) in overlays {
+        rom.align()?;
+        let path = input.join(dir_name).join(overlay_file_name(id));
+        fat[file_id as usize] = rom.write_file(&path)?;
+    }
+
+    Ok((table_start, table_end - table_start))
+}
+
+// Collects the directory tree depth-first, sorting entries by name.
+fn collect_directory(
+    root: &Path,
+    path: PathBuf,
+    parent: usize,
+    directories: &mut Vec<Directory>,
+) -> Result<usize> {
+    let index = directories.len();
+    directories.push(Directory {
+        path: path.clone(),
+        parent,
+        files: Vec::new(),
+        subdirectories: Vec::new(),
+    });
+
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(root.join(&path))
+        .with_context(|| format!("Failed to read directory {:?}", root.join(&path)))?
+    {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) if (1..=0x7F).contains(&name.len()) => name,
+            Ok(name) => bail!("File name {:?} must be between 1 and 127 bytes", name),
+            Err(name) => bail!("File name {:?} is not valid UTF-8", name),
+        };
+        entries.push((name, entry.file_type()?.is_dir()));
+    }
+    entries.sort();
+
+    for (name, is_dir) in entries {
+        if is_dir {
+            let child = collect_directory(root, path.join(&name), index, directories)?;
+            directories[index].subdirectories.push((name, child));
+        } else {
+            directories[index].files.push(name);
+        }
+    }
+
+    Ok(index)
+}
+
+// Builds the FNT and the list of NitroFS files with their IDs, in ID order.
+//
+// The FNT starts with an 8 byte entry for each directory pointing at its listing. Every
+// listing holds its files and then its subdirectories, and ends with a zero byte.
+fn build_fnt(directories: &[Directory], first_file_id: u32) -> Result<Fnt> {
+    let mut main_table = Vec::new();
+    let mut listings = Vec::new();
+    let mut file_paths = Vec::new();
+    let mut file_id = first_file_id;
+
+    for (index, directory) in directories.iter().enumerate() {
+        let subtable_offset = (directories.len() * 8 + listings.len()) as u32;
+        // The root stores the number of directories instead of its parent.
+        let parent = match index {
+            0 => directories.len() as u16,
+            _ => 0xF000 | directory.parent as u16,
+        };
+
+        main_table.extend_from_slice(&subtable_offset.to_le_bytes());
+        main_table.extend_from_slice(&file_id_u16(file_id)?.to_le_bytes());
+        main_table.extend_from_slice(&parent.to_le_bytes());
+
+        for name in &directory.files {
+            listings.push(name.len() as u8);
+            listings.extend_from_slice(name.as_bytes());
+            file_paths.push((file_id_u16(file_id)?, directory.path.join(name)));
+            file_id += 1;
+        }
+
+        for (name, child) in &directory.subdirectories {
+            listings.push(0x80 | name.len() as u8);
+            listings.extend_from_slice(name.as_bytes());
+            listings.extend_from_slice(&(0xF000 | *child as u16).to_le_bytes());
+        }
+
+        listings.push(0x00);
+    }
+
+    debug!(
+        "Built FNT for {} directories and {} files",
+        directories.len(),
+        file_paths.len()
+    );
+    main_table.extend_from_slice(&listings);
+
+    Ok(Fnt {
+        bytes: main_table,
+        files: file_paths,
+    })
+}
+
+fn file_id_u16(file_id: u32) -> Result<u16> {
+    u16::try_from(file_id).context("Too many files")
+}
+
+// Reads the FNT saved by `extract` and the list of NitroFS files with their IDs, in ID
+// order. The directory tree has to hold the same files and directories that it lists.
+fn reuse_fnt(path: &Path, directories: &[Directory], first_file_id: u32) -> Result<Fnt> {
+    let fnt = std::fs::read(path).with_context(|| format!("Failed to read {}", FNT_FILE))?;
+    let listing =
+        super::nitrofs::read_fnt(&fnt).with_context(|| format!("Failed to parse {}", FNT_FILE))?;
+    let mut file_paths = listing.files;
+
+    let on_disk: BTreeSet<PathBuf> = directories.iter().skip(1).map(|d| d.path.clone()).collect();
+    let listed: BTreeSet<PathBuf> = listing.directories.iter().map(PathBuf::from).collect();
+    if on_disk != listed {
+        bail!(
+            "The directories in {} don't match {}. Delete it to build a new one.",
+            DATA_DIR,
+            FNT_FILE
+        );
+    }
+
+    let on_disk: BTreeSet<PathBuf> = directories
+        .iter()
+        .flat_map(|d| d.files.iter().map(|name| d.path.join(name)))
+        .collect();
+    let listed: BTreeSet<PathBuf> = file_paths.iter().map(|(_, p)| PathBuf::from(p)).collect();
+    if on_disk != listed || listed.len() != file_paths.len() {
+        bail!(
+            "The files in {} don't match {}. Delete it to build a new one.",
+            DATA_DIR,
+            FNT_FILE
+        );
+    }
+
+    file_paths.sort();
+    for pair in file_paths.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            bail!("{} gives more than one file ID {}", FNT_FILE, pair[0].0);
+        }
+    }
+    if let Some(&(id, _)) = file_paths.first() {
+        if (id as u32) < first_file_id {
+            bail!("{} gives file ID {} to a file and an overlay", FNT_FILE, id);
+        }
+    }
+
+    debug!("Reusing FNT with {} files", file_paths.len());
+
+    Ok(Fnt {
+        bytes: fnt,
+        files: file_paths
+            .into_iter()
+            .map(|(id, p)| (id, PathBuf::from(p)))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::nds::{extract_files, list_files};
+
+    const FILES: [(u16, &str); 5] = [
+        (1, "b.txt"),
+        (2, "a.txt"),
+        (3, "other/e.txt"),
+        (4, "sub/deeper/d.txt"),
+        (5, "sub/c.txt"),
+    ];
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("romboss-pack-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // A name, and the ID for subdirectories
+    type Entry = (&'static str, Option<u16>);
+
+    // An FNT in an order `build_fnt` would never produce: subdirectories before files,
+    // names unsorted, and directories numbered breadth-first.
+    fn fnt() -> Vec<u8> {
+        let listings: [(u16, u16, &[Entry]); 4] = [
+            (
+                1,
+                4,
+                &[
+                    ("sub", Some(0xF001)),
+                    ("b.txt", None),
+                    ("a.txt", None),
+                    ("other", Some(0xF002)),
+                ],
+            ),
+            (5, 0xF000, &[("deeper", Some(0xF003)), ("c.txt", None)]),
+            (3, 0xF000, &[("e.txt", None)]),
+            (4, 0xF001, &[("d.txt", None)]),
+        ];
+
+        let mut main_table = Vec::new();
+        let mut subtables = Vec::new();
+
+        for (first_file_id, parent, entries) in listings {
+            let offset = (listings.len() * 8 + subtables.len()) as u32;
+            main_table.extend_from_slice(&offset.to_le_bytes());
+            main_table.extend_from_slice(&first_file_id.to_le_bytes());
+            main_table.extend_from_slice(&parent.to_le_bytes());
+
+            for (name, directory_id) in entries {
+                match directory_id {
+                    Some(id) => {
+                        subtables.push(0x80 | name.len() as u8);
+                        subtables.extend_from_slice(name.as_bytes());
+                        subtables.extend_from_slice(&id.to_le_bytes());
+                    }
+                    None => {
+                        subtables.push(name.len() as u8);
+                        subtables.extend_from_slice(name.as_bytes());
+                    }
+                }
+            }
+            subtables.push(0);
+        }
+
+        main_table.extend_from_slice(&subtables);
+        main_table
+    }
+
+    // Everything `extract` would write for a ROM with one ARM9 overlay and the files above
+    fn write_input(dir: &Path) {
+        let mut header = vec![0; HEADER_SIZE];
+        header[..12].copy_from_slice(b"PACKTEST\0\0\0\0");
+        header[0x84..0x88].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        std::fs::write(dir.join(HEADER_FILE), header).unwrap();
+
+        std::fs::write(dir.join(ARM9_FILE), vec![0x99; 0x300]).unwrap();
+        std::fs::write(dir.join(ARM7_FILE), vec![0x77; 0x80]).unwrap();
+
+        let mut table = vec![0; 32];
+        table[24..28].copy_from_slice(&0u32.to_le_bytes());
+        std::fs::write(dir.join(ARM9_OVERLAY_TABLE_FILE), table).unwrap();
+        std::fs::create_dir_all(dir.join(ARM9_OVERLAY_DIR)).unwrap();
+        std::fs::write(
+            dir.join(ARM9_OVERLAY_DIR).join(overlay_file_name(0)),
+            b"overlay",
+        )
+        .unwrap();
+
+        std::fs::write(dir.join(FNT_FILE), fnt()).unwrap();
+        for (_, path) in FILES {
+            let path = dir.join(DATA_DIR).join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, path.file_name().unwrap().as_encoded_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn keeps_file_ids_and_listing_order() {
+        let input = TempDir::new("keep");
+        write_input(&input.0);
+        let rom = input.0.join("packed.nds");
+
+        let summary = pack(&input.0, &rom, true).unwrap();
+        assert_eq!(summary.files, FILES.len());
+        assert_eq!(summary.overlays, 1);
+
+        let fs = list_files(&rom).unwrap();
+        let files: Vec<(u16, &str)> = fs.files.iter().map(|f| (f.id, f.path.as_str())).collect();
+        assert_eq!(
+            files,
+            [
+                (4, "sub/deeper/d.txt"),
+                (5, "sub/c.txt"),
+                (1, "b.txt"),
+                (2, "a.txt"),
+                (3, "other/e.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_through_extract() {
+        let input = TempDir::new("input");
+        write_input(&input.0);
+        let first = input.0.join("first.nds");
+        pack(&input.0, &first, false).unwrap();
+
+        let extracted = TempDir::new("extracted");
+        extract_files(&first, &extracted.0).unwrap();
+        assert_eq!(
+            std::fs::read(extracted.0.join(FNT_FILE)).unwrap(),
+            std::fs::read(input.0.join(FNT_FILE)).unwrap()
+        );
+
+        let second = extracted.0.join("second.nds");
+        pack(&extracted.0, &second, false).unwrap();
+
+        let first = std::fs::read(first).unwrap();
+        assert_eq!(first.len(), 128 * 1024);
+        assert!(first == std::fs::read(second).unwrap());
+    }
+
+    #[test]
+    fn keeps_what_follows_the_last_file() {
+        let input = TempDir::new("trailer");
+        write_input(&input.0);
+        let first = input.0.join("first.nds");
+        let summary = pack(&input.0, &first, true).unwrap();
+
+        // A signature after the files, with some zeroes and then padding
+        let mut trailer = vec![0x5A; 0x88];
+        trailer.extend_from_slice(&[0; 0x10]);
+        let mut rom = std::fs::read(&first).unwrap();
+        assert_eq!(rom.len() as u32, summary.total_used_rom_size);
+        rom.extend_from_slice(&trailer);
+        rom.extend_from_slice(&[PADDING; 0x100]);
+        std::fs::write(&first, &rom).unwrap();
+
+        let extracted = TempDir::new("trailer-extracted");
+        extract_files(&first, &extracted.0).unwrap();
+        assert_eq!(
+            std::fs::read(extracted.0.join(TRAILER_FILE)).unwrap(),
+            trailer
+        );
+
+        let second = extracted.0.join("second.nds");
+        pack(&extracted.0, &second, false).unwrap();
+
+        let second = std::fs::read(second).unwrap();
+        assert_eq!(second.len(), 128 * 1024);
+        assert!(second[..rom.len()] == rom[..]);
+        assert!(second[rom.len()..].iter().all(|&b| b == PADDING));
+    }
+
+    #[test]
+    fn rejects_files_missing_from_the_fnt() {
+        let input = TempDir::new("extra");
+        write_input(&input.0);
+        std::fs::write(input.0.join(DATA_DIR).join("new.txt"), b"new").unwrap();
+
+        let error = pack(&input.0, &input.0.join("packed.nds"), true).unwrap_err();
+        assert!(error.to_string().contains(FNT_FILE));
+
+        // Without the saved FNT, a new one is built.
+        std::fs::remove_file(input.0.join(FNT_FILE)).unwrap();
+        let rom = input.0.join("packed.nds");
+        assert_eq!(pack(&input.0, &rom, true).unwrap().files, FILES.len() + 1);
+
+        let fs = list_files(&rom).unwrap();
+        assert_eq!(fs.files[0].id, 1);
+        assert_eq!(fs.files[0].path, "a.txt");
+    }
+
+    #[test]
+    fn rejects_invalid_card_sizes() {
+        let input = TempDir::new("card");
+        write_input(&input.0);
+        let mut header = std::fs::read(input.0.join(HEADER_FILE)).unwrap();
+        header[CARD_SIZE_OFFSET] = 0xFF;
+        std::fs::write(input.0.join(HEADER_FILE), header).unwrap();
+
+        assert!(pack(&input.0, &input.0.join("packed.nds"), false).is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use super::{RomInfo, RomPlatform};
+use crate::archive::{self, Source};
+use crate::hash::{self, Hashes, RomHashes};
+use crate::metadata::{Metadata, SaveType, VideoStandard};
+use crate::Platform;
+use anyhow::{Context, Result};
+use binread::{io::Cursor, io::Read, io::Seek, BinRead};
+use log::debug;
+use phf::phf_map;
+use serde::Serialize;
+use std::fs::File;
+use std::io::SeekFrom;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"NES\x1A";
+
+const HEADER_SIZE: u64 = 16;
+
+// A trainer is 512 bytes of code some copiers loaded into cartridge RAM, stored between
+// the header and the PRG ROM.
+const TRAINER_SIZE: u64 = 512;
+
+const PRG_ROM_UNIT: u64 = 16 * 1024;
+const CHR_ROM_UNIT: u64 = 8 * 1024;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub enum Format {
+    #[serde(rename = "iNES")]
+    INes,
+    #[serde(rename = "NES 2.0")]
+    Nes2,
+}
+
+#[derive(Serialize, Debug)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+#[derive(Serialize, Debug)]
+pub enum ConsoleType {
+    NES,
+    VsSystem,
+    PlayChoice10,
+    // NES 2.0 only. Famiclones, the VT0x chips and so on.
+    Extended(u8),
+}
+
+#[derive(Serialize, Debug)]
+pub enum Timing {
+    NTSC,
+    PAL,
+    // Runs on both
+    Multi,
+    // The Dendy and other famiclones sold in Russia and China
+    Dendy,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rom {
+    pub format: Format,
+    // iNES headers with leftovers from ripping tools in bytes 7 to 15. Those bytes are
+    // ignored, since they'd give the wrong mapper.
+    pub dirty_header: bool,
+    pub prg_rom_size: u64,
+    // Zero when the cartridge has CHR RAM instead
+    pub chr_rom_size: u64,
+    pub mapper: u16,
+    pub submapper: Option<u8>,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub prg_ram_size: u32,
+    pub prg_nvram_size: Option<u32>,
+    pub chr_ram_size: Option<u32>,
+    pub chr_nvram_size: Option<u32>,
+    pub console_type: ConsoleType,
+    pub timing: Timing,
+    pub expansion_device: Option<String>,
+    pub prg_hashes: Hashes,
+    pub chr_hashes: Option<Hashes>,
+    pub hashes: RomHashes,
+}
+
+#[derive(BinRead, Debug)]
+#[br(magic = b"NES\x1A")]
+pub struct RomHeader {
+    prg_rom_size_lsb: u8,
+    chr_rom_size_lsb: u8,
+
+    // Mapper low nibble, four-screen, trainer, battery and mirroring
+    flags_6: u8,
+
+    // Mapper middle nibble, format and console type
+    flags_7: u8,
+
+    // iNES: PRG RAM size in 8 kB units. NES 2.0: mapper high nibble and submapper.
+    mapper_msb: u8,
+
+    // iNES: TV system. NES 2.0: the high nibbles of the PRG and CHR ROM sizes.
+    rom_size_msb: u8,
+
+    // NES 2.0 from here on
+    prg_ram_shift: u8,
+    chr_ram_shift: u8,
+    timing: u8,
+    system_type: u8,
+    misc_roms: u8,
+    expansion_device: u8,
+}
+
+impl RomHeader {
+    pub fn format(&self) -> Format {
+        match self.flags_7 & 0x0C {
+            0x08 => Format::Nes2,
+            _ => Format::INes,
+        }
+    }
+
+    fn is_nes2(&self) -> bool {
+        self.format() == Format::Nes2
+    }
+
+    // Tools like DiskDude! wrote their names over the unused end of iNES headers. Those
+    // headers either have the archaic format bits set, or something in bytes 12 to 15.
+    pub fn is_dirty(&self) -> bool {
+        if self.is_nes2() {
+            return false;
+        }
+
+        let tail = [
+            self.timing,
+            self.system_type,
+            self.misc_roms,
+            self.expansion_device,
+        ];
+
+        self.flags_7 & 0x0C == 0x04 || tail.iter().any(|&b| b != 0)
+    }
+
+    pub fn prg_rom_size(&self) -> Result<u64> {
+        let msb = match self.is_nes2() {
+            true => self.rom_size_msb & 0x0F,
+            false => 0,
+        };
+
+        rom_size(self.prg_rom_size_lsb, msb, PRG_ROM_UNIT)
+    }
+
+    pub fn chr_rom_size(&self) -> Result<u64> {
+        let msb = match self.is_nes2() {
+            true => self.rom_size_msb >> 4,
+            false => 0,
+        };
+
+        rom_size(self.chr_rom_size_lsb, msb, CHR_ROM_UNIT)
+    }
+
+    pub fn mapper(&self) -> u16 {
+        let low = (self.flags_6 >> 4) as u16;
+
+        if self.is_dirty() {
+            return low;
+        }
+
+        let middle = (self.flags_7 & 0xF0) as u16;
+        match self.is_nes2() {
+            true => ((self.mapper_msb & 0x0F) as u16) << 8 | middle | low,
+            false => middle | low,
+        }
+    }
+
+    pub fn submapper(&self) -> Option<u8> {
+        self.is_nes2().then_some(self.mapper_msb >> 4)
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        match self.flags_6 {
+            f if f & 0x08 != 0 => Mirroring::FourScreen,
+            f if f & 0x01 != 0 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.flags_6 & 0x02 != 0
+    }
+
+    pub fn has_trainer(&self) -> bool {
+        self.flags_6 & 0x04 != 0
+    }
+
+    // iNES only gives a size in 8 kB units, where zero still means 8 kB for compatibility.
+    pub fn prg_ram_size(&self) -> u32 {
+        match self.is_nes2() {
+            true => shift_size(self.prg_ram_shift & 0x0F),
+            false if self.is_dirty() => 8 * 1024,
+            false => (self.mapper_msb.max(1) as u32) * 8 * 1024,
+        }
+    }
+
+    pub fn prg_nvram_size(&self) -> Option<u32> {
+        self.is_nes2().then(|| shift_size(self.prg_ram_shift >> 4))
+    }
+
+    pub fn chr_ram_size(&self) -> Option<u32> {
+        self.is_nes2()
+            .then(|| shift_size(self.chr_ram_shift & 0x0F))
+    }
+
+    pub fn chr_nvram_size(&self) -> Option<u32> {
+        self.is_nes2().then(|| shift_size(self.chr_ram_shift >> 4))
+    }
+
+    pub fn console_type(&self) -> ConsoleType {
+        if self.is_dirty() {
+            return ConsoleType::NES;
+        }
+
+        match self.flags_7 & 0x03 {
+            0 => ConsoleType::NES,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::PlayChoice10,
+            _ => ConsoleType::Extended(self.system_type & 0x0F),
+        }
+    }
+
+    // iNES headers rarely set the TV system bit, so NTSC is mostly an assumption there.
+    pub fn timing(&self) -> Timing {
+        let code = match self.is_nes2() {
+            true => self.timing & 0x03,
+            false if self.is_dirty() => 0,
+            false => self.rom_size_msb & 0x01,
+        };
+
+        match code {
+            0 => Timing::NTSC,
+            1 => Timing::PAL,
+            2 => Timing::Multi,
+            _ => Timing::Dendy,
+        }
+    }
+
+    pub fn expansion_device(&self) -> Option<String> {
+        static EXPANSION_DEVICES: phf::Map<u8, &'static str> = phf_map! {
+            0x00u8 => "Unspecified",
+            0x01u8 => "Standard controllers",
+            0x02u8 => "NES Four Score or Satellite",
+            0x03u8 => "Famicom Four Players Adapter",
+            0x04u8 => "Vs. System (1P via $4016)",
+            0x05u8 => "Vs. System (1P via $4017)",
+            0x07u8 => "Vs. Zapper",
+            0x08u8 => "Zapper",
+            0x09u8 => "Two Zappers",
+            0x0Au8 => "Bandai Hyper Shot",
+            0x0Bu8 => "Power Pad side A",
+            0x0Cu8 => "Power Pad side B",
+            0x0Du8 => "Family Trainer side A",
+            0x0Eu8 => "Family Trainer side B",
+            0x0Fu8 => "Arkanoid Vaus (NES)",
+            0x10u8 => "Arkanoid Vaus (Famicom)",
+            0x11u8 => "Two Vaus controllers and Famicom Data Recorder",
+            0x12u8 => "Konami Hyper Shot",
+            0x13u8 => "Coconuts Pachinko controller",
+            0x14u8 => "Exciting Boxing punching bag",
+            0x15u8 => "Jissen Mahjong controller",
+            0x16u8 => "Party Tap",
+            0x17u8 => "Oeka Kids tablet",
+            0x18u8 => "Sunsoft Barcode Battler",
+            0x19u8 => "Miracle Piano keyboard",
+            0x1Au8 => "Pokkun Moguraa",
+            0x1Bu8 => "Top Rider",
+            0x1Cu8 => "Double-Fisted",
+            0x1Du8 => "Famicom 3D System",
+            0x1Eu8 => "Doremikko keyboard",
+            0x1Fu8 => "R.O.B. Gyro Set",
+            0x20u8 => "Famicom Data Recorder",
+            0x21u8 => "ASCII Turbo File",
+            0x22u8 => "IGS Storage Battle Box",
+            0x23u8 => "Family BASIC keyboard and Data Recorder",
+            0x24u8 => "Dongda PEC-586 keyboard",
+            0x25u8 => "Bit Corp. Bit-79 keyboard",
+            0x26u8 => "Subor keyboard",
+            0x27u8 => "Subor keyboard and mouse",
+            0x29u8 => "SNES mouse",
+            0x2Au8 => "Multicart",
+            0x2Bu8 => "Two SNES controllers",
+        };
+
+        if !self.is_nes2() {
+            return None;
+        }
+
+        let code = self.expansion_device & 0x3F;
+        match EXPANSION_DEVICES.get(&code) {
+            Some(desc) => Some(desc.to_string()),
+            None => Some(format!("Unknown {:#x}", code)),
+        }
+    }
+}
+
+// NES 2.0 sizes are normally a count of units. When the high nibble is all ones, the low
+// byte is an exponent and multiplier instead, as 2^E * (MM * 2 + 1) for EEEEEEMM.
+fn rom_size(lsb: u8, msb: u8, unit: u64) -> Result<u64> {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as u64 * 2 + 1;
+
+        return 2u64
+            .checked_pow(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .with_context(|| format!("ROM size {:#04x} is too large to be real", lsb));
+    }
+
+    Ok(((msb as u64) << 8 | lsb as u64) * unit)
+}
+
+// RAM sizes are stored as 64 << shift, where zero means there isn't any.
+fn shift_size(shift: u8) -> u32 {
+    match shift {
+        0 => 0,
+        shift => 64u32 << shift,
+    }
+}
+
+pub struct NES;
+
+impl RomPlatform for NES {
+    fn platform(&self) -> Platform {
+        Platform::NES
+    }
+
+    fn name(&self) -> &'static str {
+        "nes"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["famicom"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["nes"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        Ok(Box::new(rom_from_reader(&mut reader, size)?))
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    // The header doesn't identify the game at all.
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        vec![]
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            rom_size: self.hashes.payload().size,
+            save_type: self.has_battery.then_some(SaveType::SRAM),
+            video_standard: Some(match self.timing {
+                Timing::NTSC => VideoStandard::NTSC,
+                Timing::PAL | Timing::Dendy => VideoStandard::PAL,
+                Timing::Multi => VideoStandard::Multi,
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+// Estimates how likely it is that the file is a NES ROM, from 0 to 100.
+//
+// Cartridges have no header of their own, so this is the iNES header emulators use.
+pub fn probe<R: Read + Seek>(file: &mut R) -> Result<u8> {
+    let mut buffer = [0; 4];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut buffer)?;
+
+    if &buffer == MAGIC {
+        return Ok(95);
+    }
+
+    Ok(0)
+}
+
+pub fn rom_from_file(path: &Path) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size());
+    }
+
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size)
+}
+
+// Reads the ROM from the first `size` bytes of the reader.
+pub fn rom_from_reader<R: Read + Seek>(f: &mut R, size: u64) -> Result<Rom> {
+    let mut buffer = [0; HEADER_SIZE as usize];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_exact(&mut buffer)?;
+
+    debug!("Read header bytes: {:?}", buffer);
+    let header =
+        RomHeader::read(&mut Cursor::new(&buffer)).context("Failed to parse ROM header")?;
+    debug!("Read ROM header: {:?}", header);
+
+    // DATs list the ROM without the header, which is what the headerless hashes are for.
+    f.seek(SeekFrom::Start(0))?;
+    let hashes = hash::hash_reader(f.take(size), HEADER_SIZE)?;
+
+    let prg_start = match header.has_trainer() {
+        true => HEADER_SIZE + TRAINER_SIZE,
+        false => HEADER_SIZE,
+    };
+    let prg_size = header.prg_rom_size()?;
+    let chr_size = header.chr_rom_size()?;
+
+    f.seek(SeekFrom::Start(prg_start))?;
+    let prg_hashes = hash::hash_reader(f.take(prg_size), 0)?.file;
+
+    let chr_hashes = match chr_size {
+        0 => None,
+        _ => {
+            let chr_start = prg_start
+                .checked_add(prg_size)
+                .context("PRG ROM size is too large to be real")?;
+            f.seek(SeekFrom::Start(chr_start))?;
+            Some(hash::hash_reader(f.take(chr_size), 0)?.file)
+        }
+    };
+
+    Ok(rom_from_header(
+        &header, prg_size, chr_size, prg_hashes, chr_hashes, hashes,
+    ))
+}
+
+fn rom_from_header(
+    header: &RomHeader,
+    prg_rom_size: u64,
+    chr_rom_size: u64,
+    prg_hashes: Hashes,
+    chr_hashes: Option<Hashes>,
+    hashes: RomHashes,
+) -> Rom {
+    Rom {
+        format: header.format(),
+        dirty_header: header.is_dirty(),
+        prg_rom_size,
+        chr_rom_size,
+        mapper: header.mapper(),
+        submapper: header.submapper(),
+        mirroring: header.mirroring(),
+        has_battery: header.has_battery(),
+        has_trainer: header.has_trainer(),
+        prg_ram_size: header.prg_ram_size(),
+        prg_nvram_size: header.prg_nvram_size(),
+        chr_ram_size: header.chr_ram_size(),
+        chr_nvram_size: header.chr_nvram_size(),
+        console_type: header.console_type(),
+        timing: header.timing(),
+        expansion_device: header.expansion_device(),
+        prg_hashes,
+        chr_hashes,
+        hashes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_units() {
+        assert_eq!(rom_size(2, 0, PRG_ROM_UNIT).unwrap(), 32 * 1024);
+        assert_eq!(
+            rom_size(0x00, 0x01, CHR_ROM_UNIT).unwrap(),
+            256 * CHR_ROM_UNIT
+        );
+    }
+
+    #[test]
+    fn multiplies_exponents() {
+        // 2^10 * (1 * 2 + 1)
+        assert_eq!(rom_size(10 << 2 | 1, 0x0F, PRG_ROM_UNIT).unwrap(), 3 * 1024);
+    }
+
+    #[test]
+    fn rejects_sizes_that_overflow() {
+        assert!(rom_size(0xFF, 0x0F, PRG_ROM_UNIT).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use super::{RomInfo, RomPlatform};
+use crate::archive::{self, Source};
+use crate::hash::{self, RomHashes};
+use crate::metadata::{Metadata, Region, VideoStandard};
+use crate::Platform;
+use anyhow::{bail, Result};
+use binread::{io::Cursor, io::Read, io::Seek, BinRead};
+use log::debug;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, SeekFrom};
+use std::path::Path;
+
+// The header is normally at 0x7FF0. Games too small for that put it at the end of the
+// ROM instead.
+const HEADER_OFFSETS: [u64; 3] = [0x7FF0, 0x3FF0, 0x1FF0];
+const HEADER_SIZE: usize = 16;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum System {
+    MasterSystem,
+    GameGear,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Checksum {
+    pub declared: u16,
+    pub calculated: u16,
+    pub valid: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rom {
+    pub system: System,
+
+    // Japanese Master System games don't need a header, so many of them don't have one.
+    #[serde(flatten)]
+    pub header: Option<Header>,
+
+    pub hashes: RomHashes,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Header {
+    pub header_offset: u64,
+    pub product_code: String,
+    pub version: u8,
+    pub region: String,
+    pub rom_size: u32,
+    pub checksum: Checksum,
+}
+
+#[derive(BinRead, Debug)]
+#[br(little, magic = b"TMR SEGA")]
+#[allow(dead_code)]
+pub struct RomHeader {
+    #[br(pad_before = 2)]
+    checksum: u16,
+
+    // The last four digits of the product code, in little-endian BCD
+    product_code: u16,
+
+    // High nibble: any digits of the product code past the first four. Low nibble: version.
+    product_code_high_version: u8,
+
+    // High nibble: region. Low nibble: size of the ROM covered by the checksum.
+    region_rom_size: u8,
+}
+
+impl RomHeader {
+    fn region_code(&self) -> u8 {
+        self.region_rom_size >> 4
+    }
+
+    pub fn system(&self) -> Option<System> {
+        match self.region_code() {
+            3 | 4 => Some(System::MasterSystem),
+            5..=7 => Some(System::GameGear),
+            _ => None,
+        }
+    }
+
+    pub fn region(&self) -> String {
+        match self.region_code() {
+            3 => "SMS Japan".to_string(),
+            4 => "SMS Export".to_string(),
+            5 => "GG Japan".to_string(),
+            6 => "GG Export".to_string(),
+            7 => "GG International".to_string(),
+            other => format!("Unknown {:#x}", other),
+        }
+    }
+
+    // The digits are stored as BCD, with anything past four digits in a nibble of its own,
+    // so 0x7012 with a 2 on top is product 27012.
+    pub fn product_code(&self) -> String {
+        let bcd = format!("{:04x}", self.product_code);
+        let high = self.product_code_high_version >> 4;
+
+        match high {
+            0 => bcd,
+            high => format!("{}{}", high, bcd),
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.product_code_high_version & 0x0F
+    }
+
+    pub fn rom_size(&self) -> Option<u32> {
+        let kilobytes = match self.region_rom_size & 0x0F {
+            0xA => 8,
+            0xB => 16,
+            0xC => 32,
+            0xD => 48,
+            0xE => 64,
+            0xF => 128,
+            0x0 => 256,
+            0x1 => 512,
+            0x2 => 1024,
+            _ => return None,
+        };
+
+        Some(kilobytes * 1024)
+    }
+}
+
+pub struct MasterSystem;
+
+impl RomPlatform for MasterSystem {
+    fn platform(&self) -> Platform {
+        Platform::MasterSystem
+    }
+
+    fn name(&self) -> &'static str {
+        "sms"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["mastersystem"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["sms"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader, System::MasterSystem)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        let rom = rom_from_reader(&mut reader, size, System::MasterSystem)?;
+        Ok(Box::new(rom))
+    }
+}
+
+pub struct GameGear;
+
+impl RomPlatform for GameGear {
+    fn platform(&self) -> Platform {
+        Platform::GameGear
+    }
+
+    fn name(&self) -> &'static str {
+        "gg"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["gamegear"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["gg"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, _size: u64) -> Result<u8> {
+        probe(&mut reader, System::GameGear)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        let rom = rom_from_reader(&mut reader, size, System::GameGear)?;
+        Ok(Box::new(rom))
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        match &self.header {
+            Some(header) => vec![("product_code", header.product_code.to_string())],
+            None => vec![],
+        }
+    }
+
+    // The region code only tells Japan apart from everywhere else.
+    fn metadata(&self) -> Metadata {
+        let regions = match self.header.as_ref().map(|h| h.region.as_str()) {
+            Some("SMS Japan" | "GG Japan") => vec![Region::Japan],
+            Some("GG International") => vec![Region::World],
+            _ => vec![],
+        };
+
+        Metadata {
+            video_standard: match self.system {
+                System::MasterSystem => VideoStandard::for_regions(&regions),
+                System::GameGear => None,
+            },
+            regions,
+            product_code: self.header.as_ref().map(|h| h.product_code.to_string()),
+            revision: self.header.as_ref().map(|h| h.version.to_string()),
+            rom_size: self.hashes.payload().size,
+            ..Default::default()
+        }
+    }
+}
+
+// Estimates how likely it is that the file is a ROM for the system, from 0 to 100.
+//
+// Everything released outside Japan needs the header, since the export BIOS checks it.
+// The region code tells the two systems apart. When it's unknown, the header alone isn't
+// enough to go on, so the file extension gets to decide.
+pub fn probe<R: Read + Seek>(file: &mut R, system: System) -> Result<u8> {
+    let (_, header) = match find_header(file)? {
+        Some(found) => found,
+        None => return Ok(0),
+    };
+
+    match header.system() {
+        Some(found) if found == system => Ok(95),
+        Some(_) => Ok(0),
+        None => Ok(40),
+    }
+}
+
+fn find_header<R: Read + Seek>(file: &mut R) -> Result<Option<(u64, RomHeader)>> {
+    let mut buffer = [0; HEADER_SIZE];
+
+    for offset in HEADER_OFFSETS {
+        file.seek(SeekFrom::Start(offset))?;
+        if file.read_exact(&mut buffer).is_err() {
+            continue;
+        }
+
+        if let Ok(header) = RomHeader::read(&mut Cursor::new(&buffer)) {
+            debug!("Found header at {:#x}: {:?}", offset, header);
+            return Ok(Some((offset, header)));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn rom_from_file(path: &Path, system: System) -> Result<Rom> {
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size(), system);
+    }
+
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size, system)
+}
+
+// Reads the ROM from the first `size` bytes of the reader. The header decides the system
+// when its region code is known, and `system` is used otherwise. Without a header, there's
+// nothing to read but the hashes.
+pub fn rom_from_reader<R: Read + Seek>(f: &mut R, size: u64, system: System) -> Result<Rom> {
+    let found = find_header(f)?;
+    let header = match &found {
+        Some((offset, header)) => Some(header_from_raw(f, header, *offset)?),
+        None => {
+            debug!("No \"TMR SEGA\" header found");
+            None
+        }
+    };
+
+    f.seek(SeekFrom::Start(0))?;
+    let hashes = hash::hash_reader(f.take(size), 0)?;
+
+    Ok(Rom {
+        system: found
+            .and_then(|(_, header)| header.system())
+            .unwrap_or(system),
+        header,
+        hashes,
+    })
+}
+
+// Sums the bytes of the ROM, up to the size given in the header. The header itself is
+// skipped, so anything bigger than 32 kB is summed in two parts either side of it.
+pub fn calculate_checksum<R: Read + Seek>(file: &mut R, rom_size: u64) -> Result<u16> {
+    let ranges = match rom_size {
+        0..=0x8000 => vec![(0, rom_size.saturating_sub(HEADER_SIZE as u64))],
+        _ => vec![(0, 0x7FF0), (0x8000, rom_size - 0x8000)],
+    };
+
+    let mut sum: u16 = 0;
+    let mut buffer = [0; 8192];
+
+    for (start, len) in ranges {
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new(file.take(len));
+
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            for &byte in &buffer[..read] {
+                sum = sum.wrapping_add(byte as u16);
+            }
+        }
+    }
+
+    Ok(sum)
+}
+
+fn header_from_raw<R: Read + Seek>(f: &mut R, header: &RomHeader, offset: u64) -> Result<Header> {
+    let rom_size = match header.rom_size() {
+        Some(rom_size) => rom_size,
+        None => bail!("Unrecognised ROM size code in header: {:?}", header),
+    };
+    let calculated_checksum = calculate_checksum(f, rom_size as u64)?;
+
+    Ok(Header {
+        header_offset: offset,
+        product_code: header.product_code(),
+        version: header.version(),
+        region: header.region(),
+        rom_size,
+        checksum: Checksum {
+            declared: header.checksum,
+            calculated: calculated_checksum,
+            valid: header.checksum == calculated_checksum,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detect_platform;
+
+    // A 32 kB ROM with a valid header and checksum
+    fn rom(region_code: u8) -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..0x8000u32).map(|i| (i * 7) as u8).collect();
+        let header = &mut rom[0x7FF0..];
+        header[..8].copy_from_slice(b"TMR SEGA");
+        header[0xC..0xE].copy_from_slice(&0x7012u16.to_le_bytes());
+        header[0xE] = 0x21;
+        header[0xF] = region_code << 4 | 0xC;
+
+        let checksum = calculate_checksum(&mut Cursor::new(&rom), 0x8000).unwrap();
+        rom[0x7FFA..0x7FFC].copy_from_slice(&checksum.to_le_bytes());
+
+        rom
+    }
+
+    #[test]
+    fn reads_header() {
+        let rom = rom(4);
+        let rom = rom_from_reader(&mut Cursor::new(&rom), 0x8000, System::GameGear).unwrap();
+
+        assert_eq!(rom.system, System::MasterSystem);
+        let header = rom.header.unwrap();
+        assert_eq!(header.product_code, "27012");
+        assert_eq!(header.version, 1);
+        assert_eq!(header.region, "SMS Export");
+        assert!(header.checksum.valid);
+    }
+
+    #[test]
+    fn hashes_roms_without_a_header() {
+        let data: Vec<u8> = (0..0x8000u32).map(|i| (i * 7) as u8).collect();
+        let rom = rom_from_reader(&mut Cursor::new(&data), 0x8000, System::MasterSystem).unwrap();
+
+        assert_eq!(rom.system, System::MasterSystem);
+        assert!(rom.header.is_none());
+        assert_eq!(rom.hashes.file.size, 0x8000);
+        assert!(rom.header_values().is_empty());
+        assert_eq!(rom.metadata().product_code, None);
+
+        let json = serde_json::to_value(&rom).unwrap();
+        assert!(json.get("product_code").is_none());
+        assert_eq!(json["system"], "MasterSystem");
+    }
+
+    #[test]
+    fn region_code_picks_the_system() {
+        let rom = rom(6);
+        let detection = detect_platform(&mut Cursor::new(&rom), 0x8000, Path::new("game.sms"))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(detection.platform, Platform::GameGear);
+    }
+
+    #[test]
+    fn extension_decides_unknown_region() {
+        let rom = rom(0);
+
+        for (name, platform) in [
+            ("game.gg", Platform::GameGear),
+            ("game.sms", Platform::MasterSystem),
+        ] {
+            let detection = detect_platform(&mut Cursor::new(&rom), 0x8000, Path::new(name))
+                .unwrap()
+                .unwrap();
+            assert_eq!(detection.platform, platform);
+        }
+
+        let read = rom_from_reader(&mut Cursor::new(&rom), 0x8000, System::GameGear).unwrap();
+        assert_eq!(read.system, System::GameGear);
+    }
+}
@@ -1,3 +1,8 @@
+use super::{FixedChecksum, RomInfo, RomPlatform};
+use crate::archive::{self, Source};
+use crate::hash::{self, RomHashes};
+use crate::metadata::{Metadata, Region, SaveType, VideoStandard};
+use crate::Platform;
 use anyhow::bail;
 use anyhow::Result;
 use binread::{io::Cursor, io::Read, io::Seek, BinRead};
@@ -7,28 +12,38 @@ use log::debug;
 use phf::phf_map;
 use serde::Serialize;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{BufReader, SeekFrom, Write};
+use std::path::Path;
 
 #[derive(Serialize, Debug)]
 pub struct Rom {
-    map_mode: String,
-    cartridge_type: String,
-    target_market: String,
-    title: String,
-    has_smc_header: bool,
-    rom_size: StorageSize,
-    sram_size: StorageSize,
+    pub map_mode: String,
+    pub cartridge_type: String,
+    pub target_market: String,
+    pub destination_code: u8,
+    pub title: String,
+    pub maker_code: String,
+    // Only in the extended header of later games
+    pub game_code: Option<String>,
+    pub version: u8,
+    pub has_battery: bool,
+    pub has_smc_header: bool,
+    // Missing when the header's size is too large to be real
+    pub rom_size: Option<StorageSize>,
+    pub sram_size: Option<StorageSize>,
+    pub checksum: Checksum,
+    pub hashes: RomHashes,
 }
 
 #[derive(Serialize, Debug)]
 pub struct StorageSize {
-    bytes: u32,
-    kilobytes: u32,
-    kilobits: u32,
+    pub bytes: u32,
+    pub kilobytes: u32,
+    pub kilobits: u32,
 }
 
 #[derive(BinRead, Debug)]
-#[br(big)]
+#[br(little)]
 #[allow(dead_code)]
 pub struct RomHeader {
     #[br(count = 2)]
@@ -68,7 +83,7 @@ pub struct RomHeader {
 
 // Converts a series of bytes to a string using EUC-JP encoding and stripping trailing spaces.
 fn bytes_to_stripped_string(bytes: &[u8]) -> Result<String> {
-    let s = EUCJPEncoding.decode(&bytes, DecoderTrap::Ignore).unwrap();
+    let s = EUCJPEncoding.decode(bytes, DecoderTrap::Ignore).unwrap();
     Ok(s.trim_end().to_string())
 }
 
@@ -124,12 +139,41 @@ impl RomHeader {
         lookup_description(self.destination_code, &DESTINATION_CODES)
     }
 
-    pub fn rom_size(&self) -> StorageSize {
-        kilobytes_to_storage(2u32.pow(self.rom_size as u32))
+    // Games from 1993 on set the old maker code to 0x33 and use the extended header, which
+    // has a two-character maker code and a game code.
+    fn has_extended_header(&self) -> bool {
+        self.fixed_value_2 == 0x33
+    }
+
+    pub fn maker_code(&self) -> String {
+        match self.has_extended_header() {
+            true => String::from_utf8_lossy(&self.maker_code).trim().to_string(),
+            false => format!("{:02X}", self.fixed_value_2),
+        }
+    }
+
+    pub fn game_code(&self) -> Option<String> {
+        self.has_extended_header()
+            .then(|| String::from_utf8_lossy(&self.game_code).trim().to_string())
+    }
+
+    // The low nibble of the ROM type says which chips are on the board. Only some of those
+    // combinations include a battery.
+    pub fn has_battery(&self) -> bool {
+        matches!(self.rom_type & 0x0F, 0x02 | 0x05 | 0x06)
+    }
+
+    // The checksum and its complement should always add up to 0xFFFF.
+    pub fn complement_checks_out(&self) -> bool {
+        self.checksum ^ self.complement_check == 0xFFFF
+    }
+
+    pub fn rom_size(&self) -> Option<StorageSize> {
+        kilobytes_to_storage(self.rom_size)
     }
 
-    pub fn sram_size(&self) -> StorageSize {
-        kilobytes_to_storage(2u32.pow(self.sram_size as u32))
+    pub fn sram_size(&self) -> Option<StorageSize> {
+        kilobytes_to_storage(self.sram_size)
     }
 }
 
@@ -140,111 +184,459 @@ fn lookup_description(code: u8, map: &phf::Map<u8, &'static str>) -> String {
     }
 }
 
-pub fn rom_from_file(path: &PathBuf) -> Result<Rom> {
-    let metadata = std::fs::metadata(&path)?;
-    let mut offset = 0x00;
-    let mut has_smc_header = false;
+// The largest cartridges (ExHiROM) top out well below this.
+const MAX_ROM_SIZE: u64 = 0x100_0000;
+
+// Offset of the complement check from the start of the header. The checksum follows it.
+const COMPLEMENT_CHECK_OFFSET: u64 = 0x2C;
+
+#[derive(Serialize, Debug)]
+pub struct Checksum {
+    pub declared: u16,
+    pub complement: u16,
+    pub calculated: u16,
+    pub complement_valid: bool,
+    pub valid: bool,
+}
+
+impl Checksum {
+    // Builds the checksum from a parsed header and the byte sum of the whole ROM.
+    //
+    // The sum includes the checksum and complement fields themselves. In a correct ROM those
+    // four bytes always add up to 0x1FE, so swap in that value to get the checksum the ROM
+    // should have no matter what's currently stored there.
+    fn new(header: &RomHeader, rom_sum: u64) -> Checksum {
+        let field_sum: u64 = header
+            .checksum
+            .to_le_bytes()
+            .iter()
+            .chain(header.complement_check.to_le_bytes().iter())
+            .map(|&b| b as u64)
+            .sum();
+        let calculated = (rom_sum.wrapping_sub(field_sum).wrapping_add(0x1FE) & 0xFFFF) as u16;
+
+        Checksum {
+            declared: header.checksum,
+            complement: header.complement_check,
+            calculated,
+            complement_valid: header.complement_checks_out(),
+            valid: header.checksum == calculated,
+        }
+    }
+}
+
+// A header located in the ROM, along with where it was found and its checksum status.
+#[derive(Debug)]
+pub struct FoundHeader {
+    pub header: RomHeader,
+    // Position of the header in the file, including any copier header.
+    pub file_offset: u64,
+    pub checksum: Checksum,
+}
+
+pub struct SuperNintendo;
+
+impl RomPlatform for SuperNintendo {
+    fn platform(&self) -> Platform {
+        Platform::SuperNintendo
+    }
+
+    fn name(&self) -> &'static str {
+        "snes"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["sfc"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["sfc", "smc", "swc"]
+    }
+
+    fn probe(&self, mut reader: &mut dyn Source, size: u64) -> Result<u8> {
+        probe(&mut reader, size)
+    }
+
+    fn rom_from_reader(&self, mut reader: &mut dyn Source, size: u64) -> Result<Box<dyn RomInfo>> {
+        Ok(Box::new(rom_from_reader(&mut reader, size)?))
+    }
+
+    fn fixes_checksums(&self) -> bool {
+        true
+    }
+
+    // The complement is fixed along with the checksum, so both have to have been right.
+    fn fix_checksum(&self, source: &Path, dest: &Path) -> Result<FixedChecksum> {
+        let c = fix_checksum(source, dest)?;
+        Ok(FixedChecksum {
+            declared: c.declared,
+            calculated: c.calculated,
+            valid: c.valid && c.complement_valid,
+        })
+    }
+}
+
+impl RomInfo for Rom {
+    fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    fn header_values(&self) -> Vec<(&'static str, String)> {
+        vec![("title", self.title.to_string())]
+    }
+
+    fn metadata(&self) -> Metadata {
+        let regions: Vec<Region> = destination_region(self.destination_code)
+            .into_iter()
+            .collect();
+
+        Metadata {
+            title: self.title.to_string(),
+            alt_titles: vec![],
+            video_standard: VideoStandard::for_regions(&regions),
+            regions,
+            publisher: Some(self.maker_code.to_string()),
+            product_code: self.game_code.clone(),
+            revision: Some(format!("1.{}", self.version)),
+            release_date: None,
+            rom_size: self.hashes.payload().size,
+            save_type: self.has_battery.then_some(SaveType::SRAM),
+        }
+    }
+}
+
+fn destination_region(code: u8) -> Option<Region> {
+    match code {
+        0x00 => Some(Region::Japan),
+        0x01 | 0x0F => Some(Region::NorthAmerica),
+        0x02..=0x0A => Some(Region::Europe),
+        0x0B => Some(Region::China),
+        0x0C => Some(Region::Asia),
+        0x0D => Some(Region::Korea),
+        0x10 => Some(Region::Brazil),
+        0x11 => Some(Region::Australia),
+        _ => None,
+    }
+}
 
-    match metadata.len() % 1024 {
-        0 => debug!("No SMC header present"),
+// Estimates how likely it is that the file is a Super Nintendo ROM, from 0 to 100.
+//
+// A header in the LoROM or HiROM spot matching the file size is a decent signal. When its
+// checksum and complement also agree, it's very unlikely to be a coincidence. The ROM
+// isn't summed to check the checksum itself, since reading it does that anyway.
+pub fn probe<R: Read + Seek>(reader: &mut R, size: u64) -> Result<u8> {
+    let offset = match copier_header_offset(size) {
+        Ok(offset) => offset,
+        Err(_) => return Ok(0),
+    };
+    let real_size = match real_size(size, offset) {
+        Ok(real_size) => real_size,
+        Err(_) => return Ok(0),
+    };
+
+    let best = read_candidates(reader, offset)?
+        .iter()
+        .map(|(_, header)| header_score(header, None, real_size))
+        .max()
+        .unwrap_or(0);
+
+    match best {
+        4 => Ok(90),
+        3 => Ok(80),
+        MIN_HEADER_SCORE => Ok(60),
+        _ => Ok(0),
+    }
+}
+
+// Copiers like the Super Magicom prepend a 512 byte header to the ROM.
+// ROMs are always a multiple of 1 kB, so the remainder gives it away.
+fn copier_header_offset(size: u64) -> Result<u64> {
+    match size % 1024 {
+        0 => {
+            debug!("No SMC header present");
+            Ok(0x00)
+        }
         512 => {
             debug!("SMC header present");
-            offset = 0x0200;
-            has_smc_header = true;
+            Ok(0x0200)
         }
-        x => panic!("Invalid file? rem 1024 is {}", x),
+        x => bail!("Invalid file size. Remainder of 1024 is {}", x),
     }
+}
 
+pub fn rom_from_file(path: &Path) -> Result<Rom> {
     debug!("reading rom from file {:?}", &path);
 
-    let mut f = File::open(&path).unwrap();
-    let header = find_rom_header(&mut f, metadata.len(), offset)?;
+    if let Some(entry) = archive::single_entry(path)? {
+        return rom_from_reader(&mut entry.reader(), entry.size());
+    }
 
-    Ok(rom_from_header(&header, has_smc_header))
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+
+    rom_from_reader(&mut f, size)
+}
+
+// Reads the ROM from the first `size` bytes of the reader, which the copier header
+// detection relies on.
+pub fn rom_from_reader<R: Read + Seek>(reader: &mut R, size: u64) -> Result<Rom> {
+    let offset = copier_header_offset(size)?;
+    let found = find_rom_header(reader, size, offset)?;
+
+    reader.seek(SeekFrom::Start(0))?;
+    let hashes = hash::hash_reader(reader.take(size), offset)?;
+
+    Ok(rom_from_header(
+        &found.header,
+        found.checksum,
+        hashes,
+        offset > 0,
+    ))
 }
 
-fn rom_from_header(header: &RomHeader, has_smc_header: bool) -> Rom {
+// Copies the ROM to `dest` and writes the calculated checksum and its complement into
+// the copy's header. A ROM in an archive is copied out of it first. Returns the checksum
+// as it was before the fix.
+pub fn fix_checksum(source: &Path, dest: &Path) -> Result<Checksum> {
+    archive::patch_copy(source, dest, |f| {
+        let size = f.metadata()?.len();
+        let offset = copier_header_offset(size)?;
+        let found = find_rom_header(f, size, offset)?;
+
+        let checksum = found.checksum;
+        if !checksum.valid || !checksum.complement_valid {
+            let mut fields = Vec::with_capacity(4);
+            fields.extend_from_slice(&(!checksum.calculated).to_le_bytes());
+            fields.extend_from_slice(&checksum.calculated.to_le_bytes());
+
+            f.seek(SeekFrom::Start(found.file_offset + COMPLEMENT_CHECK_OFFSET))?;
+            f.write_all(&fields)?;
+        }
+
+        Ok(checksum)
+    })
+}
+
+fn rom_from_header(
+    header: &RomHeader,
+    checksum: Checksum,
+    hashes: RomHashes,
+    has_smc_header: bool,
+) -> Rom {
     Rom {
         map_mode: header.map_mode_description(),
         cartridge_type: header.cartridge_type_description(),
         target_market: header.destination_code_description(),
+        destination_code: header.destination_code,
         title: header.name.to_string(),
-        has_smc_header: has_smc_header,
+        maker_code: header.maker_code(),
+        game_code: header.game_code(),
+        version: header.version,
+        has_battery: header.has_battery(),
+        has_smc_header,
         rom_size: header.rom_size(),
         sram_size: header.sram_size(),
+        checksum,
+        hashes,
     }
 }
 
-// The header stores values in kilobytes, so "8" is 8 kB, or 8192 bytes.
-fn kilobytes_to_storage(kilobyte_len: u32) -> StorageSize {
-    StorageSize {
-        bytes: kilobyte_len * 1024,
+// The header stores sizes as 2^N kilobytes, so "3" is 8 kB, or 8192 bytes.
+fn kilobytes_to_storage(exponent: u8) -> Option<StorageSize> {
+    let kilobyte_len = 2u32.checked_pow(exponent.into())?;
+
+    Some(StorageSize {
+        bytes: kilobyte_len.checked_mul(1024)?,
         kilobits: kilobyte_len * 8,
         kilobytes: kilobyte_len,
-    }
+    })
 }
 
+// Where the header can be, relative to the start of the ROM.
+const HEADER_START_LOROM: u32 = 0x7FB0;
+const HEADER_START_HIROM: u32 = 0xFFB0;
+
 // Find a ROM header in the beginning of the file.
-// To avoid reading the file multiple times, wh
-pub fn find_rom_header(file: &mut File, size: u64, offset: u64) -> Result<RomHeader> {
-    const HEADER_START_LOROM: u32 = 0x7FB0;
-    const HEADER_START_HIROM: u32 = 0xFFB0;
+//
+// Both the LoROM and HiROM locations are read in a single pass and scored. The ROM is
+// summed once up front, since the checksum is the best way to tell them apart.
+pub fn find_rom_header<R: Read + Seek>(
+    file: &mut R,
+    size: u64,
+    offset: u64,
+) -> Result<FoundHeader> {
+    let real_size = real_size(size, offset)?;
+    let candidates = read_candidates(file, offset)?;
+    let (rom_sum, _) = mirrored_sum(file, offset, real_size)?;
+
+    let mut best: Option<(u8, FoundHeader)> = None;
+
+    for (header_start, header) in candidates {
+        let checksum = Checksum::new(&header, rom_sum);
+        let score = header_score(&header, Some(&checksum), real_size);
+        debug!(
+            "Header at {:#x} scored {}: {:?}",
+            header_start, score, header
+        );
+
+        if score >= MIN_HEADER_SCORE && score > best.as_ref().map_or(0, |(s, _)| *s) {
+            best = Some((
+                score,
+                FoundHeader {
+                    header,
+                    file_offset: offset + header_start as u64,
+                    checksum,
+                },
+            ));
+        }
+    }
+
+    match best {
+        Some((_, found)) => Ok(found),
+        None => {
+            bail!("Could not detect a valid header. This may not be a valid Super Nintendo ROM.")
+        }
+    }
+}
+
+fn real_size(size: u64, offset: u64) -> Result<u64> {
+    let real_size = size - offset;
+    if real_size > MAX_ROM_SIZE {
+        bail!(
+            "At {} bytes, this is too large to be a Super Nintendo ROM.",
+            real_size
+        );
+    }
+
+    Ok(real_size)
+}
+
+// Reads whatever is at the LoROM and HiROM header locations. Either can be garbage, and
+// locations past the end of the file are left out.
+fn read_candidates<R: Read + Seek>(file: &mut R, offset: u64) -> Result<Vec<(u32, RomHeader)>> {
     const HEADER_SIZE: u32 = 48;
     const HEADER_BUFFER_SIZE: usize =
         ((HEADER_START_HIROM - HEADER_START_LOROM) + HEADER_SIZE) as usize;
 
-    let real_size = size - offset;
-
     let start_looking_at = HEADER_START_LOROM as u64;
     let mut buffer = [0; HEADER_BUFFER_SIZE];
 
-    file.seek(std::io::SeekFrom::Start(offset + start_looking_at))?;
-    file.read(&mut buffer).expect("failed to read buffer");
+    file.seek(SeekFrom::Start(offset + start_looking_at))?;
+    // Small LoROMs end before the HiROM header location, so a short read is fine here.
+    let mut bytes_read = 0;
+    while bytes_read < buffer.len() {
+        match file.read(&mut buffer[bytes_read..])? {
+            0 => break,
+            len => bytes_read += len,
+        }
+    }
+    debug!("Read {} header buffer bytes", bytes_read);
+
+    [HEADER_START_LOROM, HEADER_START_HIROM]
+        .into_iter()
+        .filter(|start| (start - HEADER_START_LOROM + HEADER_SIZE) as usize <= bytes_read)
+        .map(|start| {
+            Ok((
+                start,
+                read_header_at(&buffer, start as u64 - start_looking_at)?,
+            ))
+        })
+        .collect()
+}
+
+// Headers scoring below this are taken to be something else.
+const MIN_HEADER_SCORE: u8 = 2;
 
-    let mut rom = read_header_at(&buffer, HEADER_START_LOROM as u64 - start_looking_at)?;
-    if header_checks_out(&rom, real_size) {
-        return Ok(rom);
+// Scores how legitimate a parsed header appears.
+//
+// The header tends to be in one of two places in the ROM file. The zeroed "fixed value"
+// and the "rom size" value matching the actual size of the ROM on disk are decent hints,
+// though games from before the extended header often have something else in the fixed
+// value. A checksum and complement that agree with each other, and with the ROM contents,
+// are much stronger. The checksum can be left out when the ROM hasn't been summed.
+fn header_score(header: &RomHeader, checksum: Option<&Checksum>, real_size: u64) -> u8 {
+    let mut score = 0;
+
+    if fixed_value_checks_out(header) {
+        score += 1;
     }
-    debug!("Does not appear to be a LoRom: {:?}", rom);
 
-    rom = read_header_at(&buffer, HEADER_START_HIROM as u64 - start_looking_at)?;
-    if header_checks_out(&rom, real_size) {
-        return Ok(rom);
+    if size_checks_out(header, real_size) {
+        score += 1;
     }
 
-    debug!("Does not appear to be a HiRom: {:?}", rom);
+    if header.complement_checks_out() {
+        score += 2;
+    }
 
-    bail!("Could not detect a valid header. This may not be a valid Super Nintendo ROM.")
+    if checksum.is_some_and(|c| c.valid) {
+        score += 4;
+    }
+
+    score
 }
 
-// Determines if the parsed header appears legitimate.
-//
-// The header tends to be in one of two places in the ROM file. A decent way to
-// check if you've read the right spot is by checking that the "fixed value"
-// gives you what you expect and that the "rom size" value matches the actual
-// size of the ROM on disk.
-fn header_checks_out(rom: &RomHeader, real_size: u64) -> bool {
+// The "fixed value" should always be zeroed out.
+fn fixed_value_checks_out(header: &RomHeader) -> bool {
     const FIXED_VALUE_1: [u8; 7] = [0, 0, 0, 0, 0, 0, 0];
 
-    if rom.fixed_value != FIXED_VALUE_1 {
-        debug!("fixed value was {:?}", rom.fixed_value);
-        return false;
-    }
+    header.fixed_value == FIXED_VALUE_1
+}
 
-    let calculated_size = 2u64.pow(rom.rom_size.into()) * 1024;
+// The header rounds the ROM size up to the next power of two.
+fn size_checks_out(header: &RomHeader, real_size: u64) -> bool {
+    let calculated_size = 2u64
+        .checked_pow(header.rom_size.into())
+        .and_then(|kb| kb.checked_mul(1024));
 
-    if real_size == calculated_size {
+    if calculated_size == Some(real_size.next_power_of_two()) {
         return true;
     }
 
     debug!(
-        "calculated_size of {} does not match real size {}",
+        "calculated_size of {:?} does not match real size {}",
         calculated_size, real_size
     );
 
     false
 }
 
+// Sums the bytes of the ROM the same way the checksum was generated.
+//
+// ROMs that aren't a power of two in size are mirrored: whatever follows the largest power
+// of two is repeated until it's the same size again. For example a 3 MB ROM is summed as
+// the first 2 MB plus the last 1 MB twice. The remainder can itself need mirroring.
+//
+// Returns the sum along with the mirrored size.
+fn mirrored_sum<R: Read + Seek>(file: &mut R, start: u64, len: u64) -> Result<(u64, u64)> {
+    if len == 0 || len.is_power_of_two() {
+        return Ok((sum_bytes(file, start, len)?, len));
+    }
+
+    let base = 1u64 << (63 - len.leading_zeros());
+    let head = sum_bytes(file, start, base)?;
+    let (tail, tail_len) = mirrored_sum(file, start + base, len - base)?;
+
+    Ok((head + tail * (base / tail_len), base * 2))
+}
+
+fn sum_bytes<R: Read + Seek>(file: &mut R, start: u64, len: u64) -> Result<u64> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(file.take(len));
+    let mut buffer = [0; 8192];
+    let mut sum = 0u64;
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        sum += buffer[..read].iter().map(|&b| b as u64).sum::<u64>();
+    }
+
+    Ok(sum)
+}
+
 fn read_header_at(mut buffer: &[u8], offset: u64) -> Result<RomHeader> {
     let mut cursor = Cursor::new(&mut buffer);
     cursor.seek(binread::io::SeekFrom::Start(offset))?;
@@ -252,3 +644,148 @@ fn read_header_at(mut buffer: &[u8], offset: u64) -> Result<RomHeader> {
 
     Ok(rom)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HEADER: usize = HEADER_START_LOROM as usize;
+
+    // A LoROM with a header at 0x7FB0 and a correct checksum and complement.
+    fn lorom(size: usize) -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let header = &mut rom[HEADER..HEADER + 48];
+        header[..6].copy_from_slice(b"01ABCE");
+        header[6..13].fill(0);
+        header[0x10..0x25].copy_from_slice(b"TEST GAME            ");
+        header[0x25] = 0x20;
+        header[0x26] = 0x02;
+        header[0x27] = (size / 1024).trailing_zeros() as u8;
+        header[0x28] = 0x03;
+        header[0x29] = 0x01;
+        header[0x2A] = 0x33;
+        header[0x2B] = 0x00;
+
+        write_checksum(&mut rom, 0x0000);
+        let sum = rom.iter().map(|&b| b as u64).sum::<u64>() as u16;
+        write_checksum(&mut rom, sum);
+
+        rom
+    }
+
+    fn write_checksum(rom: &mut [u8], checksum: u16) {
+        let at = HEADER + COMPLEMENT_CHECK_OFFSET as usize;
+        rom[at..at + 2].copy_from_slice(&(!checksum).to_le_bytes());
+        rom[at + 2..at + 4].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    fn read(rom: &[u8]) -> Rom {
+        rom_from_reader(&mut Cursor::new(rom), rom.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn validates_checksum_and_complement() {
+        let rom = read(&lorom(0x40000));
+
+        assert_eq!(rom.title, "TEST GAME");
+        assert_eq!(rom.game_code.as_deref(), Some("ABCE"));
+        assert!(rom.checksum.valid);
+        assert!(rom.checksum.complement_valid);
+    }
+
+    #[test]
+    fn calculates_checksum_when_the_stored_one_is_wrong() {
+        let mut data = lorom(0x40000);
+        let expected = read(&data).checksum.calculated;
+        write_checksum(&mut data, 0x1234);
+
+        let rom = read(&data);
+        assert_eq!(rom.checksum.declared, 0x1234);
+        assert_eq!(rom.checksum.calculated, expected);
+        assert!(!rom.checksum.valid);
+        // Still each other's complement, just not the right checksum
+        assert!(rom.checksum.complement_valid);
+    }
+
+    #[test]
+    fn finds_headers_from_before_the_extended_header() {
+        let mut data = lorom(0x40000);
+        data[HEADER + 6..HEADER + 13].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7]);
+        write_checksum(&mut data, 0x0000);
+        let sum = data.iter().map(|&b| b as u64).sum::<u64>() as u16;
+        write_checksum(&mut data, sum);
+
+        let rom = read(&data);
+        assert!(rom.checksum.valid);
+        assert_eq!(rom.title, "TEST GAME");
+    }
+
+    #[test]
+    fn skips_copier_headers() {
+        let mut data = vec![0; 512];
+        data.extend(lorom(0x40000));
+
+        let rom = read(&data);
+        assert!(rom.has_smc_header);
+        assert!(rom.checksum.valid);
+    }
+
+    #[test]
+    fn mirrors_sizes_that_are_not_a_power_of_two() {
+        // 1 + 2, then the 3 repeated to fill the other half
+        let data = [1, 2, 3];
+        assert_eq!(
+            mirrored_sum(&mut Cursor::new(&data), 0, 3).unwrap(),
+            (1 + 2 + 3 * 2, 4)
+        );
+
+        // The first four, then the last two twice
+        let data = [1, 1, 1, 1, 5, 7];
+        assert_eq!(
+            mirrored_sum(&mut Cursor::new(&data), 0, 6).unwrap(),
+            (4 + (5 + 7) * 2, 8)
+        );
+    }
+
+    #[test]
+    fn probes_without_summing() {
+        let data = lorom(0x40000);
+        let confidence = probe(&mut Cursor::new(&data), data.len() as u64).unwrap();
+        assert_eq!(confidence, 90);
+
+        let zeroes = vec![0; 0x40000];
+        assert_eq!(probe(&mut Cursor::new(&zeroes), 0x40000).unwrap(), 0);
+    }
+
+    #[test]
+    fn fixes_checksum_in_a_copy() {
+        let dir = std::env::temp_dir().join(format!("romboss-snes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("broken.sfc");
+        let dest = dir.join("fixed.sfc");
+
+        let mut data = lorom(0x40000);
+        write_checksum(&mut data, 0x1234);
+        std::fs::write(&source, &data).unwrap();
+
+        let before = fix_checksum(&source, &dest).unwrap();
+        assert!(!before.valid);
+
+        let fixed = read(&std::fs::read(&dest).unwrap());
+        assert!(fixed.checksum.valid);
+        assert!(fixed.checksum.complement_valid);
+        assert_eq!(std::fs::read(&source).unwrap(), data);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignores_header_locations_past_the_end() {
+        // Zeroes would pass for a header of a 1 kB ROM, if there were one.
+        let rom = vec![0; 1024];
+
+        assert_eq!(probe(&mut Cursor::new(&rom), 1024).unwrap(), 0);
+        assert!(rom_from_reader(&mut Cursor::new(&rom), 1024).is_err());
+    }
+}
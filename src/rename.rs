@@ -0,0 +1,562 @@
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Rename,
+    Copy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Move {
+    pub action: Action,
+    pub from: PathBuf,
+    pub to: PathBuf,
+
+    // Where the file that was at `to` was moved to before being overwritten
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<PathBuf>,
+}
+
+// What to do when the destination name is already taken. Overwriting moves the existing
+// file aside first, so that undo can put it back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collision {
+    Skip,
+    Suffix,
+    Overwrite,
+}
+
+pub fn parse_collision_label(label: &str) -> Option<Collision> {
+    match label {
+        "skip" => Some(Collision::Skip),
+        "suffix" => Some(Collision::Suffix),
+        "overwrite" => Some(Collision::Overwrite),
+        _ => None,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UndoLog {
+    pub moves: Vec<Move>,
+}
+
+// Works out where each ROM should go, keeping track of destinations that are already
+// claimed by earlier ROMs in the same run.
+//
+// A file that an earlier ROM is being renamed away from doesn't count as taken. One that's
+// only renamed away later is treated as a collision when it's planned, but an overwrite
+// won't bother backing it up once it turns out to be moving anyway.
+pub struct Planner {
+    target: Option<PathBuf>,
+    collision: Collision,
+    claimed: HashSet<PathBuf>,
+    vacated: HashSet<PathBuf>,
+    pub moves: Vec<Move>,
+}
+
+impl Planner {
+    pub fn new(target: Option<&Path>, collision: Collision) -> Planner {
+        Planner {
+            target: target.map(Path::to_path_buf),
+            collision,
+            claimed: HashSet::new(),
+            vacated: HashSet::new(),
+            moves: Vec::new(),
+        }
+    }
+
+    // Plans moving `source` to `name.extension`, either beside it or in the target
+    // directory. Returns why it was skipped, if it was.
+    pub fn plan(&mut self, source: &Path, name: &str, extension: &str) -> Option<String> {
+        let file_name = format!("{}.{}", sanitize(name), extension);
+        let (dir, action) = match &self.target {
+            Some(target) => (target.to_path_buf(), Action::Copy),
+            None => (
+                source
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .to_path_buf(),
+                Action::Rename,
+            ),
+        };
+
+        let mut dest = dir.join(&file_name);
+        let mut backup = None;
+
+        if action == Action::Rename && dest == source {
+            return Some("already has the canonical name".to_string());
+        }
+
+        if self.is_taken(&dest) {
+            match self.collision {
+                Collision::Skip => return Some(format!("{:?} already exists", dest)),
+                Collision::Overwrite if !self.claimed.contains(&dest) => {
+                    backup = Some(self.backup_path(&dest));
+                }
+                Collision::Overwrite => {
+                    return Some(format!(
+                        "{:?} is already the destination of another ROM",
+                        dest
+                    ))
+                }
+                Collision::Suffix => {
+                    dest = (1..)
+                        .map(|n| dir.join(format!("{} ({}).{}", sanitize(name), n, extension)))
+                        .find(|candidate| !self.is_taken(candidate))
+                        .expect("ran out of suffixes");
+                }
+            }
+        }
+
+        debug!("Planned {:?} {:?} -> {:?}", action, source, dest);
+        self.claimed.insert(dest.clone());
+        if let Some(backup) = &backup {
+            self.claimed.insert(backup.clone());
+        }
+        if action == Action::Rename {
+            self.vacated.insert(source.to_path_buf());
+            for m in self.moves.iter_mut().filter(|m| m.to == source) {
+                m.backup = None;
+            }
+        }
+        self.moves.push(Move {
+            action,
+            from: source.to_path_buf(),
+            to: dest,
+            backup,
+        });
+
+        None
+    }
+
+    // Puts the moves in an order they can be performed in. A move onto a file that another
+    // move reads from has to wait for it. Moves that wait on each other in a cycle, like two
+    // files swapping names, start by moving one of the files to a temporary name.
+    pub fn finish(mut self) -> Vec<Move> {
+        let mut moves = std::mem::take(&mut self.moves);
+
+        // Destinations are never shared, so each move waits on at most one other and has at
+        // most one waiting on it. That leaves chains, and cycles with nothing else attached.
+        let by_source: HashMap<&Path, usize> = moves
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (m.from.as_path(), i))
+            .collect();
+        let by_dest: HashMap<&Path, usize> = moves
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (m.to.as_path(), i))
+            .collect();
+        let waits_on: Vec<Option<usize>> = moves
+            .iter()
+            .map(|m| by_source.get(m.to.as_path()).copied())
+            .collect();
+        let waited_on_by: Vec<Option<usize>> = moves
+            .iter()
+            .map(|m| by_dest.get(m.from.as_path()).copied())
+            .collect();
+
+        let mut ordered = Vec::with_capacity(moves.len());
+        let mut done = vec![false; moves.len()];
+        for start in (0..moves.len()).filter(|&i| waits_on[i].is_none()) {
+            let mut next = Some(start);
+            while let Some(i) = next.filter(|&i| !done[i]) {
+                done[i] = true;
+                ordered.push(moves[i].clone());
+                next = waited_on_by[i];
+            }
+        }
+
+        // Everything left is in a cycle. A copy leaves its source alone, so it's the copy
+        // under the temporary name that gets renamed into place.
+        for start in 0..moves.len() {
+            if done[start] {
+                continue;
+            }
+
+            let temp = self.spare_path(&moves[start].from, "tmp");
+            self.claimed.insert(temp.clone());
+            debug!(
+                "Moving {:?} to {:?} to break a cycle",
+                moves[start].from, temp
+            );
+
+            ordered.push(Move {
+                action: moves[start].action,
+                from: moves[start].from.clone(),
+                to: temp.clone(),
+                backup: None,
+            });
+            moves[start].action = Action::Rename;
+            moves[start].from = temp;
+            done[start] = true;
+
+            let mut next = waited_on_by[start];
+            while let Some(i) = next.filter(|&i| !done[i]) {
+                done[i] = true;
+                ordered.push(moves[i].clone());
+                next = waited_on_by[i];
+            }
+            ordered.push(moves[start].clone());
+        }
+
+        ordered
+    }
+
+    // Picks an unused name beside the destination, like "game.sfc.bak".
+    fn backup_path(&self, dest: &Path) -> PathBuf {
+        self.spare_path(dest, "bak")
+    }
+
+    fn spare_path(&self, path: &Path, extension: &str) -> PathBuf {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        (0..)
+            .map(|n| match n {
+                0 => path.with_file_name(format!("{}.{}", name, extension)),
+                n => path.with_file_name(format!("{}.{}.{}", name, n, extension)),
+            })
+            .find(|candidate| !self.claimed.contains(candidate) && !candidate.exists())
+            .expect("ran out of spare names")
+    }
+
+    fn is_taken(&self, path: &Path) -> bool {
+        self.claimed.contains(path) || (path.exists() && !self.vacated.contains(path))
+    }
+}
+
+// DAT names can hold characters that aren't allowed in file names on some systems.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '-',
+            c => c,
+        })
+        .collect()
+}
+
+// Performs the moves in order, then writes the ones that succeeded to the undo log.
+// The log is written even when a move fails part way through.
+pub fn execute(moves: &[Move], undo_log: &Path) -> Result<()> {
+    let mut done = Vec::new();
+    let mut result = Ok(());
+
+    for m in moves {
+        if let Err(err) = perform(m) {
+            result = Err(err);
+            break;
+        }
+        if let Some(backup) = &m.backup {
+            println!("Moved existing {:?} to {:?}", m.to, backup);
+        }
+        println!("{:?} {:?} -> {:?}", m.action, m.from, m.to);
+        done.push(m.clone());
+    }
+
+    let log = UndoLog { moves: done };
+    let f = File::create(undo_log)
+        .with_context(|| format!("Failed to create undo log {:?}", undo_log))?;
+    serde_json::to_writer_pretty(f, &log)?;
+    println!("Wrote undo log {:?}", undo_log);
+
+    result
+}
+
+fn perform(m: &Move) -> Result<()> {
+    if let Some(parent) = m.to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    if let Some(backup) = &m.backup {
+        std::fs::rename(&m.to, backup)
+            .with_context(|| format!("Failed to move {:?} aside to {:?}", m.to, backup))?;
+    }
+
+    match m.action {
+        Action::Rename => std::fs::rename(&m.from, &m.to).map(|_| ()),
+        Action::Copy => std::fs::copy(&m.from, &m.to).map(|_| ()),
+    }
+    .with_context(|| format!("Failed to {:?} {:?} to {:?}", m.action, m.from, m.to))
+}
+
+// What `undo` put back, and what it couldn't along with why.
+pub struct UndoReport {
+    pub reverted: Vec<Move>,
+    pub failed: Vec<(Move, anyhow::Error)>,
+}
+
+// Reverts the moves in an undo log, newest first. Renames are moved back, copies are
+// deleted, and overwritten files are put back. Anything that has changed since is left
+// alone and reported, and the rest are still reverted.
+//
+// The log is rewritten with the moves that couldn't be reverted, so undoing again only
+// retries those.
+pub fn undo(undo_log: &Path) -> Result<UndoReport> {
+    let f = File::open(undo_log).with_context(|| format!("Failed to open {:?}", undo_log))?;
+    let log: UndoLog = serde_json::from_reader(f).context("Failed to parse undo log")?;
+    let mut report = UndoReport {
+        reverted: Vec::new(),
+        failed: Vec::new(),
+    };
+    let mut remaining = Vec::new();
+
+    for m in log.moves.into_iter().rev() {
+        match revert(&m) {
+            Ok(()) => report.reverted.push(m),
+            Err(err) => {
+                remaining.push(m.clone());
+                report.failed.push((m, err));
+            }
+        }
+    }
+
+    remaining.reverse();
+    let f = File::create(undo_log)
+        .with_context(|| format!("Failed to update undo log {:?}", undo_log))?;
+    serde_json::to_writer_pretty(f, &UndoLog { moves: remaining })?;
+
+    Ok(report)
+}
+
+fn revert(m: &Move) -> Result<()> {
+    if !m.to.exists() {
+        bail!("{:?} no longer exists, so it can't be reverted", m.to);
+    }
+    if let Some(backup) = &m.backup {
+        if !backup.exists() {
+            bail!(
+                "{:?} no longer exists, so {:?} can't be restored",
+                backup,
+                m.to
+            );
+        }
+    }
+
+    match m.action {
+        Action::Rename => {
+            if m.from.exists() {
+                bail!(
+                    "{:?} exists again, so {:?} can't be moved back",
+                    m.from,
+                    m.to
+                );
+            }
+            std::fs::rename(&m.to, &m.from)
+                .with_context(|| format!("Failed to move {:?} back to {:?}", m.to, m.from))?;
+        }
+        Action::Copy => {
+            std::fs::remove_file(&m.to).with_context(|| format!("Failed to remove {:?}", m.to))?
+        }
+    }
+
+    if let Some(backup) = &m.backup {
+        std::fs::rename(backup, &m.to)
+            .with_context(|| format!("Failed to restore {:?} from {:?}", m.to, backup))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("romboss-rename-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn overwrite_can_be_undone() {
+        let dir = temp_dir("overwrite");
+        let source = dir.join("smw.sfc");
+        let dest = dir.join("Super Mario World (USA).sfc");
+        std::fs::write(&source, b"new").unwrap();
+        std::fs::write(&dest, b"old").unwrap();
+
+        let mut planner = Planner::new(None, Collision::Overwrite);
+        assert_eq!(
+            planner.plan(&source, "Super Mario World (USA)", "sfc"),
+            None
+        );
+        assert_eq!(
+            planner.moves[0].backup,
+            Some(dir.join("Super Mario World (USA).sfc.bak"))
+        );
+
+        let undo_log = dir.join("undo.json");
+        execute(&planner.moves, &undo_log).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"new");
+        assert!(!source.exists());
+
+        undo(&undo_log).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"old");
+        assert_eq!(std::fs::read(&source).unwrap(), b"new");
+        assert!(!dir.join("Super Mario World (USA).sfc.bak").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backups_avoid_existing_files() {
+        let dir = temp_dir("backup");
+        let source = dir.join("smw.sfc");
+        std::fs::write(&source, b"new").unwrap();
+        std::fs::write(dir.join("Game.sfc"), b"old").unwrap();
+        std::fs::write(dir.join("Game.sfc.bak"), b"older").unwrap();
+
+        let mut planner = Planner::new(Some(&dir.join("out")), Collision::Overwrite);
+        planner.plan(&source, "Game", "sfc");
+        let mut planner_in_place = Planner::new(None, Collision::Overwrite);
+        planner_in_place.plan(&source, "Game", "sfc");
+
+        assert_eq!(planner.moves[0].backup, None);
+        assert_eq!(
+            planner_in_place.moves[0].backup,
+            Some(dir.join("Game.sfc.1.bak"))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn suffixes_names_that_are_taken() {
+        let dir = temp_dir("suffix");
+        std::fs::write(dir.join("a.sfc"), b"a").unwrap();
+        std::fs::write(dir.join("b.sfc"), b"b").unwrap();
+        std::fs::write(dir.join("Game.sfc"), b"old").unwrap();
+
+        let mut planner = Planner::new(None, Collision::Suffix);
+        planner.plan(&dir.join("a.sfc"), "Game", "sfc");
+        planner.plan(&dir.join("b.sfc"), "Game", "sfc");
+
+        let to: Vec<_> = planner.moves.iter().map(|m| m.to.clone()).collect();
+        assert_eq!(to, [dir.join("Game (1).sfc"), dir.join("Game (2).sfc")]);
+        assert!(planner.moves.iter().all(|m| m.backup.is_none()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn files(dir: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                (name, std::fs::read(&path).unwrap())
+            })
+            .filter(|(name, _)| name != "undo.json")
+            .collect();
+        files.sort();
+
+        files
+    }
+
+    #[test]
+    fn orders_moves_onto_files_that_are_moving_away() {
+        let dir = temp_dir("chain");
+        std::fs::write(dir.join("a.sfc"), b"a").unwrap();
+        std::fs::write(dir.join("b.sfc"), b"b").unwrap();
+
+        // "b.sfc" is taken when it's planned, but doesn't need a backup once it's moving too
+        let mut planner = Planner::new(None, Collision::Overwrite);
+        assert_eq!(planner.plan(&dir.join("a.sfc"), "b", "sfc"), None);
+        assert_eq!(planner.plan(&dir.join("b.sfc"), "c", "sfc"), None);
+        let moves = planner.finish();
+        assert!(moves.iter().all(|m| m.backup.is_none()));
+        assert_eq!(moves[0].from, dir.join("b.sfc"));
+
+        let undo_log = dir.join("undo.json");
+        execute(&moves, &undo_log).unwrap();
+        assert_eq!(
+            files(&dir),
+            [
+                ("b.sfc".to_string(), b"a".to_vec()),
+                ("c.sfc".to_string(), b"b".to_vec())
+            ]
+        );
+
+        undo(&undo_log).unwrap();
+        assert_eq!(
+            files(&dir),
+            [
+                ("a.sfc".to_string(), b"a".to_vec()),
+                ("b.sfc".to_string(), b"b".to_vec())
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn swaps_names_through_a_temporary_file() {
+        let dir = temp_dir("swap");
+        std::fs::write(dir.join("a.sfc"), b"a").unwrap();
+        std::fs::write(dir.join("b.sfc"), b"b").unwrap();
+
+        let mut planner = Planner::new(None, Collision::Overwrite);
+        planner.plan(&dir.join("a.sfc"), "b", "sfc");
+        planner.plan(&dir.join("b.sfc"), "a", "sfc");
+        let moves = planner.finish();
+        assert_eq!(moves.len(), 3);
+        assert_eq!(moves[0].to, dir.join("a.sfc.tmp"));
+
+        let undo_log = dir.join("undo.json");
+        execute(&moves, &undo_log).unwrap();
+        assert_eq!(
+            files(&dir),
+            [
+                ("a.sfc".to_string(), b"b".to_vec()),
+                ("b.sfc".to_string(), b"a".to_vec())
+            ]
+        );
+
+        undo(&undo_log).unwrap();
+        assert_eq!(
+            files(&dir),
+            [
+                ("a.sfc".to_string(), b"a".to_vec()),
+                ("b.sfc".to_string(), b"b".to_vec())
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undo_keeps_going_and_keeps_what_it_could_not_revert() {
+        let dir = temp_dir("undo");
+        std::fs::write(dir.join("a.sfc"), b"a").unwrap();
+        std::fs::write(dir.join("b.sfc"), b"b").unwrap();
+
+        let mut planner = Planner::new(None, Collision::Skip);
+        planner.plan(&dir.join("a.sfc"), "A", "sfc");
+        planner.plan(&dir.join("b.sfc"), "B", "sfc");
+        let undo_log = dir.join("undo.json");
+        execute(&planner.finish(), &undo_log).unwrap();
+
+        // The newest move can't be reverted, but the one before it still is
+        std::fs::remove_file(dir.join("B.sfc")).unwrap();
+        let report = undo(&undo_log).unwrap();
+        assert_eq!(report.reverted.len(), 1);
+        assert_eq!(report.reverted[0].from, dir.join("a.sfc"));
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.failed[0].1.to_string().contains("no longer exists"));
+        assert_eq!(std::fs::read(dir.join("a.sfc")).unwrap(), b"a");
+
+        let log: UndoLog = serde_json::from_reader(File::open(&undo_log).unwrap()).unwrap();
+        assert_eq!(log.moves.len(), 1);
+        assert_eq!(log.moves[0].from, dir.join("b.sfc"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}